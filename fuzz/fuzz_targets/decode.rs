@@ -0,0 +1,22 @@
+#![no_main]
+
+use img_caster::packet::Message;
+use img_caster::udpcast;
+use libfuzzer_sys::fuzz_target;
+
+// Message::decode must never panic, and whatever it accepts has to encode
+// back to the same header bytes followed by the returned payload.
+fuzz_target!(|data: &[u8]| {
+    if let Ok((msg, payload)) = Message::decode(data) {
+        let mut encoded = msg.encode();
+        encoded.extend_from_slice(payload);
+        if encoded != data {
+            // udpcast layouts are normalized while decoding, so only the
+            // decoded message has to survive the round trip
+            let encoded = udpcast::encode(&msg, payload);
+            let (again, again_payload) = Message::decode(&encoded).unwrap();
+            assert_eq!(format!("{again:?}"), format!("{msg:?}"));
+            assert_eq!(again_payload, payload);
+        }
+    }
+});
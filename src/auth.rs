@@ -0,0 +1,78 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fs;
+use std::io;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// HMAC-SHA256 truncated to 128 bits
+pub const TAG_LEN: usize = 16;
+
+// key files may end with a line break, which is not part of the key
+pub(crate) fn read_key(path: &str) -> io::Result<Vec<u8>> {
+    let mut key = fs::read(path)?;
+    while matches!(key.last(), Some(b'\n' | b'\r')) {
+        key.pop();
+    }
+    if key.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the key file is empty",
+        ));
+    }
+    Ok(key)
+}
+
+/// Pre-shared key which authenticates every packet of a session.
+#[derive(Clone)]
+pub struct Psk {
+    mac: HmacSha256,
+}
+
+impl Psk {
+    pub fn new(key: &[u8]) -> Self {
+        Self {
+            mac: HmacSha256::new_from_slice(key).expect("HMAC accepts any key length"),
+        }
+    }
+
+    /// Read the key from a file, a trailing line break is not part of it.
+    pub fn load(path: &str) -> io::Result<Self> {
+        Ok(Self::new(&read_key(path)?))
+    }
+
+    /// Append the authentication tag to an encoded message.
+    pub fn sign(&self, message: &mut Vec<u8>) {
+        let tag = self.tag(&[message]);
+        message.extend_from_slice(&tag);
+    }
+
+    /// Authentication tag of a message sent in several parts.
+    pub fn tag(&self, parts: &[&[u8]]) -> [u8; TAG_LEN] {
+        let mut mac = self.mac.clone();
+        for part in parts {
+            mac.update(part);
+        }
+        let mut tag = [0; TAG_LEN];
+        tag.copy_from_slice(&mac.finalize().into_bytes()[..TAG_LEN]);
+        tag
+    }
+
+    /// Check the tag of a received packet and return the message without it.
+    pub fn verify<'a>(&self, packet: &'a [u8]) -> Option<&'a [u8]> {
+        if packet.len() < TAG_LEN {
+            return None;
+        }
+        let (message, tag) = packet.split_at(packet.len() - TAG_LEN);
+        let mut mac = self.mac.clone();
+        mac.update(message);
+        mac.verify_truncated_left(tag).ok()?;
+        Some(message)
+    }
+}
+
+impl std::fmt::Debug for Psk {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.write_str("Psk")
+    }
+}
@@ -0,0 +1,228 @@
+use clap::Parser;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use img_caster::auth::Psk;
+use img_caster::datafifo::DataFIFO;
+use img_caster::multicast::{MultiCast, BATCH_SIZE};
+use img_caster::packet::*;
+use img_caster::transport::Gather;
+use img_caster::{BLOCK_SIZE, MAX_SLICE_SIZE, UDP_PACK_SIZE};
+
+// Every allocation of the process is counted, the paths below are measured
+// by the difference.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+#[derive(Parser, Default, Debug)]
+#[clap(author, version, about)]
+/// Send data blocks over a localhost UDP socket and count the allocations and
+/// the packet rate of the copying path, which builds every datagram in a new
+/// buffer, and of the gathering path of the sender and the receivers.
+struct Args {
+    /// Blocks sent by each path.
+    #[clap(long, default_value = "200000")]
+    packets: Option<usize>,
+
+    /// Authenticate every packet with a pre-shared key.
+    #[clap(long)]
+    psk: bool,
+}
+
+#[derive(Default)]
+struct Measure {
+    allocations: usize,
+    elapsed: Duration,
+}
+
+impl Measure {
+    fn run<T>(&mut self, f: impl FnOnce() -> T) -> T {
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+        let start = Instant::now();
+        let result = f();
+        self.elapsed += start.elapsed();
+        self.allocations += ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+        result
+    }
+
+    fn print(&self, path: &str, side: &str, packets: usize) {
+        println!(
+            "{path:<7} {side:<7} {:>6.2} allocations/packet {:>10.0} packets/s",
+            self.allocations as f64 / packets as f64,
+            packets as f64 / self.elapsed.as_secs_f64(),
+        );
+    }
+}
+
+// The blocks of a slice, as the sender takes them from the FIFO
+struct Blocks {
+    fifo: RwLock<DataFIFO>,
+    slice_no: u32,
+    bytes: u32,
+}
+
+impl Blocks {
+    fn new() -> Self {
+        let bytes = MAX_SLICE_SIZE * BLOCK_SIZE;
+        let mut fifo = DataFIFO::new(bytes as usize);
+        let data: Vec<u8> = (0..bytes).map(|i| (i % 251) as u8).collect();
+        fifo.push(&data);
+        Self {
+            fifo: RwLock::new(fifo),
+            slice_no: 1,
+            bytes,
+        }
+    }
+
+    fn pos(&self, blockno: u32) -> usize {
+        (blockno % MAX_SLICE_SIZE * BLOCK_SIZE) as usize
+    }
+
+    fn header(&self, blockno: u32) -> Message {
+        let blockno = (blockno % MAX_SLICE_SIZE) as u16;
+        Message::CmdData(DataBlock::new(self.slice_no, blockno, self.bytes))
+    }
+}
+
+// One datagram at a time, each built from the encoded header and a copy of
+// the block.
+fn copy_send(socket: &mut MultiCast, blocks: &Blocks, batch: &[u32]) -> io::Result<()> {
+    for &blockno in batch {
+        let data = blocks
+            .fifo
+            .write()
+            .unwrap()
+            .get(blocks.pos(blockno), BLOCK_SIZE);
+        let mut packet = blocks.header(blockno).encode();
+        packet.extend_from_slice(&data);
+        socket.send_to(&packet, socket.multicast_addr)?;
+    }
+    Ok(())
+}
+
+fn copy_recv(socket: &UdpSocket, psk: Option<&Psk>, count: usize) -> io::Result<usize> {
+    let mut buf = [0; UDP_PACK_SIZE];
+    let mut bytes = 0;
+    for _ in 0..count {
+        let (size, _) = socket.recv_from(&mut buf)?;
+        let packet = match psk {
+            Some(psk) => psk.verify(&buf[..size]).ok_or(io::ErrorKind::InvalidData)?,
+            None => &buf[..size],
+        };
+        let (_, payload) = Message::decode(packet)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        bytes += payload.to_vec().len();
+    }
+    Ok(bytes)
+}
+
+// A batch of datagrams with one system call, the headers encoded into
+// buffers kept from batch to batch and the blocks borrowed from the FIFO.
+fn gather_send(
+    socket: &mut MultiCast,
+    blocks: &Blocks,
+    headers: &mut [[u8; BLOCK_HEADER_LEN]],
+    batch: &[u32],
+) -> io::Result<()> {
+    for (header, &blockno) in headers.iter_mut().zip(batch) {
+        blocks.header(blockno).encode_into(header);
+        socket.stamp(header);
+    }
+    let fifo = blocks.fifo.read().unwrap();
+    let packets: Vec<Gather> = headers
+        .iter()
+        .zip(batch)
+        .map(|(header, &blockno)| {
+            let (data, wrapped) = fifo.as_slices(blocks.pos(blockno), BLOCK_SIZE);
+            Gather::new(&[header, data, wrapped])
+        })
+        .collect();
+    socket.send_batch(&packets, socket.multicast_addr)?;
+    Ok(())
+}
+
+fn gather_recv(socket: &mut MultiCast, count: usize) -> io::Result<usize> {
+    let mut bytes = 0;
+    for _ in 0..count {
+        let received = socket.recv_msg()?;
+        bytes += received.payload().len();
+        socket.recycle(received);
+    }
+    Ok(bytes)
+}
+
+fn main() -> io::Result<()> {
+    let args = Args::parse();
+    let packets = args.packets.unwrap().max(BATCH_SIZE) / BATCH_SIZE * BATCH_SIZE;
+    let session = 0x1234;
+    let psk = args.psk.then(|| Psk::new(b"packet_bench"));
+
+    let send_socket = UdpSocket::bind("127.0.0.1:0")?;
+    let recv_socket = UdpSocket::bind("127.0.0.1:0")?;
+    recv_socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+    let send_addr: SocketAddr = send_socket.local_addr()?;
+    let recv_addr: SocketAddr = recv_socket.local_addr()?;
+    let mut sender = MultiCast::new(Box::new(send_socket), 0, send_addr, recv_addr, recv_addr);
+    sender.set_session(session);
+    let mut receiver = MultiCast::new(
+        Box::new(recv_socket.try_clone()?),
+        0,
+        recv_addr,
+        send_addr,
+        send_addr,
+    );
+    receiver.join_session(session);
+    if let Some(psk) = &psk {
+        sender.set_psk(psk.clone());
+        receiver.set_psk(psk.clone());
+    }
+
+    let blocks = Blocks::new();
+    let batches: Vec<Vec<u32>> = (0..packets as u32)
+        .collect::<Vec<_>>()
+        .chunks(BATCH_SIZE)
+        .map(|batch| batch.to_vec())
+        .collect();
+    let expected = packets * BLOCK_SIZE as usize;
+
+    let (mut send, mut recv) = (Measure::default(), Measure::default());
+    let mut bytes = 0;
+    for batch in &batches {
+        send.run(|| copy_send(&mut sender, &blocks, batch))?;
+        bytes += recv.run(|| copy_recv(&recv_socket, psk.as_ref(), batch.len()))?;
+    }
+    send.print("copy", "send", packets);
+    recv.print("copy", "receive", packets);
+    assert_eq!(bytes, expected);
+
+    let mut headers = vec![[0; BLOCK_HEADER_LEN]; BATCH_SIZE];
+    let (mut send, mut recv) = (Measure::default(), Measure::default());
+    let mut bytes = 0;
+    for batch in &batches {
+        send.run(|| gather_send(&mut sender, &blocks, &mut headers, batch))?;
+        bytes += recv.run(|| gather_recv(&mut receiver, batch.len()))?;
+    }
+    send.print("gather", "send", packets);
+    recv.print("gather", "receive", packets);
+    assert_eq!(bytes, expected);
+    Ok(())
+}
@@ -1,15 +1,21 @@
 use byte_unit::Byte;
 use clap::Parser;
-use log::{info, LevelFilter};
+use log::{error, info, warn, LevelFilter};
 use simplelog::*;
 use std::fs::File;
+use std::net::IpAddr;
 use std::str::FromStr;
 use std::sync::{Arc, RwLock};
 use std::time::Instant;
 
 use dev::disk::{self, Disk};
+use img_caster::auth::Psk;
+use img_caster::crypt::Secret;
 use img_caster::datafifo::DataFIFO;
+use img_caster::multicast::{parse_scope, Announce, MultiCast, NetConfig, Nic};
+use img_caster::multinic;
 use img_caster::receiver_s::McastReceiver;
+use img_caster::tcp;
 use img_caster::*;
 
 #[derive(Parser, Default, Debug)]
@@ -24,14 +30,58 @@ struct Args {
     #[clap(short, long)]
     driveno: Option<u8>,
 
-    /// Specifie the network card.
-    #[clap(short, long, default_value = "0")]
-    nic: Option<usize>,
+    /// Network interface: a name, an IP address, a CIDR network or an index, several separated by commas. ex) eth0, 192.168.1.10, 192.168.1.0/24, eth0,eth1
+    #[clap(short, long)]
+    nic: Option<String>,
+
+    /// Use IPv6 multicast instead of IPv4.
+    #[clap(long)]
+    ipv6: bool,
+
+    /// IPv6 multicast scope: link, site or a scope number.
+    #[clap(long, default_value = "link")]
+    scope: Option<String>,
+
+    /// IPv6 interface index, takes precedence over --nic.
+    #[clap(long, default_value = "0")]
+    ifindex: Option<u32>,
+
+    /// UDP port of the receivers, the sender listens on the next one.
+    #[clap(long, default_value = "9000")]
+    port: Option<u16>,
+
+    /// UDP port the sender listens on, instead of the one after --port.
+    #[clap(long)]
+    control_port: Option<u16>,
+
+    /// Multicast group of the data, derived from the interface address by default.
+    #[clap(long)]
+    group: Option<IpAddr>,
+
+    /// Connect to the sender at this address over TCP, where multicast is not available.
+    #[clap(long, value_name = "SENDER", conflicts_with = "ipv6")]
+    tcp: Option<IpAddr>,
+
+    /// Find the peers by broadcast, by multicast or at an IP address.
+    #[clap(long, default_value = "broadcast")]
+    announce: Option<String>,
 
     /// Number of sectors to set Write chunk size.
     #[clap(short, long, default_value = "512")]
     chunk: Option<String>,
 
+    /// Authenticate every packet with the pre-shared key in this file.
+    #[clap(long, value_name = "FILE")]
+    psk_file: Option<String>,
+
+    /// Decrypt the image with a key derived from the secret in this file.
+    #[clap(long, value_name = "FILE", conflicts_with = "passphrase")]
+    key_file: Option<String>,
+
+    /// Decrypt the image with a key derived from this passphrase.
+    #[clap(long)]
+    passphrase: Option<String>,
+
     /// Log file name
     #[clap(short, long)]
     log: Option<String>,
@@ -43,6 +93,14 @@ struct Args {
     #[clap(long, default_value = "8MiB")]
     rcvbuf: Option<String>,
 
+    /// Only join the session with this name or id, when several senders share the network.
+    #[clap(long)]
+    session: Option<String>,
+
+    /// Listen only, never acknowledge to the sender and report the missed data at the end.
+    #[clap(long)]
+    passive: bool,
+
     /// enable to FUA mode
     #[clap(long)]
     fua: Option<bool>,
@@ -107,15 +165,96 @@ fn main() {
         .get_bytes() as usize
         * SECTOR_SIZE;
     let rcvbuf = Byte::from_str(args.rcvbuf.unwrap()).unwrap().get_bytes() as usize;
-    let mut receiver = McastReceiver::new(
-        args.nic.unwrap_or(0),
-        rcvbuf,
-        data_fifo_socket,
-        disk,
-        disk_trace_thread,
-    );
+    let psk = match args.psk_file.as_deref().map(Psk::load) {
+        Some(Ok(psk)) => Some(psk),
+        Some(Err(err)) => {
+            error!("Can't load the pre-shared key: {err}");
+            return;
+        }
+        None => None,
+    };
+    let secret = match (args.key_file.as_deref(), args.passphrase.as_deref()) {
+        (Some(path), _) => match Secret::load(path) {
+            Ok(secret) => Some(secret),
+            Err(err) => {
+                error!("Can't load the key file: {err}");
+                return;
+            }
+        },
+        (None, Some(passphrase)) => Some(Secret::new(passphrase.as_bytes())),
+        (None, None) => None,
+    };
+    let nics: Vec<Nic> = args
+        .nic
+        .as_deref()
+        .unwrap_or_default()
+        .split(',')
+        .map(Nic::parse)
+        .collect();
+    if nics.len() > 1 && (args.ipv6 || args.tcp.is_some()) {
+        error!("Several interfaces are only available with IPv4 multicast");
+        return;
+    }
+    let nic = match args.ifindex {
+        Some(ifindex) if ifindex > 0 => Nic::Ifindex(ifindex),
+        _ => nics[0].clone(),
+    };
+    let announce = match Announce::parse(args.announce.as_deref().unwrap()) {
+        Ok(announce) => announce,
+        Err(err) => {
+            error!("{:?}", err);
+            return;
+        }
+    };
+    let port = args.port.unwrap_or(PORTBASE);
+    let config = NetConfig {
+        nic,
+        data_port: port,
+        control_port: args.control_port.unwrap_or(port.wrapping_add(1)),
+        group: args.group,
+        announce,
+    };
+    let socket = if let Some(sender) = args.tcp {
+        tcp::receiver(&config, sender)
+    } else if nics.len() > 1 {
+        multinic::receiver(&config, &nics, rcvbuf).map(|(socket, _)| socket)
+    } else if args.ipv6 {
+        match parse_scope(args.scope.as_deref().unwrap()) {
+            Ok(scope) => MultiCast::receiver_v6(&config, scope, rcvbuf),
+            Err(err) => {
+                error!("{:?}", err);
+                return;
+            }
+        }
+    } else {
+        MultiCast::receiver(&config, rcvbuf)
+    };
+    let mut socket = match socket {
+        Ok(socket) => socket,
+        Err(err) => {
+            error!("Can't open the network interface: {err}");
+            return;
+        }
+    };
+    if let Some(psk) = psk {
+        socket.set_psk(psk);
+    }
+    let mut receiver =
+        McastReceiver::new(socket, rcvbuf, data_fifo_socket, disk, disk_trace_thread);
 
-    let _ = receiver.enumerate();
+    if args.passive {
+        receiver.set_passive();
+    }
+    if let Some(secret) = secret {
+        receiver.set_secret(secret);
+    }
+    if let Some(selector) = args.session.as_deref() {
+        receiver.set_session(selector);
+    }
+    if let Err(err) = receiver.enumerate() {
+        error!("{:?}", err);
+        return;
+    }
     receiver.write_chunk = write_chunk;
 
     println!("\nPress 'Enter' to start receiving data!\n");
@@ -137,8 +276,19 @@ fn main() {
             }
         }
     }
+    let _ = receiver.send_report();
     let _ = receiver.send_disconnect();
     receiver.display_progress(true);
+    if receiver.socket.forged_count > 0 {
+        warn!(
+            "{} unauthenticated packets dropped",
+            receiver.socket.forged_count
+        );
+    }
+    if receiver.undecryptable > 0 {
+        warn!("{} blocks failed to decrypt", receiver.undecryptable);
+    }
+    receiver.report_missed();
 
     let filename = format!(
         "sr{}_{}_{}.csv",
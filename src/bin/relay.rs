@@ -0,0 +1,468 @@
+use byte_unit::Byte;
+use clap::Parser;
+use log::{error, info, warn, LevelFilter};
+use simplelog::*;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use img_caster::auth::Psk;
+use img_caster::congestion;
+use img_caster::crypt::Secret;
+use img_caster::datafifo::DataFIFO;
+use img_caster::fec::Fec;
+use img_caster::multicast::{Announce, MultiCast, NetConfig, Nic};
+use img_caster::pacer::Pacer;
+use img_caster::receiver_a::McastReceiver;
+use img_caster::sender::McastSender;
+use img_caster::*;
+
+#[derive(Parser, Default, Debug)]
+#[clap(author, version, about)]
+/// Relay which receives a Multicast File Transfer and sends it again on another network
+struct Args {
+    /// Network interface of the upstream session: a name, an IP address, a CIDR network or an index.
+    #[clap(short, long)]
+    nic: Option<String>,
+
+    /// UDP port of the receivers of the upstream session, its sender listens on the next one.
+    #[clap(long, default_value = "9000")]
+    port: Option<u16>,
+
+    /// UDP port the upstream sender listens on, instead of the one after --port.
+    #[clap(long)]
+    control_port: Option<u16>,
+
+    /// Multicast group of the upstream data, derived from the interface address by default.
+    #[clap(long)]
+    group: Option<IpAddr>,
+
+    /// Find the upstream sender by broadcast, by multicast or at an IP address.
+    #[clap(long, default_value = "broadcast")]
+    announce: Option<String>,
+
+    /// Only join the upstream session with this name or id.
+    #[clap(long)]
+    session: Option<String>,
+
+    /// Receive buffer size.
+    #[clap(long, default_value = "8MiB")]
+    rcvbuf: Option<String>,
+
+    /// Network interface the session is sent again on.
+    #[clap(long)]
+    downstream_nic: String,
+
+    /// UDP port of the downstream receivers, the relay listens on the next one.
+    #[clap(long, default_value = "9000")]
+    downstream_port: Option<u16>,
+
+    /// Multicast group of the downstream data, derived from the interface address by default.
+    #[clap(long)]
+    downstream_group: Option<IpAddr>,
+
+    /// Find the downstream receivers by broadcast, by multicast or at an IP address.
+    #[clap(long, default_value = "broadcast")]
+    downstream_announce: Option<String>,
+
+    /// Name of the downstream session, the upstream one by default.
+    #[clap(long)]
+    downstream_session: Option<String>,
+
+    /// Set TTL value
+    #[clap(short, long, default_value = "1")]
+    ttl: Option<u32>,
+
+    /// Wait time(seconds) for the downstream receivers
+    #[clap(short, long)]
+    wait: Option<u64>,
+
+    /// Specifie the slice size under 8192. ex) 2048, 4KiB,
+    #[clap(long, default_value = "2048")]
+    slices: Option<String>,
+
+    /// Bytes of data in a downstream block, at most the MTU less 44. ex) 8956 for jumbo frames of 9000
+    #[clap(long)]
+    blocksize: Option<u32>,
+
+    /// Size the downstream blocks to the path MTU to the receivers instead of --blocksize
+    #[clap(long)]
+    probe_mtu: bool,
+
+    /// Enable forward error correction downstream. ex) 8/4 -> 8 stripes with 4 FEC blocks each
+    #[clap(long)]
+    fec: Option<String>,
+
+    /// Limit the data sent downstream to this bitrate. ex) 100M, 1.5G, 800k
+    #[clap(long)]
+    max_bitrate: Option<String>,
+
+    /// Adapt slice size and bitrate to the downstream losses: legacy or aimd.
+    #[clap(long, default_value = "legacy")]
+    congestion: Option<String>,
+
+    /// Buffer the session in this file and acknowledge upstream at once, instead of when the downstream receivers have the data.
+    #[clap(long, value_name = "FILE")]
+    spool: Option<String>,
+
+    /// Authenticate every packet on both networks with the pre-shared key in this file.
+    #[clap(long, value_name = "FILE")]
+    psk_file: Option<String>,
+
+    /// Decrypt the image upstream and encrypt it downstream with a key derived from the secret in this file.
+    #[clap(long, value_name = "FILE", conflicts_with = "passphrase")]
+    key_file: Option<String>,
+
+    /// Decrypt the image upstream and encrypt it downstream with a key derived from this passphrase.
+    #[clap(long)]
+    passphrase: Option<String>,
+
+    /// Log file name
+    #[clap(short, long)]
+    log: Option<String>,
+
+    #[clap(long, default_value = "info")]
+    loglevel: Option<String>,
+}
+
+// Move the data received upstream into the FIFO of the downstream sender,
+// through the spool file if there is one. Ends when upstream is done and
+// everything went downstream, or downstream gave up.
+fn relay(
+    upstream: Arc<RwLock<DataFIFO>>,
+    downstream: Arc<RwLock<DataFIFO>>,
+    mut spool: Option<(File, File)>,
+) {
+    let changes = upstream.read().unwrap().notify();
+    let mut spooled = 0;
+    let mut relayed = 0;
+    // the data on its way through the spool file, grown to the largest move
+    let mut buffer = Vec::new();
+    loop {
+        // taken before looking at the FIFOs, so that no change is missed
+        let seen = changes.count();
+        let (size, upstream_done) = {
+            let upstream = upstream.read().unwrap();
+            (upstream.len(), upstream.is_closed())
+        };
+        let room = MAX_BUFFER_SIZE - downstream.read().unwrap().len();
+        let mut moved = 0;
+        match spool {
+            Some((ref mut writer, ref mut reader)) => {
+                if size > 0 {
+                    buffer.resize(size.max(buffer.len()), 0);
+                    upstream.write().unwrap().pop_into(&mut buffer[..size]);
+                    if let Err(e) = writer
+                        .write_all(&buffer[..size])
+                        .and_then(|_| writer.flush())
+                    {
+                        error!("Spool write Error: {:?}", e);
+                        upstream.write().unwrap().close();
+                        downstream.write().unwrap().close();
+                        return;
+                    }
+                    spooled += size;
+                    moved += size;
+                }
+                let size = room.min(spooled - relayed);
+                if size > 0 {
+                    buffer.resize(size.max(buffer.len()), 0);
+                    if let Err(e) = reader.read_exact(&mut buffer[..size]) {
+                        error!("Spool read Error: {:?}", e);
+                        upstream.write().unwrap().close();
+                        downstream.write().unwrap().close();
+                        return;
+                    }
+                    downstream.write().unwrap().push(&buffer[..size]);
+                    relayed += size;
+                    moved += size;
+                }
+            }
+            None => {
+                let size = size.min(room);
+                if size > 0 {
+                    let mut downstream = downstream.write().unwrap();
+                    upstream.write().unwrap().pop_to(size, &mut downstream);
+                    spooled += size;
+                    relayed += size;
+                    moved += size;
+                }
+            }
+        }
+        if upstream_done && size == 0 && relayed == spooled {
+            downstream.write().unwrap().close();
+            break;
+        }
+        if downstream.read().unwrap().is_closed() {
+            upstream.write().unwrap().close();
+            break;
+        }
+        if moved == 0 {
+            changes.wait(seen);
+        }
+    }
+}
+
+// initialize logger
+fn init_logger(args: &Args) {
+    let loglevel = args.loglevel.as_ref().unwrap();
+    let termlog = TermLogger::new(
+        LevelFilter::from_str(&loglevel).unwrap(),
+        Config::default(),
+        TerminalMode::Mixed,
+        ColorChoice::Auto,
+    );
+    let mut logger: Vec<Box<dyn SharedLogger>> = vec![termlog];
+
+    if let Some(logfile) = args.log.as_ref() {
+        let flog = WriteLogger::new(
+            LevelFilter::from_str(&loglevel).unwrap(),
+            Config::default(),
+            File::create(logfile).unwrap(),
+        );
+        logger.push(flog);
+    }
+    let _ = CombinedLogger::init(logger);
+}
+
+fn main() {
+    let args = Args::parse();
+
+    init_logger(&args);
+    println!("Img_Caster: relay v{}\n", VERSION);
+
+    let upstream_fifo = Arc::new(RwLock::new(DataFIFO::new(MAX_BUFFER_SIZE)));
+    let downstream_fifo = Arc::new(RwLock::new(DataFIFO::new(MAX_BUFFER_SIZE)));
+    // the relay thread waits for a change of either
+    let changes = downstream_fifo.read().unwrap().notify();
+    upstream_fifo.write().unwrap().set_notify(changes);
+
+    let rcvbuf = Byte::from_str(args.rcvbuf.clone().unwrap())
+        .unwrap()
+        .get_bytes() as usize;
+    let psk = match args.psk_file.as_deref().map(Psk::load) {
+        Some(Ok(psk)) => Some(psk),
+        Some(Err(err)) => {
+            error!("Can't load the pre-shared key: {err}");
+            return;
+        }
+        None => None,
+    };
+    let secret = match (args.key_file.as_deref(), args.passphrase.as_deref()) {
+        (Some(path), _) => match Secret::load(path) {
+            Ok(secret) => Some(secret),
+            Err(err) => {
+                error!("Can't load the key file: {err}");
+                return;
+            }
+        },
+        (None, Some(passphrase)) => Some(Secret::new(passphrase.as_bytes())),
+        (None, None) => None,
+    };
+    let spool = match args.spool.as_deref() {
+        Some(path) => match File::create(path).and_then(|writer| Ok((writer, File::open(path)?))) {
+            Ok(spool) => Some(spool),
+            Err(err) => {
+                error!("Can't open the spool file: {err}");
+                return;
+            }
+        },
+        None => None,
+    };
+    let (announce, downstream_announce) = match (
+        Announce::parse(args.announce.as_deref().unwrap()),
+        Announce::parse(args.downstream_announce.as_deref().unwrap()),
+    ) {
+        (Ok(announce), Ok(downstream_announce)) => (announce, downstream_announce),
+        (Err(err), _) | (_, Err(err)) => {
+            error!("{:?}", err);
+            return;
+        }
+    };
+    let port = args.port.unwrap_or(PORTBASE);
+    let upstream_config = NetConfig {
+        nic: Nic::parse(args.nic.as_deref().unwrap_or_default()),
+        data_port: port,
+        control_port: args.control_port.unwrap_or(port.wrapping_add(1)),
+        group: args.group,
+        announce,
+    };
+    let port = args.downstream_port.unwrap_or(PORTBASE);
+    let downstream_config = NetConfig {
+        nic: Nic::parse(&args.downstream_nic),
+        data_port: port,
+        control_port: port.wrapping_add(1),
+        group: args.downstream_group,
+        announce: downstream_announce,
+    };
+
+    // Open the downstream sender first, upstream does not wait for the relay
+    let socket = match MultiCast::sender(&downstream_config) {
+        Ok(socket) => socket,
+        Err(err) => {
+            error!("Can't open the downstream interface: {err}");
+            return;
+        }
+    };
+    let mut sender = McastSender::new(
+        socket,
+        args.ttl.unwrap(),
+        Byte::from_str(args.slices.clone().unwrap())
+            .unwrap()
+            .get_bytes() as u32,
+        Arc::clone(&downstream_fifo),
+    );
+    if let Some(name) = args
+        .downstream_session
+        .as_deref()
+        .or(args.session.as_deref())
+    {
+        sender.set_session_name(name);
+    }
+    info!("Downstream session {}", sender.session());
+    if let Some(blocksize) = args.blocksize {
+        if let Err(err) = sender.set_blocksize(blocksize) {
+            error!("{:?}", err);
+            return;
+        }
+    }
+    if args.probe_mtu {
+        sender.set_probe_mtu();
+    }
+    if let Some(psk) = psk.clone() {
+        if let Err(err) = sender.set_psk(psk) {
+            error!("{:?}", err);
+            return;
+        }
+    }
+    if let Some(ref secret) = secret {
+        if let Err(err) = sender.set_secret(secret) {
+            error!("{:?}", err);
+            return;
+        }
+    }
+    if let Some(fec) = args.fec.as_deref() {
+        match Fec::parse(fec) {
+            Ok(fec) => sender.set_fec(fec),
+            Err(err) => {
+                error!("{:?}", err);
+                return;
+            }
+        }
+    }
+    if let Some(rate) = args.max_bitrate.as_deref() {
+        match Pacer::parse(rate) {
+            Ok(bits_per_sec) => sender.set_max_bitrate(bits_per_sec),
+            Err(err) => {
+                error!("{:?}", err);
+                return;
+            }
+        }
+    }
+    match congestion::from_name(args.congestion.as_deref().unwrap()) {
+        Ok(congestion) => sender.set_congestion(congestion),
+        Err(err) => {
+            error!("{:?}", err);
+            return;
+        }
+    }
+    if let Err(err) = sender.enumerate(Duration::new(args.wait.unwrap_or(60 * 5), 0), false) {
+        error!("{:?}", err);
+        return;
+    }
+
+    // Then join the upstream session like any receiver
+    let mut socket = match MultiCast::receiver(&upstream_config, rcvbuf) {
+        Ok(socket) => socket,
+        Err(err) => {
+            error!("Can't open the upstream interface: {err}");
+            return;
+        }
+    };
+    if let Some(psk) = psk {
+        socket.set_psk(psk);
+    }
+    let mut receiver = McastReceiver::new(socket, rcvbuf, Arc::clone(&upstream_fifo));
+    if spool.is_none() {
+        receiver.set_ack_gate(Arc::clone(&downstream_fifo));
+    }
+    if let Some(secret) = secret {
+        receiver.set_secret(secret);
+    }
+    if let Some(selector) = args.session.as_deref() {
+        receiver.set_session(selector);
+    }
+    if let Err(err) = receiver.enumerate() {
+        error!("{:?}", err);
+        return;
+    }
+
+    let relay_thread = {
+        let upstream_fifo = Arc::clone(&upstream_fifo);
+        let downstream_fifo = Arc::clone(&downstream_fifo);
+        thread::spawn(move || relay(upstream_fifo, downstream_fifo, spool))
+    };
+    let upstream_thread = {
+        let upstream_fifo = Arc::clone(&upstream_fifo);
+        let downstream_fifo = Arc::clone(&downstream_fifo);
+        thread::spawn(move || {
+            loop {
+                if let Ok(running) = receiver.dispatch_message() {
+                    if !running {
+                        break;
+                    }
+                }
+                if upstream_fifo.read().unwrap().is_closed() {
+                    break;
+                }
+            }
+            // the digest in the report covers the data taken from the FIFO
+            let changes = upstream_fifo.read().unwrap().notify();
+            loop {
+                let seen = changes.count();
+                if upstream_fifo.read().unwrap().len() == 0
+                    || downstream_fifo.read().unwrap().is_closed()
+                {
+                    break;
+                }
+                changes.wait(seen);
+            }
+            let _ = receiver.send_report();
+            let _ = receiver.send_disconnect();
+            if receiver.socket.forged_count > 0 {
+                warn!(
+                    "{} unauthenticated packets dropped upstream",
+                    receiver.socket.forged_count
+                );
+            }
+            receiver.report_missed();
+        })
+    };
+
+    loop {
+        if !sender.transfer_data() {
+            break;
+        }
+        if let Ok(running) = sender.dispatch_message() {
+            if !running {
+                break;
+            }
+        }
+    }
+    downstream_fifo.write().unwrap().close();
+    let _ = relay_thread.join();
+    let _ = upstream_thread.join();
+    sender.display_progress(true);
+    if sender.socket.forged_count > 0 {
+        warn!(
+            "{} unauthenticated packets dropped downstream",
+            sender.socket.forged_count
+        );
+    }
+    sender.collect_reports(Duration::from_secs(10));
+    sender.print_reports();
+}
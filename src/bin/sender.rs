@@ -1,17 +1,26 @@
 use byte_unit::Byte;
 use clap::Parser;
-use log::{error, info, trace, LevelFilter};
+use log::{error, info, trace, warn, LevelFilter};
 use simplelog::*;
 use std::fs::File;
-use std::io::Read;
+use std::io::{Read, Seek, SeekFrom};
+use std::net::IpAddr;
 use std::str::FromStr;
 use std::sync::{Arc, RwLock};
 use std::thread;
 use std::time::{Duration, Instant};
 
 use dev::disk::Disk;
+use img_caster::auth::Psk;
+use img_caster::congestion;
+use img_caster::crypt::Secret;
 use img_caster::datafifo::DataFIFO;
+use img_caster::fec::Fec;
+use img_caster::multicast::{parse_scope, Announce, MultiCast, NetConfig, Nic};
+use img_caster::multinic::{self, Mode};
+use img_caster::pacer::Pacer;
 use img_caster::sender::McastSender;
+use img_caster::tcp;
 use img_caster::*;
 
 #[derive(Parser, Default, Debug)]
@@ -26,9 +35,45 @@ struct Args {
     #[clap(short, long)]
     driveno: Option<u8>,
 
-    /// Specifie the network card.
-    #[clap(short, long, default_value = "0")]
-    nic: Option<usize>,
+    /// Network interface: a name, an IP address, a CIDR network or an index, several separated by commas. ex) eth0, 192.168.1.10, 192.168.1.0/24, eth0,eth1
+    #[clap(short, long)]
+    nic: Option<String>,
+
+    /// Use IPv6 multicast instead of IPv4.
+    #[clap(long)]
+    ipv6: bool,
+
+    /// IPv6 multicast scope: link, site or a scope number.
+    #[clap(long, default_value = "link")]
+    scope: Option<String>,
+
+    /// IPv6 interface index, takes precedence over --nic.
+    #[clap(long, default_value = "0")]
+    ifindex: Option<u32>,
+
+    /// UDP port of the receivers, the sender listens on the next one.
+    #[clap(long, default_value = "9000")]
+    port: Option<u16>,
+
+    /// UDP port the sender listens on, instead of the one after --port.
+    #[clap(long)]
+    control_port: Option<u16>,
+
+    /// Multicast group of the data, derived from the interface address by default.
+    #[clap(long)]
+    group: Option<IpAddr>,
+
+    /// With several interfaces, spread the blocks over all of them for receivers on all their networks, instead of sending everything on each.
+    #[clap(long)]
+    stripe: bool,
+
+    /// Serve every receiver over its own TCP connection, where multicast is not available.
+    #[clap(long, conflicts_with = "ipv6")]
+    tcp: bool,
+
+    /// Find the peers by broadcast, by multicast or at an IP address.
+    #[clap(long, default_value = "broadcast")]
+    announce: Option<String>,
 
     /// Set TTL value
     #[clap(short, long, default_value = "1")]
@@ -46,6 +91,42 @@ struct Args {
     #[clap(long, default_value = "2048")]
     slices: Option<String>,
 
+    /// Bytes of data in a block, at most the MTU less 44. ex) 8956 for jumbo frames of 9000
+    #[clap(long)]
+    blocksize: Option<u32>,
+
+    /// Size the blocks to the path MTU to the receivers instead of --blocksize
+    #[clap(long)]
+    probe_mtu: bool,
+
+    /// Enable forward error correction. ex) 8/4 -> 8 stripes with 4 FEC blocks each
+    #[clap(long)]
+    fec: Option<String>,
+
+    /// Limit the data sent to this bitrate. ex) 100M, 1.5G, 800k
+    #[clap(long)]
+    max_bitrate: Option<String>,
+
+    /// Adapt slice size and bitrate to the losses: legacy or aimd.
+    #[clap(long, default_value = "legacy")]
+    congestion: Option<String>,
+
+    /// Pause in milliseconds between the end of a slice and the next one.
+    #[clap(long)]
+    slice_gap: Option<u64>,
+
+    /// Loop the image and let receivers join while it is sent.
+    #[clap(long)]
+    streaming: bool,
+
+    /// Talk the plain udpcast protocol to udp-receiver clients.
+    #[clap(long)]
+    udpcast: bool,
+
+    /// Session name announced to the receivers, which select the session by it.
+    #[clap(long)]
+    session: Option<String>,
+
     /// enable to p2p connection
     #[clap(short, long)]
     p2p: bool,
@@ -54,6 +135,18 @@ struct Args {
     #[clap(short, long, default_value = "8192")]
     chunk: Option<String>,
 
+    /// Authenticate every packet with the pre-shared key in this file.
+    #[clap(long, value_name = "FILE")]
+    psk_file: Option<String>,
+
+    /// Encrypt the image with a key derived from the secret in this file.
+    #[clap(long, value_name = "FILE", conflicts_with = "passphrase")]
+    key_file: Option<String>,
+
+    /// Encrypt the image with a key derived from this passphrase.
+    #[clap(long)]
+    passphrase: Option<String>,
+
     /// Log file name
     #[clap(short, long)]
     log: Option<String>,
@@ -70,35 +163,57 @@ fn read(
     disk: &mut Option<Disk>,
     data_fifo: Arc<RwLock<DataFIFO>>,
     read_chunk: usize,
+    streaming: bool,
     disk_trace: Arc<RwLock<Box<Vec<(Instant, Instant)>>>>,
 ) -> bool {
+    let changes = data_fifo.read().unwrap().notify();
+    let mut offset = 0;
     loop {
-        let delay = Instant::now() + Duration::from_millis(50);
+        // taken before looking at the FIFO, so that no change is missed
+        let seen = changes.count();
         let mut size: usize = MAX_BUFFER_SIZE - data_fifo.read().unwrap().len();
         if (size % read_chunk) != 0 {
             size -= size % read_chunk;
         }
         if let Some(ref mut disk) = disk {
-            if data_fifo.read().unwrap().endpoint() >= disk.size {
-                data_fifo.write().unwrap().close();
-                trace!("read end");
-                return false;
+            if offset >= disk.size {
+                if !streaming || disk.seek(SeekFrom::Start(0)).is_err() {
+                    data_fifo.write().unwrap().close();
+                    trace!("read end");
+                    return false;
+                }
+                trace!("rewind image");
+                offset = 0;
             }
-            let start = Instant::now();
-            let mut buff = Box::new(vec![0u8; size]);
-            if let Ok(size) = disk.read(&mut buff) {
-                trace!("read {size} bytes");
-                if size > 0 {
-                    data_fifo.write().unwrap().push(&mut buff[..size]);
-                    let end = Instant::now();
-                    disk_trace.write().unwrap().push((start, end));
+            size = size.min(disk.size - offset);
+            if size > 0 {
+                let start = Instant::now();
+                let mut buff = Box::new(vec![0u8; size]);
+                match disk.read(&mut buff) {
+                    // the image ends before its size
+                    Ok(0) => offset = disk.size,
+                    Ok(size) => {
+                        trace!("read {size} bytes");
+                        offset += size;
+                        data_fifo.write().unwrap().push(&buff[..size]);
+                        let end = Instant::now();
+                        disk_trace.write().unwrap().push((start, end));
+                    }
+                    Err(e) => {
+                        error!("Disk read Error: {:?}", e);
+                        data_fifo.write().unwrap().close();
+                        return false;
+                    }
                 }
             }
         }
         if data_fifo.read().unwrap().is_closed() {
             return false;
         }
-        thread::sleep(delay.saturating_duration_since(Instant::now()));
+        // the FIFO is full, or there is no disk and the sender closes it
+        if size == 0 || disk.is_none() {
+            changes.wait(seen);
+        }
     }
 }
 
@@ -166,16 +281,172 @@ fn main() {
     let disk_trace_thread = Arc::clone(&disk_trace);
 
     // Open Network socket sender
+    let psk = match args.psk_file.as_deref().map(Psk::load) {
+        Some(Ok(psk)) => Some(psk),
+        Some(Err(err)) => {
+            error!("Can't load the pre-shared key: {err}");
+            return;
+        }
+        None => None,
+    };
+    let secret = match (args.key_file.as_deref(), args.passphrase.as_deref()) {
+        (Some(path), _) => match Secret::load(path) {
+            Ok(secret) => Some(secret),
+            Err(err) => {
+                error!("Can't load the key file: {err}");
+                return;
+            }
+        },
+        (None, Some(passphrase)) => Some(Secret::new(passphrase.as_bytes())),
+        (None, None) => None,
+    };
+    let nics: Vec<Nic> = args
+        .nic
+        .as_deref()
+        .unwrap_or_default()
+        .split(',')
+        .map(Nic::parse)
+        .collect();
+    if nics.len() > 1 && (args.ipv6 || args.tcp) {
+        error!("Several interfaces are only available with IPv4 multicast");
+        return;
+    }
+    let nic = match args.ifindex {
+        Some(ifindex) if ifindex > 0 => Nic::Ifindex(ifindex),
+        _ => nics[0].clone(),
+    };
+    let announce = match Announce::parse(args.announce.as_deref().unwrap()) {
+        Ok(announce) => announce,
+        Err(err) => {
+            error!("{:?}", err);
+            return;
+        }
+    };
+    let port = args.port.unwrap_or(PORTBASE);
+    let config = NetConfig {
+        nic,
+        data_port: port,
+        control_port: args.control_port.unwrap_or(port.wrapping_add(1)),
+        group: args.group,
+        announce,
+    };
+    let mut interfaces = None;
+    let socket = if args.tcp {
+        tcp::sender(&config)
+    } else if nics.len() > 1 {
+        let mode = if args.stripe {
+            Mode::Stripe
+        } else {
+            Mode::Replicate
+        };
+        multinic::sender(&config, &nics, mode).map(|(socket, nics)| {
+            interfaces = Some(nics);
+            socket
+        })
+    } else if args.ipv6 {
+        match parse_scope(args.scope.as_deref().unwrap()) {
+            Ok(scope) => MultiCast::sender_v6(&config, scope),
+            Err(err) => {
+                error!("{:?}", err);
+                return;
+            }
+        }
+    } else {
+        MultiCast::sender(&config)
+    };
+    let socket = match socket {
+        Ok(socket) => socket,
+        Err(err) => {
+            error!("Can't open the network interface: {err}");
+            return;
+        }
+    };
     let mut sender = McastSender::new(
-        args.nic.unwrap_or(0),
+        socket,
         args.ttl.unwrap(),
         Byte::from_str(args.slices.clone().unwrap())
             .unwrap()
             .get_bytes() as u32,
         data_fifo_socket,
     );
-    let disk_thread =
-        thread::spawn(move || read(&mut disk, data_fifo_thread, read_chunk, disk_trace_thread));
+    if let Some(interfaces) = interfaces {
+        sender.set_interfaces(interfaces);
+    }
+    if args.udpcast {
+        if args.fec.is_some() || args.streaming || psk.is_some() || secret.is_some() {
+            error!(
+                "FEC, streaming, pre-shared keys and encryption are not available in udpcast mode"
+            );
+            return;
+        }
+        sender.set_udpcast();
+    }
+    if let Some(name) = args.session.as_deref() {
+        sender.set_session_name(name);
+    }
+    info!("Session {}", sender.session());
+    if let Some(blocksize) = args.blocksize {
+        if let Err(err) = sender.set_blocksize(blocksize) {
+            error!("{:?}", err);
+            return;
+        }
+    }
+    if args.probe_mtu {
+        sender.set_probe_mtu();
+    }
+    if let Some(psk) = psk {
+        if let Err(err) = sender.set_psk(psk) {
+            error!("{:?}", err);
+            return;
+        }
+    }
+    if let Some(secret) = secret {
+        if let Err(err) = sender.set_secret(&secret) {
+            error!("{:?}", err);
+            return;
+        }
+    }
+    if let Some(fec) = args.fec.as_deref() {
+        match Fec::parse(fec) {
+            Ok(fec) => sender.set_fec(fec),
+            Err(err) => {
+                error!("{:?}", err);
+                return;
+            }
+        }
+    }
+    if let Some(rate) = args.max_bitrate.as_deref() {
+        match Pacer::parse(rate) {
+            Ok(bits_per_sec) => sender.set_max_bitrate(bits_per_sec),
+            Err(err) => {
+                error!("{:?}", err);
+                return;
+            }
+        }
+    }
+    match congestion::from_name(args.congestion.as_deref().unwrap()) {
+        Ok(congestion) => sender.set_congestion(congestion),
+        Err(err) => {
+            error!("{:?}", err);
+            return;
+        }
+    }
+    if let Some(gap) = args.slice_gap {
+        sender.set_slice_gap(Duration::from_millis(gap));
+    }
+    if args.streaming {
+        sender.set_streaming(disk.as_ref().map_or(0, |disk| disk.size));
+    }
+    let streaming = args.streaming;
+    let disk_thread = thread::spawn(move || {
+        read(
+            &mut disk,
+            data_fifo_thread,
+            read_chunk,
+            streaming,
+            disk_trace_thread,
+        )
+    });
     // thread::sleep(Duration::from_secs(2));
 
     if let Err(err) = sender.enumerate(Duration::new(args.wait.unwrap_or(60 * 5), 0), args.p2p) {
@@ -196,11 +467,20 @@ fn main() {
     data_fifo.write().unwrap().close();
     let _ = disk_thread.join();
     sender.display_progress(true);
+    if sender.socket.forged_count > 0 {
+        warn!(
+            "{} unauthenticated packets dropped",
+            sender.socket.forged_count
+        );
+    }
+    sender.collect_reports(Duration::from_secs(10));
+    sender.print_reports();
 
     let filename = format!(
-        "as{}_{}.csv",
+        "as{}_{}_{}.csv",
         sender.socket.myip_addr.ip().to_string(),
-        args.slices.unwrap()
+        args.slices.unwrap(),
+        sender.congestion()
     );
     let mut events = sender.get_events();
     for (start_time, end_time) in disk_trace.write().unwrap().iter() {
@@ -1,6 +1,6 @@
 use std::ops::{BitAndAssign, BitOrAssign};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct BitArray {
     bits: Vec<u8>,
     size: usize,
@@ -35,8 +35,11 @@ impl BitArray {
         self
     }
 
+    // bits past the end are unset, ready sets from the network may be short
     pub fn get(&self, index: usize) -> bool {
-        assert!(index < self.size, "Index out of range");
+        if index >= self.size {
+            return false;
+        }
 
         let byte_index = index / 8;
         let bit_index = index % 8;
@@ -44,6 +47,21 @@ impl BitArray {
         (self.bits[byte_index] & (1 << bit_index)) != 0
     }
 
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    pub fn count_ones(&self) -> usize {
+        self.bits
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
     pub fn bits(&mut self) -> Vec<u8> {
         self.bits.clone()
     }
@@ -75,7 +93,7 @@ impl BitOrAssign for BitArray {
 
 impl From<Vec<u8>> for BitArray {
     fn from(data: Vec<u8>) -> Self {
-        let size = data.len();
+        let size = data.len() * 8;
         let bits = data;
         Self { bits, size }
     }
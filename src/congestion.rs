@@ -0,0 +1,142 @@
+use std::fmt;
+use std::time::Duration;
+
+// bitrate of the aimd controller without --max-bitrate, and its ceiling
+const AIMD_START: u64 = 100_000_000;
+const AIMD_CEILING: u64 = 10_000_000_000;
+const AIMD_FLOOR: u64 = 1_000_000;
+// the worst receiver missing more of a slice than this counts as congestion
+const AIMD_LOSS: f64 = 0.02;
+
+/// What the controller steers. The sender keeps the slice size between 32
+/// blocks and --slices, and the bitrate under --max-bitrate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Control {
+    /// blocks of the next slice
+    pub slice_size: u32,
+    /// pace of the data blocks, None sends as fast as the socket takes them
+    pub bitrate: Option<u64>,
+}
+
+/// A retransmission round about to be sent.
+#[derive(Debug, Clone, Copy)]
+pub struct Round {
+    pub blocks: u32,
+    /// blocks sent again in this round
+    pub resent: u32,
+    /// last block every receiver got, 0 if none
+    pub last_good_block: u32,
+}
+
+/// A slice every receiver acknowledged.
+#[derive(Debug, Clone, Copy)]
+pub struct SliceDone {
+    pub blocks: u32,
+    /// blocks sent again in all its rounds
+    pub resent: u32,
+    pub rounds: u32,
+    pub elapsed: Duration,
+}
+
+/// Strategy which adapts slice size and send rate of the sender to the
+/// losses the receivers report.
+pub trait CongestionControl: fmt::Debug + Send {
+    fn name(&self) -> &'static str;
+
+    /// Set the control of the first slice.
+    fn start(&mut self, _control: &mut Control) {}
+
+    /// Receiver client_no misses `missing` of the `blocks` of the current slice.
+    fn on_nack(&mut self, _client_no: usize, _missing: u32, _blocks: u32) {}
+
+    fn on_round(&mut self, round: &Round, control: &mut Control);
+
+    fn on_slice(&mut self, slice: &SliceDone, control: &mut Control);
+}
+
+/// Controller by name, for the --congestion option.
+pub fn from_name(name: &str) -> Result<Box<dyn CongestionControl>, &'static str> {
+    match name {
+        "legacy" => Ok(Box::new(Legacy::default())),
+        "aimd" => Ok(Box::new(Aimd::default())),
+        _ => Err("Unknown congestion control, expected legacy or aimd"),
+    }
+}
+
+/// The slice sizing img_caster always did: grow by a quarter per slice until
+/// the first retransmission, shrink to the blocks received before a loss.
+/// The bitrate is left alone.
+#[derive(Debug, Default)]
+pub struct Legacy {
+    retransmitted: bool,
+}
+
+impl CongestionControl for Legacy {
+    fn name(&self) -> &'static str {
+        "legacy"
+    }
+
+    fn on_round(&mut self, round: &Round, control: &mut Control) {
+        self.retransmitted = true;
+        let good = round.last_good_block;
+        if good > 0 && good < round.blocks {
+            if good < control.slice_size / 2 {
+                control.slice_size /= 2;
+            } else {
+                control.slice_size = good;
+            }
+        }
+    }
+
+    fn on_slice(&mut self, _slice: &SliceDone, control: &mut Control) {
+        if !self.retransmitted {
+            control.slice_size += control.slice_size / 4;
+        }
+    }
+}
+
+/// Additive increase, multiplicative decrease of the bitrate, driven by the
+/// worst receiver of each slice like TFMCC. Slices grow while there is no
+/// congestion. They are not cut with the rate: blocks still in flight at the
+/// reqack look lost, and shorter slices would only make that worse.
+#[derive(Debug, Default)]
+pub struct Aimd {
+    // highest share of a slice a receiver missed
+    worst_loss: f64,
+    // bitrate before the last decrease, the increase is a fraction of it
+    last_peak: u64,
+}
+
+impl CongestionControl for Aimd {
+    fn name(&self) -> &'static str {
+        "aimd"
+    }
+
+    fn start(&mut self, control: &mut Control) {
+        control.bitrate = Some(control.bitrate.unwrap_or(AIMD_START));
+    }
+
+    fn on_nack(&mut self, _client_no: usize, missing: u32, blocks: u32) {
+        if blocks > 0 {
+            self.worst_loss = self.worst_loss.max(missing as f64 / blocks as f64);
+        }
+    }
+
+    fn on_round(&mut self, _round: &Round, _control: &mut Control) {}
+
+    fn on_slice(&mut self, _slice: &SliceDone, control: &mut Control) {
+        let bitrate = control.bitrate.unwrap_or(AIMD_START);
+        if self.last_peak == 0 {
+            self.last_peak = bitrate;
+        }
+        if self.worst_loss > AIMD_LOSS {
+            self.last_peak = bitrate;
+            control.bitrate = Some((bitrate / 4 * 3).max(AIMD_FLOOR));
+        } else {
+            let step = (self.last_peak / 16).max(AIMD_FLOOR);
+            control.bitrate = Some((bitrate + step).min(AIMD_CEILING));
+            control.slice_size += control.slice_size / 4;
+        }
+        self.worst_loss = 0.0;
+    }
+}
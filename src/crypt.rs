@@ -0,0 +1,102 @@
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{AeadInPlace, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, Nonce, Tag};
+use sha2::Sha256;
+use std::io;
+
+use crate::auth::read_key;
+
+// Poly1305 tag appended to every encrypted block
+pub const AEAD_TAG_LEN: usize = 16;
+pub const SALT_LEN: usize = 16;
+// opcode, slice and block number at the start of a block header
+const NONCE_LEN: usize = 12;
+const KDF_ROUNDS: u32 = 100_000;
+
+/// Passphrase or key file from which the key of each session is derived.
+#[derive(Clone)]
+pub struct Secret(Vec<u8>);
+
+impl Secret {
+    pub fn new(passphrase: &[u8]) -> Self {
+        Self(passphrase.to_vec())
+    }
+
+    /// Read the secret from a file, a trailing line break is not part of it.
+    pub fn load(path: &str) -> io::Result<Self> {
+        Ok(Self(read_key(path)?))
+    }
+
+    /// Derive the key of the session announced with `salt`.
+    pub fn session(&self, salt: &[u8; SALT_LEN]) -> Cipher {
+        let mut key = [0; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(&self.0, salt, KDF_ROUNDS, &mut key);
+        Cipher {
+            aead: ChaCha20Poly1305::new(&key.into()),
+            salt: *salt,
+        }
+    }
+
+    /// Derive the key of a new session with a random salt.
+    pub fn new_session(&self) -> Cipher {
+        let mut salt = [0; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        self.session(&salt)
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        fmt.write_str("Secret")
+    }
+}
+
+/// ChaCha20-Poly1305 key of a session, encrypting the payload of data and FEC
+/// blocks.
+///
+/// The first 12 bytes of the encoded block header hold the opcode, the slice
+/// number and the block number. They are unique for every block of a session,
+/// a retransmission encrypts the same data again, so they serve as nonce. The
+/// whole header is authenticated along with the payload.
+#[derive(Clone)]
+pub struct Cipher {
+    aead: ChaCha20Poly1305,
+    salt: [u8; SALT_LEN],
+}
+
+impl Cipher {
+    pub fn salt(&self) -> &[u8; SALT_LEN] {
+        &self.salt
+    }
+
+    /// Encrypt the data, given in parts, into out with the tag appended.
+    /// out keeps its capacity from block to block.
+    pub fn seal_into(&self, header: &[u8], data: &[&[u8]], out: &mut Vec<u8>) {
+        let nonce = Nonce::from_slice(&header[..NONCE_LEN]);
+        out.clear();
+        for part in data {
+            out.extend_from_slice(part);
+        }
+        self.aead
+            .encrypt_in_place(nonce, header, out)
+            .expect("the block fits into the cipher")
+    }
+
+    /// Decrypt a payload in place and return the length of the plain data,
+    /// None if it was not sealed with this key and header.
+    pub fn open_in_place(&self, header: &[u8], data: &mut [u8]) -> Option<usize> {
+        let len = data.len().checked_sub(AEAD_TAG_LEN)?;
+        let (data, tag) = data.split_at_mut(len);
+        let nonce = Nonce::from_slice(&header[..NONCE_LEN]);
+        self.aead
+            .decrypt_in_place_detached(nonce, header, data, Tag::from_slice(tag))
+            .ok()?;
+        Some(len)
+    }
+}
+
+impl std::fmt::Debug for Cipher {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "Cipher({})", crate::hex(&self.salt))
+    }
+}
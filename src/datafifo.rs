@@ -1,6 +1,9 @@
 use log::trace;
+use sha2::{Digest, Sha256};
 use std::fmt;
+use std::sync::Arc;
 
+use crate::event_loop::Notify;
 use crate::*;
 
 pub struct DataFIFO {
@@ -10,6 +13,9 @@ pub struct DataFIFO {
     startpoint: usize,
     endpoint: usize,
     close: bool,
+    image: Option<(usize, usize)>,
+    digest: Option<Sha256>,
+    notify: Arc<Notify>,
 }
 
 impl DataFIFO {
@@ -25,10 +31,26 @@ impl DataFIFO {
             startpoint: 0,
             endpoint: 0,
             close: false,
+            image: None,
+            digest: None,
+            notify: Arc::default(),
         }
     }
 
-    pub fn push(&mut self, data: &mut [u8]) -> &mut Self {
+    // Counted on every change of the data or the read position and on close,
+    // for the threads and event loops waiting on the FIFO.
+    pub fn notify(&self) -> Arc<Notify> {
+        Arc::clone(&self.notify)
+    }
+
+    // Count the changes together with those of another FIFO, for a thread
+    // which waits on both.
+    pub fn set_notify(&mut self, notify: Arc<Notify>) -> &mut Self {
+        self.notify = notify;
+        self
+    }
+
+    pub fn push(&mut self, data: &[u8]) -> &mut Self {
         let size = data.len();
         let start = self.endpoint % self.capacity;
         let end = start + size;
@@ -40,6 +62,7 @@ impl DataFIFO {
             self.buffer[start..].copy_from_slice(&data[..split]);
             self.buffer[..size - split].copy_from_slice(&data[split..]);
         }
+        self.notify.notify();
         self
     }
 
@@ -48,14 +71,69 @@ impl DataFIFO {
         let end = start + size;
         let split = self.capacity - start;
         self.startpoint += size;
-        if end <= self.capacity {
-            Some(self.buffer[start..end].to_vec())
+        let data = if end <= self.capacity {
+            self.buffer[start..end].to_vec()
         } else {
             let mut data = vec![0; size];
             data[..split].copy_from_slice(&self.buffer[start..]);
             data[split..].copy_from_slice(&self.buffer[..size - split]);
-            Some(data)
+            data
+        };
+        if let Some(digest) = self.digest.as_mut() {
+            digest.update(&data);
         }
+        self.notify.notify();
+        Some(data)
+    }
+
+    // Pop like pop, into a buffer of the caller.
+    pub fn pop_into(&mut self, data: &mut [u8]) -> &mut Self {
+        let (first, second) = self.as_slices(self.startpoint, data.len() as u32);
+        let split = first.len();
+        data[..split].copy_from_slice(first);
+        data[split..].copy_from_slice(second);
+        self.popped(data.len())
+    }
+
+    // Pop like pop, straight into another FIFO.
+    pub fn pop_to(&mut self, size: usize, to: &mut DataFIFO) -> &mut Self {
+        let (first, second) = self.as_slices(self.startpoint, size as u32);
+        to.push(first);
+        if !second.is_empty() {
+            to.push(second);
+        }
+        self.popped(size)
+    }
+
+    fn popped(&mut self, size: usize) -> &mut Self {
+        if let Some(digest) = self.digest.as_mut() {
+            let start = self.startpoint % self.capacity;
+            let end = start + size;
+            if end <= self.capacity {
+                digest.update(&self.buffer[start..end]);
+            } else {
+                digest.update(&self.buffer[start..]);
+                digest.update(&self.buffer[..end - self.capacity]);
+            }
+        }
+        self.startpoint += size;
+        self.notify.notify();
+        self
+    }
+
+    // hash all data popped from now on
+    pub fn enable_digest(&mut self) -> &mut Self {
+        if self.digest.is_none() {
+            self.digest = Some(Sha256::new());
+        }
+        self
+    }
+
+    // SHA-256 of the data popped so far
+    pub fn digest(&self) -> Option<[u8; 32]> {
+        self.digest
+            .as_ref()
+            .map(|digest| digest.clone().finalize().into())
     }
 
     pub fn set(&mut self, pos: usize, data: &[u8]) -> &mut Self {
@@ -72,7 +150,7 @@ impl DataFIFO {
         self
     }
 
-    pub fn get(&mut self, pos: usize, size: u32) -> Vec<u8> {
+    pub fn get(&self, pos: usize, size: u32) -> Vec<u8> {
         let mut size = size as usize;
         if pos + size > self.endpoint {
             size = self.endpoint - pos;
@@ -90,6 +168,24 @@ impl DataFIFO {
         }
     }
 
+    // Borrow like get, without the copy: the data in one part, or in two
+    // where it wraps around the end of the buffer.
+    pub fn as_slices(&self, pos: usize, size: u32) -> (&[u8], &[u8]) {
+        let size = (size as usize).min(self.endpoint.saturating_sub(pos));
+        self.peek(pos, size)
+    }
+
+    // borrow without checking endpoint, used for data of slices still in reception
+    pub fn peek(&self, pos: usize, size: usize) -> (&[u8], &[u8]) {
+        let start = pos % self.capacity;
+        let end = start + size;
+        if end <= self.capacity {
+            (&self.buffer[start..end], &[])
+        } else {
+            (&self.buffer[start..], &self.buffer[..end - self.capacity])
+        }
+    }
+
     // reserve buffer for received data from server
     pub fn reserve(&mut self, size: u32) -> usize {
         let base = self.slicebase;
@@ -101,9 +197,19 @@ impl DataFIFO {
             self.endpoint,
             self.slicebase
         );
+        self.notify.notify();
         base
     }
 
+    // Make the data of a completed slice readable before the next one is reserved.
+    pub fn commit(&mut self, end: usize) -> &mut Self {
+        if end > self.endpoint && end <= self.slicebase {
+            self.endpoint = end;
+            self.notify.notify();
+        }
+        self
+    }
+
     // Allocate as slice to transfer data read from file
     pub fn assign(&mut self, size: u32) -> usize {
         let base = self.slicebase;
@@ -116,6 +222,7 @@ impl DataFIFO {
 
     pub fn drain(&mut self, size: usize) -> &mut Self {
         self.startpoint += size;
+        self.notify.notify();
         self
     }
 
@@ -148,8 +255,36 @@ impl DataFIFO {
         self.endpoint
     }
 
+    // bytes a late joiner skips so that its writes start sector aligned
+    pub fn image_pad(offset: usize) -> usize {
+        (SECTOR_SIZE - offset % SECTOR_SIZE) % SECTOR_SIZE
+    }
+
+    // The FIFO carries a looped image of image_size bytes which starts at offset.
+    pub fn set_image(&mut self, offset: usize, image_size: usize) -> &mut Self {
+        self.image = Some((offset, image_size));
+        self
+    }
+
+    // Where the data at the read pointer belongs in a looped image.
+    // Returns (image offset, bytes until the image wraps, bytes to skip)
+    pub fn image_pos(&self) -> Option<(usize, usize, usize)> {
+        let (join, size) = self.image?;
+        let pad = Self::image_pad(join);
+        let pos = self.startpoint;
+        if pos < pad {
+            return Some((0, 0, pad - pos));
+        }
+        if pos >= pad + size {
+            return Some((0, 0, self.len()));
+        }
+        let offset = (join + pos) % size;
+        Some((offset, (size - offset).min(pad + size - pos), 0))
+    }
+
     pub fn close(&mut self) -> &mut Self {
         self.close = true;
+        self.notify.notify();
         self
     }
 
@@ -8,7 +8,7 @@ use windows_sys::{
 use std::{
     ffi::c_void,
     fmt,
-    io::{Read, Write},
+    io::{Read, Seek, SeekFrom, Write},
     mem::{size_of_val, zeroed},
     ptr::{null, null_mut},
 };
@@ -404,6 +404,34 @@ impl Write for Disk {
     }
 }
 
+impl Seek for Disk {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        if let SeekFrom::Start(offset) = pos {
+            if self.fua.is_some() {
+                // scsi writes address the lba by write_offset
+                self.write_offset = offset;
+                return Ok(offset);
+            }
+        }
+        let (distance, method) = match pos {
+            SeekFrom::Start(offset) => (offset as i64, FILE_BEGIN),
+            SeekFrom::Current(offset) => (offset, FILE_CURRENT),
+            SeekFrom::End(offset) => (offset, FILE_END),
+        };
+        let mut new_pos = 0i64;
+        let res = unsafe { SetFilePointerEx(self.handle, distance, &mut new_pos, method) };
+        if res == 0 {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("Error code: {:#08x}", last_error()),
+            ))
+        } else {
+            self.write_offset = new_pos as u64;
+            Ok(new_pos as u64)
+        }
+    }
+}
+
 unsafe impl Send for Disk {}
 unsafe impl Sync for Disk {}
 
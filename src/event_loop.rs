@@ -0,0 +1,270 @@
+use crossterm::event::{self, Event, KeyCode, KeyEvent};
+use mio::{Events, Poll, Registry, Token, Waker};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::transport::Transport;
+
+/// Token the transport registers its sockets with.
+pub const SOCKET: Token = Token(0);
+// token of the waker, for the FIFOs, the keyboard and in-process transports
+const WAKE: Token = Token(1);
+
+/// What a transport registered with an event loop, kept while the loop lasts.
+pub type Registration = Box<dyn Registered>;
+
+/// Sockets of a transport registered with the poll of an event loop.
+pub trait Registered: Send + fmt::Debug {
+    /// Arm the sockets again before the loop waits for them. mio on Windows
+    /// reports a socket no more after an event until an I/O call on its own
+    /// handle would block, which a duplicate standing in for a socket never
+    /// sees.
+    fn rearm(&mut self, _registry: &Registry) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Registered for () {}
+
+impl Registered for Vec<Registration> {
+    fn rearm(&mut self, registry: &Registry) -> io::Result<()> {
+        self.iter_mut()
+            .try_for_each(|registration| registration.rearm(registry))
+    }
+}
+
+/// Why `EventLoop::wait` returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ready {
+    Readable,
+    Woken,
+    Deadline,
+}
+
+/// Poll of the socket of a `MultiCast`, woken as well when a FIFO it watches
+/// changes or a key is pressed.
+#[derive(Debug)]
+pub struct EventLoop {
+    poll: Poll,
+    events: Events,
+    waker: Arc<Waker>,
+    // a wakeup seen while waiting for room to send, reported by the next wait
+    woken: bool,
+    registration: Registration,
+}
+
+impl EventLoop {
+    /// Register the transport and make it nonblocking.
+    pub fn new(transport: &dyn Transport) -> io::Result<Self> {
+        let events = Self::register(transport)?;
+        transport.set_nonblocking(true)?;
+        KEYBOARD
+            .lock()
+            .unwrap()
+            .wakers
+            .push(Arc::downgrade(&events.waker));
+        Ok(events)
+    }
+
+    // Register the transport and leave it as it is.
+    fn register(transport: &dyn Transport) -> io::Result<Self> {
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKE)?);
+        let registration = transport.register(poll.registry(), SOCKET, &waker)?;
+        Ok(Self {
+            poll,
+            events: Events::with_capacity(16),
+            waker,
+            woken: false,
+            registration,
+        })
+    }
+
+    pub fn waker(&self) -> Arc<Waker> {
+        Arc::clone(&self.waker)
+    }
+
+    // Poll until the deadline, false when it passed without an event.
+    fn poll(&mut self, deadline: Option<Instant>) -> io::Result<bool> {
+        self.registration.rearm(self.poll.registry())?;
+        let timeout = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        match self.poll.poll(&mut self.events, timeout) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => return Ok(true),
+            Err(err) => return Err(err),
+        }
+        Ok(!self.events.is_empty() || deadline.is_none_or(|deadline| Instant::now() < deadline))
+    }
+
+    /// Wait until the socket is readable, the loop is woken or the deadline
+    /// passed. A readable socket has to be read until it would block before
+    /// it is reported again.
+    pub fn wait(&mut self, deadline: Option<Instant>) -> io::Result<Ready> {
+        loop {
+            if std::mem::take(&mut self.woken) {
+                return Ok(Ready::Woken);
+            }
+            if !self.poll(deadline)? {
+                return Ok(Ready::Deadline);
+            }
+            let mut readable = false;
+            for event in self.events.iter() {
+                match event.token() {
+                    WAKE => self.woken = true,
+                    _ => readable |= event.is_readable() || event.is_error(),
+                }
+            }
+            if readable {
+                return Ok(Ready::Readable);
+            }
+        }
+    }
+
+    /// Wait until the socket takes datagrams again, at most until the
+    /// deadline.
+    pub fn wait_writable(&mut self, deadline: Instant) -> io::Result<()> {
+        while self.poll(Some(deadline))? {
+            let mut writable = false;
+            for event in self.events.iter() {
+                match event.token() {
+                    WAKE => self.woken = true,
+                    _ => writable |= event.is_writable() || event.is_error(),
+                }
+            }
+            if writable {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Event loop of a transport which reads nonblocking sockets of its own and
+/// waits for them while it is blocking itself, set up when first needed.
+#[derive(Debug, Default)]
+pub struct Waiter(Mutex<Option<EventLoop>>);
+
+impl Waiter {
+    /// Wait until the sockets of the transport are readable, at most until
+    /// the deadline.
+    pub fn wait(&self, transport: &dyn Transport, deadline: Instant) -> io::Result<()> {
+        let mut events = self.0.lock().unwrap();
+        if events.is_none() {
+            *events = Some(EventLoop::register(transport)?);
+        }
+        events.as_mut().unwrap().wait(Some(deadline))?;
+        Ok(())
+    }
+}
+
+/// Waker of the event loop a transport is registered with, for the threads
+/// and in-process networks which queue its datagrams.
+#[derive(Debug, Clone, Default)]
+pub struct SharedWaker(Arc<Mutex<Option<Arc<Waker>>>>);
+
+impl SharedWaker {
+    pub fn set(&self, waker: &Arc<Waker>) {
+        *self.0.lock().unwrap() = Some(Arc::clone(waker));
+    }
+
+    pub fn wake(&self) {
+        if let Some(waker) = &*self.0.lock().unwrap() {
+            let _ = waker.wake();
+        }
+    }
+}
+
+fn wake(wakers: &mut Vec<Weak<Waker>>) {
+    wakers.retain(|waker| match waker.upgrade() {
+        Some(waker) => {
+            let _ = waker.wake();
+            true
+        }
+        None => false,
+    });
+}
+
+/// Counter of the changes of a FIFO. Threads wait for the next change, event
+/// loops which watch it are woken.
+#[derive(Debug, Default)]
+pub struct Notify {
+    changes: Mutex<u64>,
+    changed: Condvar,
+    wakers: Mutex<Vec<Weak<Waker>>>,
+}
+
+impl Notify {
+    /// Read before looking at the FIFO, so that `wait` misses no change made
+    /// in between.
+    pub fn count(&self) -> u64 {
+        *self.changes.lock().unwrap()
+    }
+
+    pub fn notify(&self) {
+        *self.changes.lock().unwrap() += 1;
+        self.changed.notify_all();
+        wake(&mut self.wakers.lock().unwrap());
+    }
+
+    /// Block until there was a change after the count seen.
+    pub fn wait(&self, seen: u64) {
+        let changes = self.changes.lock().unwrap();
+        drop(self.changed.wait_while(changes, |changes| *changes == seen));
+    }
+
+    pub fn watch(&self, waker: &Arc<Waker>) {
+        self.wakers.lock().unwrap().push(Arc::downgrade(waker));
+    }
+}
+
+// Keys pressed and the event loops to wake for them. The console is read by a
+// thread of its own, started by the first look at the keyboard.
+struct Keyboard {
+    started: bool,
+    keys: VecDeque<char>,
+    wakers: Vec<Weak<Waker>>,
+}
+
+static KEYBOARD: Mutex<Keyboard> = Mutex::new(Keyboard {
+    started: false,
+    keys: VecDeque::new(),
+    wakers: Vec::new(),
+});
+static KEY_PRESSED: Condvar = Condvar::new();
+
+/// Next key pressed, waiting up to timeout for one. None without a console.
+pub fn key(timeout: Duration) -> Option<char> {
+    let mut keyboard = KEYBOARD.lock().unwrap();
+    if !keyboard.started {
+        keyboard.started = true;
+        thread::spawn(read_keys);
+    }
+    let (mut keyboard, _) = KEY_PRESSED
+        .wait_timeout_while(keyboard, timeout, |keyboard| keyboard.keys.is_empty())
+        .unwrap();
+    keyboard.keys.pop_front()
+}
+
+fn read_keys() {
+    while let Ok(event) = event::read() {
+        let key = match event {
+            Event::Key(KeyEvent {
+                code: KeyCode::Char(c),
+                ..
+            }) => c,
+            Event::Key(KeyEvent {
+                code: KeyCode::Enter,
+                ..
+            }) => '\r',
+            _ => continue,
+        };
+        let mut keyboard = KEYBOARD.lock().unwrap();
+        keyboard.keys.push_back(key);
+        wake(&mut keyboard.wakers);
+        KEY_PRESSED.notify_all();
+    }
+}
@@ -0,0 +1,215 @@
+use log::{debug, warn};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+use crate::datafifo::DataFIFO;
+use crate::slice::Slice;
+
+// GF(2^8) limits data + parity shards of one stripe to 256
+const MAX_SHARDS: u32 = 256;
+
+/// Forward error correction settings: every slice is interleaved into `stripes`
+/// stripes (block n belongs to stripe n % stripes) and each stripe is protected
+/// by `redundancy` Reed-Solomon blocks.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Fec {
+    pub stripes: u32,
+    pub redundancy: u32,
+}
+
+impl Fec {
+    pub fn new(stripes: u32, redundancy: u32) -> Self {
+        Self {
+            stripes,
+            redundancy,
+        }
+    }
+
+    /// Parse a "stripes/redundancy" option string. ex) 8/4
+    pub fn parse(spec: &str) -> Result<Self, &'static str> {
+        let (stripes, redundancy) = spec
+            .split_once('/')
+            .ok_or("FEC must be given as stripes/redundancy")?;
+        let stripes: u32 = stripes.trim().parse().map_err(|_| "Invalid FEC stripes")?;
+        let redundancy: u32 = redundancy
+            .trim()
+            .parse()
+            .map_err(|_| "Invalid FEC redundancy")?;
+        if stripes == 0 || stripes > u16::MAX as u32 {
+            return Err("FEC stripes out of range");
+        }
+        if redundancy == 0 || redundancy >= MAX_SHARDS {
+            return Err("FEC redundancy out of range");
+        }
+        Ok(Self::new(stripes, redundancy))
+    }
+
+    /// Number of stripes used for a slice. It is raised above the configured
+    /// value when a stripe would otherwise hold more shards than GF(2^8) allows.
+    pub fn stripes_for(&self, blocks_in_slice: u32) -> u32 {
+        let max_data = MAX_SHARDS - self.redundancy;
+        self.stripes
+            .max(blocks_in_slice.div_ceil(max_data))
+            .min(u16::MAX as u32)
+    }
+}
+
+/// Reed-Solomon encoder of the stripes of a slice. The shard buffers are kept
+/// from slice to slice, so encoding allocates nothing once they are sized.
+#[derive(Debug, Default)]
+pub struct Encoder {
+    rs: Option<ReedSolomon>,
+    redundancy: usize,
+    data: Vec<Vec<u8>>,
+    // redundancy blocks, stripe after stripe
+    parity: Vec<Vec<u8>>,
+}
+
+impl Encoder {
+    /// Compute the redundancy blocks of one stripe. The data blocks are given
+    /// as the two halves returned by `DataFIFO::as_slices` and padded to
+    /// `block_size`. Returns false when the stripe has no data block.
+    pub fn encode<'a>(
+        &mut self,
+        stripe: u32,
+        redundancy: u32,
+        block_size: usize,
+        blocks: impl Iterator<Item = (&'a [u8], &'a [u8])>,
+    ) -> bool {
+        let mut count = 0;
+        for (data, wrapped) in blocks {
+            if self.data.len() == count {
+                self.data.push(Vec::with_capacity(block_size));
+            }
+            let shard = &mut self.data[count];
+            shard.clear();
+            shard.extend_from_slice(data);
+            shard.extend_from_slice(wrapped);
+            shard.resize(block_size, 0);
+            count += 1;
+        }
+        if count == 0 {
+            return false;
+        }
+        let redundancy = redundancy as usize;
+        // the last stripes of a slice may hold one block less
+        if !matches!(&self.rs, Some(rs) if rs.data_shard_count() == count
+            && rs.parity_shard_count() == redundancy)
+        {
+            self.rs = ReedSolomon::new(count, redundancy).ok();
+        }
+        let rs = match &self.rs {
+            Some(rs) => rs,
+            None => return false,
+        };
+        self.redundancy = redundancy;
+        let start = stripe as usize * redundancy;
+        if self.parity.len() < start + redundancy {
+            self.parity.resize_with(start + redundancy, Vec::new);
+        }
+        let parity = &mut self.parity[start..start + redundancy];
+        for block in parity.iter_mut() {
+            block.resize(block_size, 0);
+        }
+        rs.encode_sep(&self.data[..count], parity).is_ok()
+    }
+
+    /// The `index`th redundancy block of `stripe`, as computed by `encode`.
+    pub fn parity(&self, stripe: u32, index: u32) -> &[u8] {
+        &self.parity[stripe as usize * self.redundancy + index as usize]
+    }
+}
+
+/// Block numbers of the data blocks belonging to `stripe`.
+pub fn stripe_blocks(blocks_in_slice: u32, stripes: u32, stripe: u32) -> Vec<u32> {
+    (stripe..blocks_in_slice)
+        .step_by(stripes as usize)
+        .collect()
+}
+
+/// Block number carried by the `index`th redundancy block of `stripe`.
+pub fn fec_blockno(stripes: u32, stripe: u32, index: u32) -> u32 {
+    index * stripes + stripe
+}
+
+/// Rebuild missing data blocks of a slice from the received FEC blocks.
+/// Recovered blocks are stored in the FIFO and marked as received.
+/// Returns the number of recovered blocks.
+pub fn recover(slice: &mut Slice, data_fifo: &mut DataFIFO) -> u32 {
+    let stripes = slice.fec_stripes;
+    if stripes == 0 || slice.fec_blocks.is_empty() || slice.is_completed() {
+        return 0;
+    }
+    let block_size = slice.block_size() as usize;
+    let mut recovered = 0;
+    for stripe in 0..stripes {
+        let blocks = stripe_blocks(slice.blocks_in_slice, stripes, stripe);
+        let missing: Vec<usize> = blocks
+            .iter()
+            .enumerate()
+            .filter(|(_, &block_no)| !slice.retransmit.map.get(block_no as usize))
+            .map(|(idx, _)| idx)
+            .collect();
+        if missing.is_empty() {
+            continue;
+        }
+        let mut parity: Vec<Option<Vec<u8>>> = Vec::new();
+        for (&blockno, data) in slice.fec_blocks.iter() {
+            if blockno % stripes != stripe {
+                continue;
+            }
+            let index = (blockno / stripes) as usize;
+            if parity.len() <= index {
+                parity.resize(index + 1, None);
+            }
+            let mut data = data.clone();
+            data.resize(block_size, 0);
+            parity[index] = Some(data);
+        }
+        if parity.iter().flatten().count() < missing.len() {
+            continue;
+        }
+        let mut shards: Vec<Option<Vec<u8>>> = blocks
+            .iter()
+            .map(|&block_no| {
+                if slice.retransmit.map.get(block_no as usize) {
+                    let len = slice.block_len(block_no) as usize;
+                    let (data, wrapped) = data_fifo.peek(slice.get_block_pos(block_no), len);
+                    let mut data = [data, wrapped].concat();
+                    data.resize(block_size, 0);
+                    Some(data)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        shards.append(&mut parity);
+        let rs = match ReedSolomon::new(blocks.len(), shards.len() - blocks.len()) {
+            Ok(rs) => rs,
+            Err(_) => continue,
+        };
+        if let Err(e) = rs.reconstruct_data(&mut shards) {
+            warn!(
+                "FEC reconstruct failed slice {} stripe {stripe}: {:?}",
+                slice.slice_no, e
+            );
+            continue;
+        }
+        for idx in missing {
+            let block_no = blocks[idx];
+            if let Some(data) = &shards[idx] {
+                let len = slice.block_len(block_no) as usize;
+                let pos = slice.get_block_pos(block_no);
+                data_fifo.set(pos, &data[..len]);
+                slice.update_block(block_no);
+                recovered += 1;
+            }
+        }
+    }
+    if recovered > 0 {
+        debug!(
+            "FEC recovered {recovered} blocks in slice {}",
+            slice.slice_no
+        );
+    }
+    recovered
+}
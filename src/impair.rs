@@ -0,0 +1,208 @@
+use mio::{Registry, Token, Waker};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::event_loop::{Registration, Waiter};
+use crate::transport::Transport;
+
+// how long recv_from waits for a datagram, like the UDP sockets
+const READ_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// Faults injected into the datagrams a receiver gets. Probabilities are per
+/// datagram, all choices come from a generator seeded with `seed`.
+#[derive(Debug, Clone, Default)]
+pub struct Impairment {
+    /// random loss
+    pub loss: f64,
+    /// chance to start a loss burst, all datagrams of a burst are lost
+    pub burst: f64,
+    /// how long a burst lasts, an outage of the link rather than a number of
+    /// datagrams, so that it ends while the sender waits for answers
+    pub burst_len: Duration,
+    /// chance that a datagram is held back and overtaken by later ones
+    pub reorder: f64,
+    pub duplicate: f64,
+    pub delay: Duration,
+    /// random extra delay up to this, which reorders datagrams as well
+    pub jitter: Duration,
+    pub seed: u64,
+}
+
+type Held = (Instant, u64, Vec<u8>, SocketAddr);
+
+#[derive(Debug)]
+struct State {
+    rng: StdRng,
+    burst_end: Option<Instant>,
+    // datagrams delayed until the instant, in the order received when due together
+    held: BinaryHeap<Reverse<Held>>,
+    received: u64,
+    nonblocking: bool,
+}
+
+/// Datagrams an `Impaired` transport lost, duplicated and reordered.
+#[derive(Debug, Default)]
+pub struct ImpairStats {
+    pub lost: AtomicUsize,
+    pub duplicated: AtomicUsize,
+    pub reordered: AtomicUsize,
+}
+
+/// Transport which impairs what another one receives.
+#[derive(Debug)]
+pub struct Impaired<T: Transport> {
+    inner: T,
+    impairment: Impairment,
+    state: Mutex<State>,
+    stats: Arc<ImpairStats>,
+    waiter: Waiter,
+}
+
+impl<T: Transport> Impaired<T> {
+    pub fn new(inner: T, impairment: Impairment) -> io::Result<Self> {
+        // polled, so that held datagrams are released in time
+        inner.set_nonblocking(true)?;
+        Ok(Self {
+            inner,
+            state: Mutex::new(State {
+                rng: StdRng::seed_from_u64(impairment.seed),
+                burst_end: None,
+                held: BinaryHeap::new(),
+                received: 0,
+                nonblocking: false,
+            }),
+            impairment,
+            stats: Arc::default(),
+            waiter: Waiter::default(),
+        })
+    }
+
+    // Decide the fate of a received datagram: dropped, or held until the
+    // returned instants, twice if duplicated.
+    fn impair(&self, state: &mut State, now: Instant) -> Vec<Instant> {
+        let imp = &self.impairment;
+        if state.burst_end.is_some_and(|end| now < end) {
+            self.stats.lost.fetch_add(1, Ordering::Relaxed);
+            return Vec::new();
+        }
+        if imp.burst > 0.0 && state.rng.gen_bool(imp.burst) {
+            state.burst_end = Some(now + imp.burst_len);
+            self.stats.lost.fetch_add(1, Ordering::Relaxed);
+            return Vec::new();
+        }
+        if imp.loss > 0.0 && state.rng.gen_bool(imp.loss) {
+            self.stats.lost.fetch_add(1, Ordering::Relaxed);
+            return Vec::new();
+        }
+        let mut due = now + imp.delay;
+        if !imp.jitter.is_zero() {
+            due += imp.jitter.mul_f64(state.rng.gen());
+        }
+        if imp.reorder > 0.0 && state.rng.gen_bool(imp.reorder) {
+            // long enough for the following blocks of the slice to overtake it
+            due += Duration::from_millis(5) + imp.jitter;
+            self.stats.reordered.fetch_add(1, Ordering::Relaxed);
+        }
+        if imp.duplicate > 0.0 && state.rng.gen_bool(imp.duplicate) {
+            self.stats.duplicated.fetch_add(1, Ordering::Relaxed);
+            return vec![due, due];
+        }
+        vec![due]
+    }
+
+    /// Counters which stay available when the transport is handed over.
+    pub fn stats(&self) -> Arc<ImpairStats> {
+        Arc::clone(&self.stats)
+    }
+}
+
+impl<T: Transport> Transport for Impaired<T> {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.inner.send_to(buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let deadline = Instant::now() + READ_TIMEOUT;
+        loop {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            loop {
+                // buf holds the datagram until the impairment decided about it
+                match self.inner.recv_from(buf) {
+                    Ok((size, from)) => {
+                        for due in self.impair(&mut state, now) {
+                            state.received += 1;
+                            let seq = state.received;
+                            state
+                                .held
+                                .push(Reverse((due, seq, buf[..size].to_vec(), from)));
+                        }
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(err) => return Err(err),
+                }
+            }
+            if matches!(state.held.peek(), Some(Reverse((due, ..))) if *due <= now) {
+                let Reverse((_, _, data, from)) = state.held.pop().unwrap();
+                let size = data.len().min(buf.len());
+                buf[..size].copy_from_slice(&data[..size]);
+                return Ok((size, from));
+            }
+            if state.nonblocking {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let due = match state.held.peek() {
+                Some(Reverse((due, ..))) => deadline.min(*due),
+                None => deadline,
+            };
+            drop(state);
+            if Instant::now() >= deadline {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            // until the next datagram arrives or a held one is due
+            self.waiter.wait(&self.inner, due)?;
+        }
+    }
+
+    fn join_multicast(&self, group: IpAddr, myip: IpAddr, ifindex: u32) -> io::Result<()> {
+        self.inner.join_multicast(group, myip, ifindex)
+    }
+
+    fn set_broadcast(&self, broadcast: bool) -> io::Result<()> {
+        self.inner.set_broadcast(broadcast)
+    }
+
+    fn set_multicast_ttl(&self, ttl: u32) -> io::Result<()> {
+        self.inner.set_multicast_ttl(ttl)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.state.lock().unwrap().nonblocking = nonblocking;
+        Ok(())
+    }
+
+    fn path_mtu(&self, addr: SocketAddr) -> io::Result<usize> {
+        self.inner.path_mtu(addr)
+    }
+
+    fn register(
+        &self,
+        registry: &Registry,
+        token: Token,
+        waker: &Arc<Waker>,
+    ) -> io::Result<Registration> {
+        self.inner.register(registry, token, waker)
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        let state = self.state.lock().unwrap();
+        state.held.peek().map(|Reverse((due, ..))| *due)
+    }
+}
@@ -1,14 +1,24 @@
-use crossterm::event::{self, KeyCode, KeyEvent};
-
+pub mod auth;
 pub mod bitarray;
+pub mod congestion;
+pub mod crypt;
 pub mod datafifo;
 pub mod dev;
+pub mod event_loop;
+pub mod fec;
+pub mod impair;
+pub mod loopback;
 pub mod multicast;
+pub mod multinic;
+pub mod pacer;
 pub mod packet;
 pub mod receiver_a;
 pub mod receiver_s;
 pub mod sender;
 pub mod slice;
+pub mod tcp;
+pub mod transport;
+pub mod udpcast;
 // pub mod statistics;
 
 pub const RUNNING: bool = true;
@@ -23,6 +33,11 @@ pub const MAX_READ_PIPE: usize = 1024 * MI_BYTES;
 pub const MAX_WRITE_PIPE: usize = 128 * MI_BYTES;
 
 pub const BLOCK_SIZE: u32 = 1456;
+// smaller blocks would be mostly header, the largest one still fits into a
+// UDP datagram with its header and tags
+pub const MIN_BLOCK_SIZE: u32 = 256;
+pub const MAX_BLOCK_SIZE: u32 = 65507 - packet::HEADER_MAX as u32;
+// receive buffer for blocks of BLOCK_SIZE, larger ones get buffers to fit
 pub const UDP_PACK_SIZE: usize = 2048;
 
 pub const MAX_CLIENTS: u32 = 128;
@@ -30,11 +45,57 @@ pub const MAX_SLICE_SIZE: u32 = 2048;
 pub const BITS_PER_CHAR: u32 = 8;
 
 pub const CAP_NEW_GEN: u32 = 0x0001;
+pub const CAP_FEC: u32 = 0x0002;
 pub const CAP_BIG_ENDIAN: u32 = 0x0008;
 pub const CAP_LITTLE_ENDIAN: u32 = 0x0010;
 pub const CAP_ASYNC: u32 = 0x0020;
-pub const SENDER_CAPABILITIES: u32 = CAP_NEW_GEN | CAP_BIG_ENDIAN;
-pub const RECEIVER_CAPABILITIES: u32 = CAP_NEW_GEN | CAP_BIG_ENDIAN;
+pub const CAP_STREAMING: u32 = 0x0040;
+// CRC32C of the slice data follows the ready set of a reqack
+pub const CAP_CHECKSUM: u32 = 0x0080;
+// SHA-256 of the image in the final reqack, receivers answer with a report
+pub const CAP_DIGEST: u32 = 0x0100;
+// data and FEC payloads are encrypted, the session salt follows hello and connect reply
+pub const CAP_ENCRYPTED: u32 = 0x0200;
+pub const SENDER_CAPABILITIES: u32 =
+    CAP_NEW_GEN | CAP_BIG_ENDIAN | CAP_FEC | CAP_STREAMING | CAP_CHECKSUM | CAP_DIGEST;
+// receivers without a key refuse an encrypted session themselves
+pub const RECEIVER_CAPABILITIES: u32 = CAP_NEW_GEN
+    | CAP_BIG_ENDIAN
+    | CAP_FEC
+    | CAP_STREAMING
+    | CAP_CHECKSUM
+    | CAP_DIGEST
+    | CAP_ENCRYPTED;
+// capabilities every peer of a session must have, the others are optional features
+pub const CAP_REQUIRED: u32 = CAP_NEW_GEN | CAP_BIG_ENDIAN;
+
+pub fn capabilities_str(capabilities: u32) -> String {
+    let names = [
+        (CAP_NEW_GEN, "new_gen"),
+        (CAP_FEC, "fec"),
+        (CAP_BIG_ENDIAN, "big_endian"),
+        (CAP_LITTLE_ENDIAN, "little_endian"),
+        (CAP_ASYNC, "async"),
+        (CAP_STREAMING, "streaming"),
+        (CAP_CHECKSUM, "checksum"),
+        (CAP_DIGEST, "digest"),
+        (CAP_ENCRYPTED, "encrypted"),
+    ];
+    let mut list: Vec<String> = names
+        .iter()
+        .filter(|(cap, _)| capabilities & cap != 0)
+        .map(|(_, name)| name.to_string())
+        .collect();
+    let known = names.iter().fold(0, |acc, (cap, _)| acc | cap);
+    if capabilities & !known != 0 {
+        list.push(format!("{:#x}", capabilities & !known));
+    }
+    format!("[{}]", list.join(", "))
+}
+
+pub fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
 
 pub const FLAG_PASSIVE: u16 = 0x0010;
 pub const FLAG_NOSYNC: u16 = 0x0040;
@@ -47,21 +108,9 @@ pub const PORTBASE: u16 = 9000;
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+// None without a console as well, e.g. in a session run by a test
 pub fn getch(secs: u64) -> Option<char> {
-    if event::poll(std::time::Duration::from_secs(secs)).unwrap() {
-        if let event::Event::Key(KeyEvent {
-            code, modifiers: _, ..
-        }) = event::read().unwrap()
-        {
-            if let KeyCode::Char(c) = code {
-                return Some(c);
-            }
-            if let KeyCode::Enter = code {
-                return Some('\r');
-            }
-        }
-    }
-    return None;
+    event_loop::key(std::time::Duration::from_secs(secs))
 }
 
 use std::time::{Duration, Instant};
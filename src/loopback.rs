@@ -0,0 +1,231 @@
+use mio::{Registry, Token, Waker};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::event_loop::{Registration, SharedWaker};
+use crate::impair::{ImpairStats, Impaired, Impairment};
+use crate::multicast::MultiCast;
+use crate::transport::Transport;
+use crate::*;
+
+// hosts of the loopback network are 192.0.2.x, the documentation network
+const LOOPBACK_NET: [u8; 3] = [192, 0, 2];
+const LOOPBACK_BROADCAST: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 255);
+// same read timeout as the UDP sockets
+const READ_TIMEOUT: Duration = Duration::from_millis(50);
+
+type Datagram = (Vec<u8>, SocketAddr);
+
+#[derive(Debug)]
+struct Port {
+    queue: Sender<Datagram>,
+    groups: Vec<IpAddr>,
+    waker: SharedWaker,
+}
+
+/// In-process network on which a sender and its receivers run a session
+/// without touching the real one.
+///
+/// Datagrams to a multicast group reach every socket which joined it,
+/// datagrams to 192.0.2.255 every other socket bound to that port and all
+/// others the socket bound to the destination. Nothing is lost or reordered.
+#[derive(Debug, Clone, Default)]
+pub struct Loopback {
+    ports: Arc<Mutex<HashMap<SocketAddr, Port>>>,
+}
+
+impl Loopback {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn addr(host: u8) -> Ipv4Addr {
+        let [a, b, c] = LOOPBACK_NET;
+        Ipv4Addr::new(a, b, c, host)
+    }
+
+    pub fn bind(&self, addr: SocketAddr) -> io::Result<LoopbackSocket> {
+        let mut ports = self.ports.lock().unwrap();
+        if ports.contains_key(&addr) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{addr} is already bound"),
+            ));
+        }
+        let (queue, incoming) = mpsc::channel();
+        let waker = SharedWaker::default();
+        ports.insert(
+            addr,
+            Port {
+                queue,
+                groups: Vec::new(),
+                waker: waker.clone(),
+            },
+        );
+        Ok(LoopbackSocket {
+            network: self.clone(),
+            addr,
+            incoming,
+            nonblocking: AtomicBool::new(false),
+            waker,
+        })
+    }
+
+    /// Sender socket of the host 192.0.2.host with the default ports.
+    pub fn sender(&self, host: u8) -> io::Result<MultiCast> {
+        self.endpoint(host, PORTBASE + 1, PORTBASE)
+    }
+
+    /// Receiver socket of the host 192.0.2.host with the default ports.
+    pub fn receiver(&self, host: u8) -> io::Result<MultiCast> {
+        self.endpoint(host, PORTBASE, PORTBASE + 1)
+    }
+
+    /// Receiver socket which loses, reorders, duplicates and delays what it
+    /// receives, with the counters of what it did.
+    pub fn impaired_receiver(
+        &self,
+        host: u8,
+        impairment: Impairment,
+    ) -> io::Result<(MultiCast, Arc<ImpairStats>)> {
+        let myip = Self::addr(host);
+        let socket = self.bind(SocketAddrV4::new(myip, PORTBASE).into())?;
+        let socket = Impaired::new(socket, impairment)?;
+        let stats = socket.stats();
+        Ok((
+            self.multicast(Box::new(socket), myip, PORTBASE, PORTBASE + 1),
+            stats,
+        ))
+    }
+
+    fn endpoint(&self, host: u8, port: u16, peer_port: u16) -> io::Result<MultiCast> {
+        let myip = Self::addr(host);
+        let socket = self.bind(SocketAddrV4::new(myip, port).into())?;
+        Ok(self.multicast(Box::new(socket), myip, port, peer_port))
+    }
+
+    fn multicast(
+        &self,
+        socket: Box<dyn Transport>,
+        myip: Ipv4Addr,
+        port: u16,
+        peer_port: u16,
+    ) -> MultiCast {
+        let group = Ipv4Addr::from(u32::from(myip) & 0x07ffffff | 0xe8000000);
+        MultiCast::new(
+            socket,
+            0,
+            SocketAddrV4::new(myip, port).into(),
+            SocketAddrV4::new(LOOPBACK_BROADCAST, peer_port).into(),
+            SocketAddrV4::new(group, PORTBASE).into(),
+        )
+    }
+
+    fn deliver(&self, data: &[u8], from: SocketAddr, to: SocketAddr) {
+        let ports = self.ports.lock().unwrap();
+        let broadcast = to.ip() == IpAddr::V4(LOOPBACK_BROADCAST);
+        for (addr, port) in ports.iter() {
+            let reached = if to.ip().is_multicast() {
+                addr.port() == to.port() && port.groups.contains(&to.ip())
+            } else if broadcast {
+                addr.port() == to.port() && *addr != from
+            } else {
+                *addr == to
+            };
+            if reached {
+                // a dropped socket just misses the datagram
+                if port.queue.send((data.to_vec(), from)).is_ok() {
+                    port.waker.wake();
+                }
+            }
+        }
+    }
+}
+
+/// Socket bound to an address of a `Loopback` network.
+#[derive(Debug)]
+pub struct LoopbackSocket {
+    network: Loopback,
+    addr: SocketAddr,
+    incoming: Receiver<Datagram>,
+    nonblocking: AtomicBool,
+    waker: SharedWaker,
+}
+
+impl Transport for LoopbackSocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.network.deliver(buf, self.addr, addr);
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (data, from) = if self.nonblocking.load(Ordering::Relaxed) {
+            self.incoming.try_recv().map_err(|err| match err {
+                TryRecvError::Empty => io::Error::from(io::ErrorKind::WouldBlock),
+                TryRecvError::Disconnected => io::Error::from(io::ErrorKind::NotConnected),
+            })?
+        } else {
+            self.incoming
+                .recv_timeout(READ_TIMEOUT)
+                .map_err(|err| match err {
+                    RecvTimeoutError::Timeout => io::Error::from(io::ErrorKind::TimedOut),
+                    RecvTimeoutError::Disconnected => io::Error::from(io::ErrorKind::NotConnected),
+                })?
+        };
+        // like UDP, the rest of a datagram which does not fit is lost
+        let size = data.len().min(buf.len());
+        buf[..size].copy_from_slice(&data[..size]);
+        Ok((size, from))
+    }
+
+    fn join_multicast(&self, group: IpAddr, _myip: IpAddr, _ifindex: u32) -> io::Result<()> {
+        if !group.is_multicast() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no multicast group",
+            ));
+        }
+        let mut ports = self.network.ports.lock().unwrap();
+        let port = ports.get_mut(&self.addr).unwrap();
+        if !port.groups.contains(&group) {
+            port.groups.push(group);
+        }
+        Ok(())
+    }
+
+    fn set_broadcast(&self, _broadcast: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_multicast_ttl(&self, _ttl: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking.store(nonblocking, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn register(
+        &self,
+        _registry: &Registry,
+        _token: Token,
+        waker: &Arc<Waker>,
+    ) -> io::Result<Registration> {
+        self.waker.set(waker);
+        Ok(Box::new(()))
+    }
+}
+
+impl Drop for LoopbackSocket {
+    fn drop(&mut self) {
+        if let Ok(mut ports) = self.network.ports.lock() {
+            ports.remove(&self.addr);
+        }
+    }
+}
@@ -1,188 +1,780 @@
-use crate::packet::Message;
+use crate::auth::{Psk, TAG_LEN};
+use crate::event_loop::{EventLoop, Ready};
+use crate::packet::{Message, HEADER_MAX};
+use crate::transport::{Gather, Transport};
 use crate::*;
 
-use ipnet::Ipv4Net;
-use log::trace;
-use socket2::{Domain, Socket, Type};
+use default_net::Interface;
+use ipnet::{IpNet, Ipv4Net};
+use log::{debug, trace};
+use mio::Waker;
+use socket2::{Domain, SockRef, Socket, Type};
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::fmt;
 use std::io;
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
-use std::process::Command;
-use std::time::Duration;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-#[derive(Debug)]
-struct NetworkInterface {
-    ip_address: Option<String>,
-    subnet_mask: Option<String>,
+// how long recv_msg waits for a message
+const READ_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// Network interface selected on the command line: an interface name, one of
+/// its addresses, a network in CIDR notation or the position in the list of
+/// interfaces with an address of the session's family.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Nic {
+    Default,
+    Index(usize),
+    Ifindex(u32),
+    Name(String),
+    Addr(IpAddr),
+    Net(IpNet),
 }
 
-impl From<NetworkInterface> for Ipv4Net {
-    fn from(interface: NetworkInterface) -> Ipv4Net {
-        Ipv4Net::with_netmask(
-            interface
-                .ip_address
-                .expect("Can't get ip address")
-                .parse()
-                .expect("Unable to parse socket address"),
-            interface
-                .subnet_mask
-                .expect("Can't get subnet mask")
-                .parse()
-                .expect("Unable to parse socket address"),
-        )
-        .expect("Unable to parse socket address")
-    }
-}
-
-fn get_ipconfig() -> Vec<Ipv4Net> {
-    let mut interfaces: Vec<Ipv4Net> = Vec::new();
-    let mut current_interface: Option<NetworkInterface> = None;
-    let mut is_taken = false;
-
-    let output = Command::new("ipconfig")
-        .output()
-        .expect("Failed to execute ipconfig");
-
-    let output = String::from_utf8_lossy(&output.stdout);
-
-    for line in output.lines() {
-        if line.starts_with("Ethernet adapter") {
-            current_interface = Some(NetworkInterface {
-                ip_address: None,
-                subnet_mask: None,
-            });
-        } else if let Some(ref mut interface) = current_interface {
-            if line.trim().starts_with("IPv4 Address") {
-                let parts: Vec<&str> = line.trim().split(':').collect();
-                if let Some(ip_str) = parts.get(1) {
-                    interface.ip_address = Some(ip_str.trim().to_string());
-                    is_taken = true;
-                }
-            } else if line.trim().starts_with("Subnet Mask") {
-                let parts: Vec<&str> = line.trim().split(':').collect();
-                if let Some(subnet_mask_str) = parts.get(1) {
-                    interface.subnet_mask = Some(subnet_mask_str.trim().to_string());
-                }
-            } else if line.trim().is_empty() && is_taken {
-                if let Some(interface) = current_interface.take() {
-                    interfaces.push(Ipv4Net::from(interface));
-                    is_taken = false;
-                }
+impl Nic {
+    pub fn parse(nic: &str) -> Self {
+        if nic.is_empty() || nic == "default" {
+            Nic::Default
+        } else if let Ok(index) = nic.parse() {
+            Nic::Index(index)
+        } else if let Ok(addr) = nic.parse() {
+            Nic::Addr(addr)
+        } else if let Ok(net) = nic.parse() {
+            Nic::Net(net)
+        } else {
+            Nic::Name(nic.to_owned())
+        }
+    }
+
+    fn matches(&self, interface: &Interface) -> bool {
+        match self {
+            Nic::Default | Nic::Index(_) => false,
+            Nic::Ifindex(index) => interface.index == *index,
+            Nic::Name(name) => {
+                interface.name == *name || interface.friendly_name.as_ref() == Some(name)
             }
+            Nic::Addr(_) | Nic::Net(_) => addresses(interface).any(|addr| self.prefers(addr)),
+        }
+    }
+
+    // the address an interface is selected by, if any
+    fn prefers(&self, addr: IpAddr) -> bool {
+        match self {
+            Nic::Addr(selected) => *selected == addr,
+            Nic::Net(net) => net.contains(&addr),
+            _ => false,
+        }
+    }
+
+    fn find(&self, usable: impl Fn(&Interface) -> bool) -> io::Result<Interface> {
+        if *self == Nic::Default {
+            return default_net::get_default_interface()
+                .map_err(|err| io::Error::new(io::ErrorKind::NotFound, err));
+        }
+        let interfaces: Vec<Interface> = default_net::get_interfaces()
+            .into_iter()
+            .filter(usable)
+            .collect();
+        let found = match self {
+            Nic::Index(index) => interfaces.get(*index),
+            _ => interfaces.iter().find(|interface| self.matches(interface)),
+        };
+        found.cloned().ok_or_else(|| {
+            let available: Vec<String> = interfaces
+                .iter()
+                .enumerate()
+                .map(|(index, interface)| {
+                    let addrs: Vec<String> = addresses(interface).map(|a| a.to_string()).collect();
+                    format!("{index}: {} [{}]", interface.name, addrs.join(", "))
+                })
+                .collect();
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "no network interface matches {self}, available: {}",
+                    available.join("; ")
+                ),
+            )
+        })
+    }
+
+    /// The IPv4 network of the selected interface.
+    pub fn ipv4(&self) -> io::Result<Ipv4Net> {
+        let interface = self.find(|interface| !interface.ipv4.is_empty())?;
+        let net = interface
+            .ipv4
+            .iter()
+            .find(|net| self.prefers(net.addr.into()))
+            .or(interface.ipv4.first())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} has no IPv4 address", interface.name),
+                )
+            })?;
+        Ipv4Net::new(net.addr, net.prefix_len)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    // Interface index and address to use for an IPv6 session. Link scoped
+    // groups go with the link-local address of the interface.
+    fn ipv6(&self, scope: u8) -> io::Result<(u32, Ipv6Addr)> {
+        let interface = self.find(|interface| !interface.ipv6.is_empty())?;
+        let addrs: Vec<Ipv6Addr> = interface.ipv6.iter().map(|net| net.addr).collect();
+        let addr = addrs
+            .iter()
+            .find(|addr| self.prefers((**addr).into()))
+            .or(addrs
+                .iter()
+                .find(|addr| (addr.segments()[0] & 0xffc0 == 0xfe80) == (scope == SCOPE_LINK)))
+            .or(addrs.first())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} has no IPv6 address", interface.name),
+                )
+            })?;
+        Ok((interface.index, *addr))
+    }
+}
+
+impl fmt::Display for Nic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Nic::Default => write!(f, "the default interface"),
+            Nic::Index(index) => write!(f, "#{index}"),
+            Nic::Ifindex(index) => write!(f, "interface index {index}"),
+            Nic::Name(name) => write!(f, "'{name}'"),
+            Nic::Addr(addr) => write!(f, "{addr}"),
+            Nic::Net(net) => write!(f, "{net}"),
+        }
+    }
+}
+
+fn addresses(interface: &Interface) -> impl Iterator<Item = IpAddr> + '_ {
+    let ipv4 = interface.ipv4.iter().map(|net| IpAddr::V4(net.addr));
+    let ipv6 = interface.ipv6.iter().map(|net| IpAddr::V6(net.addr));
+    ipv4.chain(ipv6)
+}
+
+// IPv6 multicast scopes: ff02:: link-local and ff05:: site-local groups
+pub const SCOPE_LINK: u8 = 2;
+pub const SCOPE_SITE: u8 = 5;
+
+pub fn parse_scope(scope: &str) -> Result<u8, &'static str> {
+    match scope {
+        "link" => Ok(SCOPE_LINK),
+        "site" => Ok(SCOPE_SITE),
+        _ => match scope.parse::<u8>() {
+            Ok(scope) if scope > 0 && scope < 16 => Ok(scope),
+            _ => Err("Invalid multicast scope"),
+        },
+    }
+}
+
+// IPv6 has no broadcast, senders and receivers find each other on ff0S::6963:0:0:1
+fn ipv6_discovery_group(scope: u8) -> Ipv6Addr {
+    Ipv6Addr::new(0xff00 | scope as u16, 0, 0, 0, 0x6963, 0, 0, 1)
+}
+
+// ff0S::6963:1:xxxx:xxxx, the low 32 bits are taken from the interface address
+fn ipv6_session_group(scope: u8, addr: &Ipv6Addr) -> Ipv6Addr {
+    let segments = addr.segments();
+    Ipv6Addr::new(
+        0xff00 | scope as u16,
+        0,
+        0,
+        0,
+        0x6963,
+        1,
+        segments[6],
+        segments[7],
+    )
+}
+
+fn ipv6_socket(ifindex: u32, scope: u8, port: u16, rcvbuf: Option<usize>) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, None)?;
+    let _ = socket.set_only_v6(true);
+    let _ = socket.set_reuse_address(true);
+    if let Some(rcvbuf) = rcvbuf {
+        let _ = socket.set_recv_buffer_size(rcvbuf);
+    }
+    let _ = socket.set_multicast_if_v6(ifindex);
+    let any = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port);
+    socket.bind(&any.into())?;
+    let _ = socket.join_multicast_v6(&ipv6_discovery_group(scope), ifindex);
+
+    let socket: UdpSocket = socket.into();
+    let _ = socket.set_read_timeout(Some(READ_TIMEOUT));
+    Ok(socket)
+}
+
+// IPv4 counterpart of the IPv6 discovery group, for networks which block broadcast
+pub const IPV4_DISCOVERY_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 0x69, 0x63);
+
+/// How senders and receivers find each other: the destination of the hello
+/// and of connect requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Announce {
+    /// Subnet broadcast, the discovery group with IPv6.
+    Broadcast,
+    /// The discovery group.
+    Multicast,
+    /// A single host or a directed broadcast address.
+    Unicast(IpAddr),
+}
+
+impl Announce {
+    pub fn parse(announce: &str) -> Result<Self, &'static str> {
+        match announce {
+            "broadcast" => Ok(Announce::Broadcast),
+            "multicast" => Ok(Announce::Multicast),
+            _ => announce
+                .parse()
+                .map(Announce::Unicast)
+                .map_err(|_| "Announce with broadcast, multicast or an IP address"),
+        }
+    }
+}
+
+/// Interface, ports and multicast group of a session. The defaults are the
+/// ones of udpcast.
+#[derive(Debug, Clone)]
+pub struct NetConfig {
+    pub nic: Nic,
+    // receivers listen here, the sender sends the data and its messages to it
+    pub data_port: u16,
+    // the sender listens here for the messages of the receivers
+    pub control_port: u16,
+    // derived from the interface address if not given
+    pub group: Option<IpAddr>,
+    pub announce: Announce,
+}
+
+impl Default for NetConfig {
+    fn default() -> Self {
+        Self {
+            nic: Nic::Default,
+            data_port: PORTBASE,
+            control_port: PORTBASE + 1,
+            group: None,
+            announce: Announce::Broadcast,
+        }
+    }
+}
+
+impl NetConfig {
+    fn group_v4(&self, myip: Ipv4Addr) -> io::Result<Ipv4Addr> {
+        match self.group {
+            Some(IpAddr::V4(group)) if group.is_multicast() => Ok(group),
+            Some(_) => Err(invalid_input(
+                "the multicast group is no IPv4 multicast address",
+            )),
+            None => Ok(Ipv4Addr::from(u32::from(myip) & 0x07ffffff | 0xe8000000)),
+        }
+    }
+
+    fn group_v6(&self, scope: u8, myip: &Ipv6Addr) -> io::Result<Ipv6Addr> {
+        match self.group {
+            Some(IpAddr::V6(group)) if group.is_multicast() => Ok(group),
+            Some(_) => Err(invalid_input(
+                "the multicast group is no IPv6 multicast address",
+            )),
+            None => Ok(ipv6_session_group(scope, myip)),
+        }
+    }
+
+    fn announce_v4(&self, myip_net: &Ipv4Net) -> io::Result<Ipv4Addr> {
+        match self.announce {
+            Announce::Broadcast => Ok(myip_net.broadcast()),
+            Announce::Multicast => Ok(IPV4_DISCOVERY_GROUP),
+            Announce::Unicast(IpAddr::V4(addr)) => Ok(addr),
+            Announce::Unicast(_) => Err(invalid_input("the announce address is no IPv4 address")),
         }
     }
 
-    if let Some(interface) = current_interface.take() {
-        interfaces.push(Ipv4Net::from(interface));
+    fn announce_v6(&self, scope: u8) -> io::Result<Ipv6Addr> {
+        match self.announce {
+            Announce::Broadcast | Announce::Multicast => Ok(ipv6_discovery_group(scope)),
+            Announce::Unicast(IpAddr::V6(addr)) => Ok(addr),
+            Announce::Unicast(_) => Err(invalid_input("the announce address is no IPv6 address")),
+        }
+    }
+
+    // IPv4 sockets join the discovery group or may send broadcasts
+    fn setup_v4(&self, socket: &UdpSocket, myip: &Ipv4Addr) -> io::Result<()> {
+        match self.announce {
+            Announce::Broadcast => socket.set_broadcast(true),
+            Announce::Multicast => socket.join_multicast_v4(&IPV4_DISCOVERY_GROUP, myip),
+            Announce::Unicast(_) => Ok(()),
+        }
     }
+}
 
-    interfaces
+fn invalid_input(err: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, err)
 }
 
 #[derive(Debug)]
 pub struct MultiCast {
-    socket: UdpSocket,
-    pub myip_addr: SocketAddrV4,
-    pub broadcast_addr: SocketAddrV4,
-    pub multicast_addr: SocketAddrV4,
-    pub receivefrom: Option<SocketAddrV4>,
+    socket: Box<dyn Transport>,
+    ifindex: u32,
+    pub myip_addr: SocketAddr,
+    pub announce_addr: SocketAddr,
+    pub multicast_addr: SocketAddr,
+    pub receivefrom: Option<SocketAddr>,
     pub packet_count: usize,
+    pub dropped_count: usize,
+    psk: Option<Psk>,
+    pub forged_count: usize,
+    session: Option<u16>,
+    strict_session: bool,
+    // datagrams of the last batch not yet handed out by recv_msg, with their size
+    received: VecDeque<(Vec<u8>, usize, SocketAddr)>,
+    recv_bufs: Vec<Vec<u8>>,
+    // receive buffers given back by recycle
+    spare: Vec<Vec<u8>>,
+    // size of the receive buffers, see set_block_size
+    packet_size: usize,
+    // authentication tags of the batch being sent
+    tags: Vec<[u8; TAG_LEN]>,
+    // set up by the first wait, see event_loop
+    events: Option<EventLoop>,
+    pollable: bool,
 }
 
-impl MultiCast {
-    pub fn receiver(nic: usize, rcvbuf: usize) -> Self {
-        let myip_net = get_ipconfig()[nic];
-        let myip_addr: SocketAddrV4 = SocketAddrV4::new(myip_net.addr(), PORTBASE);
+/// Message received by `MultiCast::recv_msg`. The payload stays in the buffer
+/// the datagram was received into, `MultiCast::recycle` takes it back.
+#[derive(Debug)]
+pub struct Received {
+    pub msg: Message,
+    buf: Vec<u8>,
+    payload: Range<usize>,
+}
 
-        let broadcast_addr = SocketAddrV4::new(myip_net.broadcast(), PORTBASE + 1);
-        let multicast_addr = Ipv4Addr::from(u32::from(myip_net.addr()) & 0x07ffffff | 0xe8000000);
-        let multicast_addr = SocketAddrV4::new(multicast_addr, PORTBASE + 1);
+impl Received {
+    pub fn payload(&self) -> &[u8] {
+        &self.buf[self.payload.clone()]
+    }
 
-        // Create a UDP socket
-        let socket = Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap();
-        let _ = socket.set_recv_buffer_size(rcvbuf);
-        let _ = socket.bind(&myip_addr.into());
+    pub fn payload_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[self.payload.clone()]
+    }
+}
 
-        let socket: UdpSocket = socket.into();
-        let _ = socket.set_read_timeout(Some(Duration::from_millis(50)));
+// datagrams sent or received with one system call
+pub const BATCH_SIZE: usize = 64;
 
+impl MultiCast {
+    /// Session endpoint on any transport, the constructors below open a UDP
+    /// socket on the selected interface.
+    pub fn new(
+        socket: Box<dyn Transport>,
+        ifindex: u32,
+        myip_addr: SocketAddr,
+        announce_addr: SocketAddr,
+        multicast_addr: SocketAddr,
+    ) -> Self {
         Self {
             socket,
+            ifindex,
             myip_addr,
-            broadcast_addr,
+            announce_addr,
             multicast_addr,
             receivefrom: None,
             packet_count: 0,
+            dropped_count: 0,
+            psk: None,
+            forged_count: 0,
+            session: None,
+            strict_session: false,
+            received: VecDeque::new(),
+            recv_bufs: Vec::new(),
+            spare: Vec::new(),
+            packet_size: UDP_PACK_SIZE,
+            tags: Vec::new(),
+            events: None,
+            pollable: true,
         }
     }
 
-    pub fn sender(nic: usize) -> Self {
-        let myip_net = get_ipconfig()[nic];
-        let myip_addr: SocketAddrV4 = SocketAddrV4::new(myip_net.addr(), PORTBASE + 1);
+    pub fn receiver(config: &NetConfig, rcvbuf: usize) -> io::Result<Self> {
+        let myip_net = config.nic.ipv4()?;
+        let myip = myip_net.addr();
+        let announce = config.announce_v4(&myip_net)?;
+        let group = config.group_v4(myip)?;
+
+        // Create a UDP socket
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, None)?;
+        let _ = socket.set_recv_buffer_size(rcvbuf);
+        socket.bind(&SocketAddrV4::new(myip, config.data_port).into())?;
+
+        let socket: UdpSocket = socket.into();
+        let _ = socket.set_read_timeout(Some(READ_TIMEOUT));
+        config.setup_v4(&socket, &myip)?;
+
+        Ok(Self::new(
+            Box::new(socket),
+            0,
+            SocketAddrV4::new(myip, config.data_port).into(),
+            SocketAddrV4::new(announce, config.control_port).into(),
+            SocketAddrV4::new(group, config.data_port).into(),
+        ))
+    }
 
-        let broadcast_addr = SocketAddrV4::new(myip_net.broadcast(), PORTBASE);
-        let multicast_addr = Ipv4Addr::from(u32::from(myip_net.addr()) & 0x07ffffff | 0xe8000000);
-        let multicast_addr = SocketAddrV4::new(multicast_addr, PORTBASE);
+    pub fn sender(config: &NetConfig) -> io::Result<Self> {
+        let myip_net = config.nic.ipv4()?;
+        let myip = myip_net.addr();
+        let announce = config.announce_v4(&myip_net)?;
+        let group = config.group_v4(myip)?;
 
         // Create a UDP socket
-        let socket = UdpSocket::bind(myip_addr.to_string()).expect("Failed to bind socket");
+        let socket = UdpSocket::bind(SocketAddrV4::new(myip, config.control_port))?;
+        // the data leaves by the selected interface, not the one of the route
+        let _ = SockRef::from(&socket).set_multicast_if_v4(&myip);
 
-        let _ = socket.set_read_timeout(Some(Duration::from_millis(50)));
+        let _ = socket.set_read_timeout(Some(READ_TIMEOUT));
+        config.setup_v4(&socket, &myip)?;
 
-        Self {
-            socket,
-            myip_addr,
-            broadcast_addr,
-            multicast_addr,
-            receivefrom: None,
-            packet_count: 0,
+        Ok(Self::new(
+            Box::new(socket),
+            0,
+            SocketAddrV4::new(myip, config.control_port).into(),
+            SocketAddrV4::new(announce, config.data_port).into(),
+            SocketAddrV4::new(group, config.data_port).into(),
+        ))
+    }
+
+    pub fn receiver_v6(config: &NetConfig, scope: u8, rcvbuf: usize) -> io::Result<Self> {
+        let (ifindex, myip) = config.nic.ipv6(scope)?;
+        let announce = config.announce_v6(scope)?;
+        let group = config.group_v6(scope, &myip)?;
+        let socket = ipv6_socket(ifindex, scope, config.data_port, Some(rcvbuf))?;
+
+        Ok(Self::new(
+            Box::new(socket),
+            ifindex,
+            SocketAddrV6::new(myip, config.data_port, 0, ifindex).into(),
+            SocketAddrV6::new(announce, config.control_port, 0, ifindex).into(),
+            SocketAddrV6::new(group, config.data_port, 0, ifindex).into(),
+        ))
+    }
+
+    pub fn sender_v6(config: &NetConfig, scope: u8) -> io::Result<Self> {
+        let (ifindex, myip) = config.nic.ipv6(scope)?;
+        let announce = config.announce_v6(scope)?;
+        let group = config.group_v6(scope, &myip)?;
+        let socket = ipv6_socket(ifindex, scope, config.control_port, None)?;
+
+        Ok(Self::new(
+            Box::new(socket),
+            ifindex,
+            SocketAddrV6::new(myip, config.control_port, 0, ifindex).into(),
+            SocketAddrV6::new(announce, config.data_port, 0, ifindex).into(),
+            SocketAddrV6::new(group, config.data_port, 0, ifindex).into(),
+        ))
+    }
+
+    // The transport alone, to combine the sockets of several interfaces
+    pub fn into_transport(self) -> Box<dyn Transport> {
+        self.socket
+    }
+
+    // Socket address of a multicast group announced by the sender
+    pub fn group_addr(&self, group: IpAddr) -> SocketAddr {
+        match group {
+            IpAddr::V4(group) => SocketAddrV4::new(group, self.myip_addr.port()).into(),
+            IpAddr::V6(group) => {
+                SocketAddrV6::new(group, self.myip_addr.port(), 0, self.ifindex).into()
+            }
         }
     }
 
     pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
-        self.socket.set_multicast_ttl_v4(ttl)
+        self.socket.set_multicast_ttl(ttl)
     }
 
     pub fn set_broadcast(&self) -> io::Result<()> {
-        self.socket.set_broadcast(true)
+        match self.myip_addr {
+            SocketAddr::V4(_) => self.socket.set_broadcast(true),
+            // the discovery group is joined instead
+            SocketAddr::V6(_) => Ok(()),
+        }
     }
 
     pub fn join_multicast(&self) -> io::Result<()> {
         self.socket
-            .join_multicast_v4(&self.multicast_addr.ip(), &self.myip_addr.ip())
+            .join_multicast(self.multicast_addr.ip(), self.myip_addr.ip(), self.ifindex)
     }
 
     pub fn set_nonblocking(&mut self) -> io::Result<()> {
         self.socket.set_nonblocking(true)
     }
 
+    pub fn path_mtu(&self, addr: SocketAddr) -> io::Result<usize> {
+        self.socket.path_mtu(addr)
+    }
+
+    // The event loop of the socket, set up when first needed as it makes the
+    // transport nonblocking. None if the transport can not be polled, it is
+    // read with its read timeout then.
+    fn event_loop(&mut self) -> Option<&mut EventLoop> {
+        if self.events.is_none() && self.pollable {
+            match EventLoop::new(&*self.socket) {
+                Ok(events) => self.events = Some(events),
+                Err(err) => {
+                    debug!("Can't poll the socket, reading with a timeout: {err}");
+                    self.pollable = false;
+                }
+            }
+        }
+        self.events.as_mut()
+    }
+
+    /// Waker of the event loop, which ends the wait of wait_msg, for the
+    /// FIFOs and threads the session waits on besides the socket.
+    pub fn waker(&mut self) -> Option<Arc<Waker>> {
+        self.event_loop().map(|events| events.waker())
+    }
+
+    // Receive the blocks of block_size announced by the sender, the buffers
+    // keep room for the header and the tags.
+    pub fn set_block_size(&mut self, block_size: u32) {
+        let packet_size = (block_size as usize + HEADER_MAX).max(UDP_PACK_SIZE);
+        if packet_size != self.packet_size {
+            self.packet_size = packet_size;
+            self.spare.clear();
+            self.recv_bufs.clear();
+        }
+    }
+
+    // Authenticate every packet sent and drop received ones without a valid tag.
+    pub fn set_psk(&mut self, psk: Psk) {
+        self.psk = Some(psk);
+    }
+
+    pub fn has_psk(&self) -> bool {
+        self.psk.is_some()
+    }
+
+    // Stamp every message sent with the session id and drop received ones of
+    // other sessions. Unstamped messages of udp-receiver and older receivers
+    // are still accepted.
+    pub fn set_session(&mut self, session: u16) {
+        self.session = Some(session);
+    }
+
+    // Like set_session, but only messages of exactly this session are accepted.
+    pub fn join_session(&mut self, session: u16) {
+        self.session = Some(session);
+        self.strict_session = true;
+    }
+
+    pub fn session(&self) -> Option<u16> {
+        self.session
+    }
+
+    pub fn stamp(&self, message: &mut [u8]) {
+        if let Some(session) = self.session {
+            packet::set_session(message, session);
+        }
+    }
+
+    fn in_session(&self, msg: &Message) -> bool {
+        match self.session {
+            Some(session) => {
+                msg.session() == session || (!self.strict_session && msg.session() == 0)
+            }
+            None => true,
+        }
+    }
+
     pub fn send_msg(&mut self, message: &[u8]) -> io::Result<usize> {
+        let sendto = self.receivefrom.unwrap_or(self.multicast_addr);
+        self.send_to(message, sendto)
+    }
+
+    pub fn send_to(&mut self, message: &[u8], sendto: SocketAddr) -> io::Result<usize> {
         self.packet_count += 1;
-        if let Some(rcvfrom) = self.receivefrom {
-            self.socket.send_to(message, rcvfrom)
-        } else {
-            self.socket.send_to(message, self.multicast_addr)
+        if self.session.is_none() && self.psk.is_none() {
+            return sending(&mut self.events, || self.socket.send_to(message, sendto));
         }
+        let mut message = message.to_vec();
+        self.stamp_and_sign(&mut message);
+        sending(&mut self.events, || self.socket.send_to(&message, sendto))
     }
 
-    pub fn send_to(&mut self, message: &[u8], sendto: SocketAddrV4) -> io::Result<usize> {
-        self.packet_count += 1;
-        self.socket.send_to(message, sendto)
+    fn stamp_and_sign(&self, message: &mut Vec<u8>) {
+        self.stamp(message);
+        if let Some(psk) = &self.psk {
+            psk.sign(message);
+        }
+    }
+
+    // Send messages in batches of BATCH_SIZE, with one system call each where
+    // the transport supports it. The messages carry the session id already,
+    // see stamp, the authentication tag is added as a part of its own.
+    pub fn send_batch(&mut self, messages: &[Gather], sendto: SocketAddr) -> io::Result<usize> {
+        self.packet_count += messages.len();
+        for batch in messages.chunks(BATCH_SIZE) {
+            let batch = match &self.psk {
+                Some(psk) => {
+                    self.tags.clear();
+                    self.tags
+                        .extend(batch.iter().map(|message| psk.tag(message.parts())));
+                    let signed: Vec<Gather> = batch
+                        .iter()
+                        .zip(&self.tags)
+                        .map(|(message, tag)| {
+                            let mut message = *message;
+                            message.push(tag);
+                            message
+                        })
+                        .collect();
+                    Cow::Owned(signed)
+                }
+                None => Cow::Borrowed(batch),
+            };
+            let mut sent = 0;
+            while sent < batch.len() {
+                let socket = &self.socket;
+                sent += sending(&mut self.events, || {
+                    socket.send_batch(&batch[sent..], sendto)
+                })?;
+            }
+        }
+        Ok(messages.len())
+    }
+
+    // Next datagram and its size, from the last batch or a new one
+    fn recv_from(&mut self) -> io::Result<(Vec<u8>, usize, SocketAddr)> {
+        if self.received.is_empty() {
+            self.recv_bufs.resize_with(BATCH_SIZE, Vec::new);
+            for buf in self.recv_bufs.iter_mut().filter(|buf| buf.is_empty()) {
+                *buf = self
+                    .spare
+                    .pop()
+                    .unwrap_or_else(|| vec![0; self.packet_size]);
+            }
+            let received = self.socket.recv_batch(&mut self.recv_bufs)?;
+            for (index, (size, address)) in received.into_iter().enumerate() {
+                let buf = std::mem::take(&mut self.recv_bufs[index]);
+                self.received.push_back((buf, size, address));
+            }
+        }
+        self.received
+            .pop_front()
+            .ok_or_else(|| io::ErrorKind::WouldBlock.into())
+    }
+
+    /// Give the buffer of a received message back for the next datagrams,
+    /// the message is what remains.
+    pub fn recycle(&mut self, received: Received) -> Message {
+        self.recycle_buf(received.buf);
+        received.msg
+    }
+
+    fn recycle_buf(&mut self, buf: Vec<u8>) {
+        if self.spare.len() < BATCH_SIZE && buf.len() == self.packet_size {
+            self.spare.push(buf);
+        }
+    }
+
+    /// Wait for a message up to the read timeout of the sockets.
+    pub fn recv_msg(&mut self) -> io::Result<Received> {
+        self.wait_msg(Some(Instant::now() + READ_TIMEOUT))
+    }
+
+    /// Wait for a message until the deadline, TimedOut then, or WouldBlock
+    /// when the event loop was woken before. Without a deadline only a wakeup
+    /// ends the wait.
+    pub fn wait_msg(&mut self, deadline: Option<Instant>) -> io::Result<Received> {
+        if self.event_loop().is_none() {
+            return self.read_msg();
+        }
+        let mut woken = false;
+        loop {
+            match self.read_msg() {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+            if woken {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let due = match (deadline, self.socket.next_due()) {
+                (Some(deadline), Some(due)) => Some(deadline.min(due)),
+                (deadline, due) => deadline.or(due),
+            };
+            // in-process transports wake the loop for a datagram as well
+            match self.events.as_mut().unwrap().wait(due)? {
+                Ready::Readable => {}
+                Ready::Woken => woken = true,
+                Ready::Deadline => {
+                    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                        return Err(io::ErrorKind::TimedOut.into());
+                    }
+                }
+            }
+        }
     }
 
-    pub fn recv_msg(&mut self, buf: &mut [u8]) -> io::Result<(Message, Vec<u8>)> {
+    fn read_msg(&mut self) -> io::Result<Received> {
+        let (buf, size, address) = self.recv_from()?;
+        // datagrams read, not attempts which would block
         self.packet_count += 1;
-        match self.socket.recv_from(buf) {
-            Ok((size, address)) => {
-                let (msg, remain) = Message::decode(&buf[..size]);
-                match address {
-                    SocketAddr::V4(v4) => self.receivefrom = Some(v4),
-                    _ => self.receivefrom = None,
+        match self.decode(&buf[..size], address) {
+            Ok((msg, payload)) => {
+                self.receivefrom = Some(address);
+                Ok(Received { msg, buf, payload })
+            }
+            Err(err) => {
+                self.recycle_buf(buf);
+                Err(err)
+            }
+        }
+    }
+
+    // Check and decode a datagram, the payload is returned as its range.
+    fn decode(
+        &mut self,
+        packet: &[u8],
+        address: SocketAddr,
+    ) -> io::Result<(Message, Range<usize>)> {
+        let message = match &self.psk {
+            Some(psk) => match psk.verify(packet) {
+                Some(message) => message,
+                None => {
+                    self.forged_count += 1;
+                    debug!("drop unauthenticated packet from {address}");
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "unauthenticated packet",
+                    ));
                 }
-                trace!("message {:?} from {address}", msg);
-                return Ok((msg, remain));
+            },
+            None => packet,
+        };
+        let (msg, remain) = match Message::decode(message) {
+            Ok(decoded) => decoded,
+            Err(err) => {
+                self.dropped_count += 1;
+                debug!("drop packet from {address}: {err}");
+                return Err(io::Error::new(io::ErrorKind::InvalidData, err));
             }
-            Err(err) => return Err(err),
+        };
+        if !self.in_session(&msg) {
+            trace!("drop message of session {} from {address}", msg.session());
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "message of another session",
+            ));
         }
+        trace!("message {:?} from {address}", msg);
+        let start = remain.as_ptr() as usize - packet.as_ptr() as usize;
+        Ok((msg, start..start + remain.len()))
     }
 }
 
@@ -231,3 +823,16 @@ pub fn get_default_interface() -> Ipv4Net {
         }
     }
 }
+
+// The socket of an event loop is nonblocking, a full send buffer is waited
+// out as a blocking socket would.
+fn sending<T>(events: &mut Option<EventLoop>, send: impl Fn() -> io::Result<T>) -> io::Result<T> {
+    loop {
+        match (send(), events.as_mut()) {
+            (Err(err), Some(events)) if err.kind() == io::ErrorKind::WouldBlock => {
+                events.wait_writable(Instant::now() + READ_TIMEOUT)?
+            }
+            (result, _) => return result,
+        }
+    }
+}
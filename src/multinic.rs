@@ -0,0 +1,320 @@
+use mio::{Registry, Token, Waker};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::event_loop::{Registration, Waiter};
+use crate::multicast::{MultiCast, NetConfig, Nic};
+use crate::transport::{Gather, Transport};
+
+// same read timeout as the UDP sockets
+const READ_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// How a session uses several interfaces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Every interface sends the whole session to the receivers on its network.
+    Replicate,
+    /// The blocks of each slice are spread over the interfaces, for receivers
+    /// with an address on all of their networks.
+    Stripe,
+    /// Receive on every interface, send from the first.
+    Receive,
+}
+
+/// Interfaces of a session: their addresses, the bytes sent on each and the
+/// interface each peer was last heard on.
+#[derive(Debug)]
+pub struct Interfaces {
+    addrs: Vec<SocketAddr>,
+    sent: Vec<AtomicUsize>,
+    peers: Mutex<HashMap<SocketAddr, usize>>,
+}
+
+impl Interfaces {
+    pub fn len(&self) -> usize {
+        self.addrs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addrs.is_empty()
+    }
+
+    pub fn addr(&self, nic: usize) -> SocketAddr {
+        self.addrs[nic]
+    }
+
+    pub fn sent_bytes(&self, nic: usize) -> usize {
+        self.sent[nic].load(Ordering::Relaxed)
+    }
+
+    pub fn nic_of(&self, peer: &SocketAddr) -> Option<usize> {
+        self.peers.lock().unwrap().get(peer).copied()
+    }
+}
+
+#[derive(Debug)]
+struct Link {
+    socket: Box<dyn Transport>,
+    myip: SocketAddr,
+    announce: SocketAddr,
+}
+
+/// Transport over the sockets of several interfaces. The announce address and
+/// multicast group of the first interface stand for those of all of them.
+#[derive(Debug)]
+pub struct MultiNic {
+    links: Vec<Link>,
+    mode: Mode,
+    announce: SocketAddr,
+    group: SocketAddr,
+    interfaces: Arc<Interfaces>,
+    // link polled first, so that none is starved
+    next: AtomicUsize,
+    nonblocking: AtomicBool,
+    waiter: Waiter,
+}
+
+impl MultiNic {
+    fn new(endpoints: Vec<MultiCast>, mode: Mode) -> io::Result<Self> {
+        let first = endpoints
+            .first()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no interface"))?;
+        let (announce, group) = (first.announce_addr, first.multicast_addr);
+        let mut links = Vec::new();
+        for endpoint in endpoints {
+            let (myip, announce) = (endpoint.myip_addr, endpoint.announce_addr);
+            let socket = endpoint.into_transport();
+            // polled in turn
+            socket.set_nonblocking(true)?;
+            links.push(Link {
+                socket,
+                myip,
+                announce,
+            });
+        }
+        let interfaces = Arc::new(Interfaces {
+            addrs: links.iter().map(|link| link.myip).collect(),
+            sent: links.iter().map(|_| AtomicUsize::new(0)).collect(),
+            peers: Mutex::new(HashMap::new()),
+        });
+        Ok(Self {
+            links,
+            mode,
+            announce,
+            group,
+            interfaces,
+            next: AtomicUsize::new(0),
+            nonblocking: AtomicBool::new(false),
+            waiter: Waiter::default(),
+        })
+    }
+
+    pub fn interfaces(&self) -> Arc<Interfaces> {
+        Arc::clone(&self.interfaces)
+    }
+
+    // Links a datagram to addr goes out on, with the destination on each.
+    fn route(&self, addr: SocketAddr) -> Vec<(usize, SocketAddr)> {
+        if self.mode == Mode::Replicate && (addr == self.announce || addr == self.group) {
+            return self
+                .links
+                .iter()
+                .enumerate()
+                .map(|(nic, link)| {
+                    let to = if addr == self.announce {
+                        link.announce
+                    } else {
+                        addr
+                    };
+                    (nic, to)
+                })
+                .collect();
+        }
+        // a receiver keeps one source address, whatever interface it heard the sender on
+        if self.mode != Mode::Receive {
+            if let Some(nic) = self.interfaces.nic_of(&addr) {
+                return vec![(nic, addr)];
+            }
+        }
+        vec![(0, addr)]
+    }
+
+    fn send_on(&self, nic: usize, packets: &[Gather], addr: SocketAddr) -> io::Result<()> {
+        let mut sent = 0;
+        while sent < packets.len() {
+            sent += self.links[nic].socket.send_batch(&packets[sent..], addr)?;
+        }
+        let bytes: usize = packets.iter().map(|packet| packet.len()).sum();
+        self.interfaces.sent[nic].fetch_add(bytes, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl Transport for MultiNic {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        for (nic, to) in self.route(addr) {
+            self.links[nic].socket.send_to(buf, to)?;
+            self.interfaces.sent[nic].fetch_add(buf.len(), Ordering::Relaxed);
+        }
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let deadline = Instant::now() + READ_TIMEOUT;
+        loop {
+            let first = self.next.fetch_add(1, Ordering::Relaxed);
+            for turn in 0..self.links.len() {
+                let nic = (first + turn) % self.links.len();
+                match self.links[nic].socket.recv_from(buf) {
+                    Ok((size, from)) => {
+                        self.interfaces.peers.lock().unwrap().insert(from, nic);
+                        return Ok((size, from));
+                    }
+                    Err(err)
+                        if err.kind() == io::ErrorKind::WouldBlock
+                            || err.kind() == io::ErrorKind::TimedOut => {}
+                    Err(err) => return Err(err),
+                }
+            }
+            if self.nonblocking.load(Ordering::Relaxed) {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            if Instant::now() >= deadline {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            // the links stay nonblocking, until one of them is readable
+            self.waiter.wait(self, deadline)?;
+        }
+    }
+
+    // Striped blocks take turns on the interfaces, each share in one batch.
+    fn send_batch(&self, packets: &[Gather], addr: SocketAddr) -> io::Result<usize> {
+        if self.mode == Mode::Stripe && addr == self.group {
+            let count = self.links.len();
+            for nic in 0..count {
+                let share: Vec<Gather> = packets.iter().skip(nic).step_by(count).copied().collect();
+                self.send_on(nic, &share, addr)?;
+            }
+        } else {
+            for (nic, to) in self.route(addr) {
+                self.send_on(nic, packets, to)?;
+            }
+        }
+        Ok(packets.len())
+    }
+
+    fn join_multicast(&self, group: IpAddr, _myip: IpAddr, ifindex: u32) -> io::Result<()> {
+        for link in &self.links {
+            link.socket.join_multicast(group, link.myip.ip(), ifindex)?;
+        }
+        Ok(())
+    }
+
+    fn set_broadcast(&self, broadcast: bool) -> io::Result<()> {
+        for link in &self.links {
+            link.socket.set_broadcast(broadcast)?;
+        }
+        Ok(())
+    }
+
+    fn set_multicast_ttl(&self, ttl: u32) -> io::Result<()> {
+        for link in &self.links {
+            link.socket.set_multicast_ttl(ttl)?;
+        }
+        Ok(())
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking.store(nonblocking, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // the smallest of the interfaces the datagrams to addr leave by
+    fn path_mtu(&self, addr: SocketAddr) -> io::Result<usize> {
+        let routes = if self.mode == Mode::Stripe && addr == self.group {
+            (0..self.links.len()).map(|nic| (nic, addr)).collect()
+        } else {
+            self.route(addr)
+        };
+        let mut mtu = usize::MAX;
+        for (nic, to) in routes {
+            mtu = mtu.min(self.links[nic].socket.path_mtu(to)?);
+        }
+        Ok(mtu)
+    }
+
+    // the sockets of all interfaces under the one token
+    fn register(
+        &self,
+        registry: &Registry,
+        token: Token,
+        waker: &Arc<Waker>,
+    ) -> io::Result<Registration> {
+        let registrations = self
+            .links
+            .iter()
+            .map(|link| link.socket.register(registry, token, waker))
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(Box::new(registrations))
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        self.links
+            .iter()
+            .filter_map(|link| link.socket.next_due())
+            .min()
+    }
+}
+
+/// Combine the endpoints of several interfaces into one, the first one's
+/// addresses stand for all of them.
+pub fn combine(endpoints: Vec<MultiCast>, mode: Mode) -> io::Result<(MultiCast, Arc<Interfaces>)> {
+    let first = endpoints
+        .first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no interface"))?;
+    let (myip, announce, group) = (first.myip_addr, first.announce_addr, first.multicast_addr);
+    let transport = MultiNic::new(endpoints, mode)?;
+    let interfaces = transport.interfaces();
+    Ok((
+        MultiCast::new(Box::new(transport), 0, myip, announce, group),
+        interfaces,
+    ))
+}
+
+/// Sender on every one of nics. The multicast group of the first interface is
+/// used on all of them.
+pub fn sender(
+    config: &NetConfig,
+    nics: &[Nic],
+    mode: Mode,
+) -> io::Result<(MultiCast, Arc<Interfaces>)> {
+    let mut config = config.clone();
+    let mut endpoints = Vec::new();
+    for nic in nics {
+        config.nic = nic.clone();
+        let endpoint = MultiCast::sender(&config)?;
+        config.group = Some(endpoint.multicast_addr.ip());
+        endpoints.push(endpoint);
+    }
+    combine(endpoints, mode)
+}
+
+/// Receiver with an address on the network of every one of nics, for striped
+/// sessions.
+pub fn receiver(
+    config: &NetConfig,
+    nics: &[Nic],
+    rcvbuf: usize,
+) -> io::Result<(MultiCast, Arc<Interfaces>)> {
+    let mut config = config.clone();
+    let mut endpoints = Vec::new();
+    for nic in nics {
+        config.nic = nic.clone();
+        endpoints.push(MultiCast::receiver(&config, rcvbuf)?);
+    }
+    combine(endpoints, Mode::Receive)
+}
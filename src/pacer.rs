@@ -0,0 +1,89 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::UDP_PACK_SIZE;
+
+// Bytes the bucket holds: the burst sent back to back. Long enough to ride
+// out the coarse sleep of Windows, short enough for the buffer of a switch.
+const BURST_TIME: Duration = Duration::from_millis(10);
+
+/// Token bucket which keeps the sender under a bitrate.
+#[derive(Debug)]
+pub struct Pacer {
+    // bytes per second
+    rate: f64,
+    tokens: f64,
+    depth: f64,
+    last: Instant,
+}
+
+impl Pacer {
+    pub fn new(bits_per_sec: u64) -> Self {
+        let mut pacer = Self {
+            rate: 0.0,
+            tokens: 0.0,
+            depth: 0.0,
+            last: Instant::now(),
+        };
+        pacer.set_rate(bits_per_sec);
+        pacer.tokens = pacer.depth;
+        pacer
+    }
+
+    /// Parse a bitrate in bits per second with an optional k, M or G suffix
+    /// (powers of 1000) and an optional "bit", "bps" or "b/s" unit. ex) 100M, 1.5Gbit, 800kbps
+    pub fn parse(rate: &str) -> Result<u64, &'static str> {
+        let rate = rate.trim();
+        let rate = ["bit", "bps", "b/s"]
+            .iter()
+            .find_map(|unit| rate.strip_suffix(unit))
+            .unwrap_or(rate);
+        let (number, scale) = match rate.chars().last() {
+            Some('k' | 'K') => (&rate[..rate.len() - 1], 1e3),
+            Some('m' | 'M') => (&rate[..rate.len() - 1], 1e6),
+            Some('g' | 'G') => (&rate[..rate.len() - 1], 1e9),
+            _ => (rate, 1.0),
+        };
+        match number.trim().parse::<f64>() {
+            Ok(number) if number * scale >= 1.0 && (number * scale).is_finite() => {
+                Ok((number * scale) as u64)
+            }
+            _ => Err("Invalid bitrate, ex) 100M, 1.5G, 800k"),
+        }
+    }
+
+    pub fn set_rate(&mut self, bits_per_sec: u64) {
+        self.refill();
+        self.rate = bits_per_sec.max(1) as f64 / 8.0;
+        self.depth = (self.rate * BURST_TIME.as_secs_f64()).max(UDP_PACK_SIZE as f64);
+        self.tokens = self.tokens.min(self.depth);
+    }
+
+    pub fn bitrate(&self) -> u64 {
+        (self.rate * 8.0) as u64
+    }
+
+    /// Bytes which may go out back to back.
+    pub fn burst(&self) -> usize {
+        self.depth as usize
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.depth);
+        self.last = now;
+    }
+
+    /// Wait until bytes may be sent and take them from the bucket.
+    pub fn wait(&mut self, bytes: usize) {
+        self.refill();
+        if self.tokens < bytes as f64 {
+            // a burst larger than the bucket runs into debt instead of waiting forever
+            let missing = bytes as f64 - self.tokens;
+            thread::sleep(Duration::from_secs_f64(missing / self.rate));
+            self.refill();
+        }
+        self.tokens -= bytes as f64;
+    }
+}
@@ -1,19 +1,23 @@
 use crate::bitarray::BitArray;
+use crate::crypt;
+use crate::udpcast;
+use crate::{BITS_PER_CHAR, MAX_BLOCK_SIZE, MAX_CLIENTS, MIN_BLOCK_SIZE};
 
 use core::convert::TryInto;
+use core::fmt;
 use endian_codec::{DecodeBE, EncodeBE, PackedSize};
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 #[derive(Debug, PartialEq, Eq, PackedSize, EncodeBE, DecodeBE)]
 pub struct MsgOk {
-    reserved: u16,
+    pub session: u16,
     pub sliceno: u32,
 }
 
 impl MsgOk {
     pub fn new(sliceno: u32) -> Self {
         Self {
-            reserved: 0,
+            session: 0,
             sliceno,
         }
     }
@@ -21,7 +25,7 @@ impl MsgOk {
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PackedSize, EncodeBE, DecodeBE)]
 pub struct MsgRetransmit {
-    reserved: u16,
+    pub session: u16,
     pub sliceno: u32,
     pub rxmit: u32,
 }
@@ -29,7 +33,7 @@ pub struct MsgRetransmit {
 impl MsgRetransmit {
     pub fn new(sliceno: u32, rxmit: u32) -> Self {
         Self {
-            reserved: 0,
+            session: 0,
             sliceno,
             rxmit,
         }
@@ -53,18 +57,18 @@ impl Retransmit {
 
 #[derive(Debug, PartialEq, Eq, PackedSize, EncodeBE, DecodeBE)]
 pub struct MsgGo {
-    reserved: u16,
+    pub session: u16,
 }
 
 impl MsgGo {
     pub fn new() -> Self {
-        Self { reserved: 0 }
+        Self { session: 0 }
     }
 }
 
 #[derive(Debug, PartialEq, Eq, PackedSize, EncodeBE, DecodeBE)]
 pub struct MsgConnectReq {
-    reserved: u16,
+    pub session: u16,
     pub capabilities: u32,
     pub rcvbuf: u32,
 }
@@ -72,7 +76,7 @@ pub struct MsgConnectReq {
 impl MsgConnectReq {
     pub fn new(capabilities: u32, rcvbuf: u32) -> Self {
         Self {
-            reserved: 0,
+            session: 0,
             capabilities,
             rcvbuf,
         }
@@ -81,18 +85,18 @@ impl MsgConnectReq {
 
 #[derive(Debug, PartialEq, Eq, PackedSize, EncodeBE, DecodeBE)]
 pub struct MsgDisconnect {
-    reserved: u16,
+    pub session: u16,
 }
 
 impl MsgDisconnect {
     pub fn new() -> Self {
-        Self { reserved: 0 }
+        Self { session: 0 }
     }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PackedSize, EncodeBE, DecodeBE)]
 pub struct MsgReqAck {
-    reserved: u16,
+    pub session: u16,
     pub sliceno: u32,
     pub bytes: u32,
     pub rxmit: u32,
@@ -101,7 +105,7 @@ pub struct MsgReqAck {
 impl MsgReqAck {
     pub fn new(sliceno: u32, bytes: u32, rxmit: u32) -> Self {
         Self {
-            reserved: 0,
+            session: 0,
             sliceno,
             bytes,
             rxmit,
@@ -109,9 +113,31 @@ impl MsgReqAck {
     }
 }
 
+// IPv4 groups fill the first 4 bytes like udpcast does, IPv6 groups all 16.
+pub(crate) fn encode_mcastaddr(addr: &IpAddr) -> [u8; 16] {
+    match addr {
+        IpAddr::V4(addr) => {
+            let mut buf = [0; 16];
+            buf[0..4].copy_from_slice(&addr.octets());
+            buf
+        }
+        IpAddr::V6(addr) => addr.octets(),
+    }
+}
+
+// IPv4 multicast groups never start with 0xff, IPv6 ones always do
+pub(crate) fn decode_mcastaddr(buf: &[u8; 16]) -> IpAddr {
+    if buf[0] == 0xff {
+        IpAddr::V6(Ipv6Addr::from(*buf))
+    } else {
+        let octets = u32::from_be_bytes(buf[..4].try_into().unwrap());
+        IpAddr::V4(Ipv4Addr::from(octets))
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, PackedSize, EncodeBE, DecodeBE)]
 pub struct MsgConnectReply {
-    reserved: u16,
+    pub session: u16,
     pub clnr: u32,
     pub blocksize: u32,
     pub capabilities: u32,
@@ -125,29 +151,26 @@ impl MsgConnectReply {
         blocksize: u32,
         capabilities: u32,
         max_slices: u32,
-        mcastaddr: &Ipv4Addr,
+        mcastaddr: &IpAddr,
     ) -> Self {
-        let mut buf = [0; 16];
-        buf[0..4].copy_from_slice(&mcastaddr.octets());
         Self {
-            reserved: 0,
+            session: 0,
             clnr,
             blocksize,
             capabilities,
             max_slices,
-            mcastaddr: buf,
+            mcastaddr: encode_mcastaddr(mcastaddr),
         }
     }
 
-    pub fn mcastaddr(&self) -> Ipv4Addr {
-        let octets = u32::from_be_bytes(self.mcastaddr[..4].try_into().unwrap());
-        Ipv4Addr::from(octets)
+    pub fn mcastaddr(&self) -> IpAddr {
+        decode_mcastaddr(&self.mcastaddr)
     }
 }
 
-#[derive(Debug, PartialEq, Eq, PackedSize, EncodeBE, DecodeBE)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PackedSize, EncodeBE, DecodeBE)]
 pub struct DataBlock {
-    reserved: u16,
+    pub session: u16,
     pub sliceno: u32,
     pub blockno: u16,
     reserved2: u16,
@@ -157,7 +180,7 @@ pub struct DataBlock {
 impl DataBlock {
     pub fn new(sliceno: u32, blockno: u16, bytes: u32) -> Self {
         Self {
-            reserved: 0,
+            session: 0,
             sliceno,
             blockno,
             reserved2: 0,
@@ -166,12 +189,13 @@ impl DataBlock {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, PackedSize, EncodeBE, DecodeBE)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PackedSize, EncodeBE, DecodeBE)]
 pub struct FecBlock {
     pub stripes: u16,
     pub sliceno: u32,
     pub blockno: u16,
-    reserved2: u16,
+    // the first field holds the stripes here
+    pub session: u16,
     pub bytes: u32,
 }
 
@@ -181,7 +205,7 @@ impl FecBlock {
             stripes,
             sliceno,
             blockno,
-            reserved2: 0,
+            session: 0,
             bytes,
         }
     }
@@ -189,27 +213,67 @@ impl FecBlock {
 
 #[derive(Debug, PartialEq, Eq, PackedSize, EncodeBE, DecodeBE)]
 pub struct MsgHello {
-    reserved: u16,
+    pub session: u16,
     pub capabilities: u32,
     mcastaddr: [u8; 16],
     pub blocksize: u16,
 }
 
 impl MsgHello {
-    pub fn new(capabilities: u32, mcastaddr: &Ipv4Addr, blocksize: u16) -> Self {
-        let mut buf = [0; 16];
-        buf[0..4].copy_from_slice(&mcastaddr.octets());
+    pub fn new(capabilities: u32, mcastaddr: &IpAddr, blocksize: u16) -> Self {
         Self {
-            reserved: 0,
+            session: 0,
             capabilities,
-            mcastaddr: buf,
+            mcastaddr: encode_mcastaddr(mcastaddr),
             blocksize,
         }
     }
 
-    pub fn mcastaddr(&self) -> Ipv4Addr {
-        let octets = u32::from_be_bytes(self.mcastaddr[..4].try_into().unwrap());
-        Ipv4Addr::from(octets)
+    pub fn mcastaddr(&self) -> IpAddr {
+        decode_mcastaddr(&self.mcastaddr)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PackedSize, EncodeBE, DecodeBE)]
+pub struct MsgStreamJoin {
+    pub session: u16,
+    pub sliceno: u32,
+    pub offset: u64,
+    pub image_size: u64,
+}
+
+impl MsgStreamJoin {
+    pub fn new(sliceno: u32, offset: u64, image_size: u64) -> Self {
+        Self {
+            session: 0,
+            sliceno,
+            offset,
+            image_size,
+        }
+    }
+}
+
+pub const REPORT_NO_DIGEST: u32 = 0;
+pub const REPORT_MATCH: u32 = 1;
+pub const REPORT_MISMATCH: u32 = 2;
+
+/// Verification result a receiver sends after writing the whole image.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PackedSize, EncodeBE, DecodeBE)]
+pub struct MsgReport {
+    pub session: u16,
+    pub status: u32,
+    pub bytes: u64,
+    pub digest: [u8; 32],
+}
+
+impl MsgReport {
+    pub fn new(status: u32, bytes: u64, digest: [u8; 32]) -> Self {
+        Self {
+            session: 0,
+            status,
+            bytes,
+            digest,
+        }
     }
 }
 
@@ -227,6 +291,9 @@ pub enum Opcode {
     CmdFec,
     CmdHelloNew,
     CmdHelloStreaming,
+    // img_caster extensions
+    CmdStreamJoin = 0x20,
+    CmdReport,
     CmdHello = 0x500,
 }
 
@@ -243,68 +310,328 @@ pub enum Message {
     CmdData(DataBlock),
     CmdFec(FecBlock),
     CmdHello(MsgHello),
+    CmdHelloStreaming(MsgHello),
+    CmdStreamJoin(MsgStreamJoin),
+    CmdReport(MsgReport),
     None,
 }
 
 const OPCODE_LEN: usize = 2;
 const OPCODE_VAL: u16 = 0_u16;
+/// Room for the encoding of any message, without its payload.
+pub const HEADER_MAX: usize = 64;
+/// Encoded header of a data or FEC block.
+pub const BLOCK_HEADER_LEN: usize = OPCODE_LEN + DataBlock::PACKED_LEN;
+// the session of a FEC block follows its slice and block number
+const FEC_SESSION_POS: usize = OPCODE_LEN + 8;
+
+pub const MAX_SESSION_NAME: usize = 64;
+
+/// Session salt and name a sender appends to its hello and connect reply.
+/// The salt is only present when the session is encrypted.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SessionInfo<'a> {
+    pub salt: &'a [u8],
+    pub name: &'a [u8],
+}
+
+impl<'a> SessionInfo<'a> {
+    pub fn new(salt: &'a [u8], name: &'a str) -> Self {
+        Self {
+            salt,
+            name: name.as_bytes(),
+        }
+    }
+
+    pub fn parse(capabilities: u32, payload: &'a [u8]) -> Option<Self> {
+        let salt_len = if capabilities & crate::CAP_ENCRYPTED != 0 {
+            crypt::SALT_LEN
+        } else {
+            0
+        };
+        if payload.len() < salt_len || payload.len() - salt_len > MAX_SESSION_NAME {
+            return None;
+        }
+        let (salt, name) = payload.split_at(salt_len);
+        Some(Self { salt, name })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let name = &self.name[..self.name.len().min(MAX_SESSION_NAME)];
+        [self.salt, name].concat()
+    }
+
+    pub fn name(&self) -> String {
+        String::from_utf8_lossy(self.name).into_owned()
+    }
+
+    /// Whether a session id or name given by the user selects this session.
+    /// Ids are decimal or hexadecimal with a 0x prefix.
+    pub fn matches(&self, session: u16, selector: &str) -> bool {
+        let id = match selector.strip_prefix("0x") {
+            Some(hex) => u16::from_str_radix(hex, 16).ok(),
+            None => selector.parse().ok(),
+        };
+        self.name == selector.as_bytes() || id == Some(session)
+    }
+}
+
+/// Write the session id into an encoded message.
+pub fn set_session(packet: &mut [u8], session: u16) {
+    if packet.len() < OPCODE_LEN {
+        return;
+    }
+    let pos = match u16::from_be_bytes([packet[0], packet[1]]) {
+        9 => FEC_SESSION_POS,
+        _ => OPCODE_LEN,
+    };
+    if let Some(field) = packet.get_mut(pos..pos + 2) {
+        field.copy_from_slice(&session.to_be_bytes());
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The datagram does not even hold an opcode.
+    Empty(usize),
+    /// The datagram is shorter than the fixed part of the message.
+    Truncated {
+        opcode: u16,
+        expected: usize,
+        actual: usize,
+    },
+    /// Bytes follow a message which carries no payload.
+    TrailingBytes {
+        opcode: u16,
+        len: usize,
+    },
+    /// A message which needs a payload (data, bitmap) came without one.
+    MissingPayload {
+        opcode: u16,
+        expected: usize,
+        actual: usize,
+    },
+    /// A hello or connect reply announces blocks no receiver can take.
+    BlockSize {
+        opcode: u16,
+        blocksize: u32,
+    },
+    UnknownOpcode(u16),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        use DecodeError::*;
+        match self {
+            Empty(len) => write!(fmt, "packet of {len} bytes has no opcode"),
+            Truncated {
+                opcode,
+                expected,
+                actual,
+            } => write!(
+                fmt,
+                "opcode {opcode}: truncated message, {actual} of {expected} bytes"
+            ),
+            TrailingBytes { opcode, len } => {
+                write!(fmt, "opcode {opcode}: {len} unexpected trailing bytes")
+            }
+            MissingPayload {
+                opcode,
+                expected,
+                actual,
+            } => write!(
+                fmt,
+                "opcode {opcode}: payload of {actual} bytes, at least {expected} expected"
+            ),
+            BlockSize { opcode, blocksize } => {
+                write!(fmt, "opcode {opcode}: block size {blocksize} out of range")
+            }
+            UnknownOpcode(opcode) => write!(fmt, "unknown opcode {opcode:#x}"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+// split the fixed size message header from the payload following it
+fn decode_body<T: DecodeBE + PackedSize>(
+    opcode: u16,
+    data: &[u8],
+) -> Result<(T, &[u8]), DecodeError> {
+    if data.len() < T::PACKED_LEN {
+        return Err(DecodeError::Truncated {
+            opcode,
+            expected: T::PACKED_LEN,
+            actual: data.len(),
+        });
+    }
+    let (body, payload) = data.split_at(T::PACKED_LEN);
+    Ok((T::decode_from_be_bytes(body), payload))
+}
+
+fn no_payload(opcode: u16, payload: &[u8]) -> Result<(), DecodeError> {
+    if payload.is_empty() {
+        Ok(())
+    } else {
+        Err(DecodeError::TrailingBytes {
+            opcode,
+            len: payload.len(),
+        })
+    }
+}
+
+fn session_payload(opcode: u16, capabilities: u32, payload: &[u8]) -> Result<(), DecodeError> {
+    SessionInfo::parse(capabilities, payload)
+        .map(|_| ())
+        .ok_or(DecodeError::TrailingBytes {
+            opcode,
+            len: payload.len(),
+        })
+}
+
+fn min_payload(opcode: u16, payload: &[u8], expected: usize) -> Result<(), DecodeError> {
+    if payload.len() >= expected {
+        Ok(())
+    } else {
+        Err(DecodeError::MissingPayload {
+            opcode,
+            expected,
+            actual: payload.len(),
+        })
+    }
+}
+
+// refused connect replies may come without a block size
+fn block_size(opcode: u16, clnr: u32, blocksize: u32) -> Result<(), DecodeError> {
+    if clnr == u32::MAX || (MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&blocksize) {
+        Ok(())
+    } else {
+        Err(DecodeError::BlockSize { opcode, blocksize })
+    }
+}
 
 impl Message {
-    pub fn decode(data: &[u8]) -> (Self, Vec<u8>) {
-        let mut data_vec = data.to_vec();
-        let (opcode, data) = data_vec.split_at_mut(OPCODE_LEN);
+    /// The session a message belongs to, 0 for udpcast peers and for connect
+    /// requests to any sender.
+    pub fn session(&self) -> u16 {
+        use Message::*;
+
+        match self {
+            CmdOk(msg) => msg.session,
+            CmdRetransmit(msg) => msg.session,
+            CmdGo(msg) => msg.session,
+            CmdConnectReq(msg) => msg.session,
+            CmdDisconnect(msg) => msg.session,
+            CmdReqack(msg) => msg.session,
+            CmdConnectReply(msg) => msg.session,
+            CmdData(msg) => msg.session,
+            CmdFec(msg) => msg.session,
+            CmdHello(msg) | CmdHelloStreaming(msg) => msg.session,
+            CmdStreamJoin(msg) => msg.session,
+            CmdReport(msg) => msg.session,
+            CmdUnused | None => 0,
+        }
+    }
+
+    pub fn decode(data: &[u8]) -> Result<(Self, &[u8]), DecodeError> {
+        if data.len() < OPCODE_LEN {
+            return Err(DecodeError::Empty(data.len()));
+        }
+        let (opcode, data) = data.split_at(OPCODE_LEN);
         let opcode = u16::from_be_bytes(opcode.try_into().unwrap());
-        let mut data_vec = data.to_vec();
         match opcode {
-            0 => (
-                Self::CmdOk(MsgOk::decode_from_be_bytes(data)),
-                data_vec.split_off(MsgOk::PACKED_LEN),
-            ),
-            1 => (
-                Self::CmdRetransmit(MsgRetransmit::decode_from_be_bytes(data)),
-                data_vec.split_off(MsgRetransmit::PACKED_LEN),
-            ),
-            2 => (
-                Self::CmdGo(MsgGo::decode_from_be_bytes(data)),
-                data_vec.split_off(MsgGo::PACKED_LEN),
-            ),
-            3 => (
-                Self::CmdConnectReq(MsgConnectReq::decode_from_be_bytes(data)),
-                data_vec.split_off(MsgConnectReq::PACKED_LEN),
-            ),
-            4 => (
-                Self::CmdDisconnect(MsgDisconnect::decode_from_be_bytes(data)),
-                data_vec.split_off(MsgDisconnect::PACKED_LEN),
-            ),
-            6 => (
-                Self::CmdReqack(MsgReqAck::decode_from_be_bytes(data)),
-                data_vec.split_off(MsgReqAck::PACKED_LEN),
-            ),
-            7 => (
-                Self::CmdConnectReply(MsgConnectReply::decode_from_be_bytes(data)),
-                data_vec.split_off(MsgConnectReply::PACKED_LEN),
-            ),
-            8 => (
-                Self::CmdData(DataBlock::decode_from_be_bytes(data)),
-                data_vec.split_off(DataBlock::PACKED_LEN),
-            ),
-            9 => (
-                Self::CmdFec(FecBlock::decode_from_be_bytes(data)),
-                data_vec.split_off(FecBlock::PACKED_LEN),
-            ),
-            10 => (
-                Self::CmdHello(MsgHello::decode_from_be_bytes(data)),
-                data_vec.split_off(MsgHello::PACKED_LEN),
-            ),
-            _ => (Self::None, Vec::new()),
+            0 => {
+                let (msg, payload) = decode_body::<MsgOk>(opcode, data)?;
+                no_payload(opcode, payload)?;
+                Ok((Self::CmdOk(msg), payload))
+            }
+            1 => {
+                let (msg, payload) = decode_body::<MsgRetransmit>(opcode, data)?;
+                min_payload(opcode, payload, 1)?;
+                Ok((Self::CmdRetransmit(msg), payload))
+            }
+            2 => {
+                let (msg, payload) = decode_body::<MsgGo>(opcode, data)?;
+                no_payload(opcode, payload)?;
+                Ok((Self::CmdGo(msg), payload))
+            }
+            3 => {
+                let (msg, payload) = decode_body::<MsgConnectReq>(opcode, data)?;
+                no_payload(opcode, payload)?;
+                Ok((Self::CmdConnectReq(msg), payload))
+            }
+            4 => {
+                let (msg, payload) = decode_body::<MsgDisconnect>(opcode, data)?;
+                no_payload(opcode, payload)?;
+                Ok((Self::CmdDisconnect(msg), payload))
+            }
+            6 => {
+                let (msg, payload) = decode_body::<MsgReqAck>(opcode, data)?;
+                min_payload(opcode, payload, (MAX_CLIENTS / BITS_PER_CHAR) as usize)?;
+                Ok((Self::CmdReqack(msg), payload))
+            }
+            7 => {
+                // udp-sender replies without the max_slices field
+                if data.len() == udpcast::ConnectReply::PACKED_LEN {
+                    let (msg, payload) = decode_body::<udpcast::ConnectReply>(opcode, data)?;
+                    block_size(opcode, msg.clnr, msg.blocksize)?;
+                    return Ok((Self::CmdConnectReply(msg.into()), payload));
+                }
+                let (msg, payload) = decode_body::<MsgConnectReply>(opcode, data)?;
+                block_size(opcode, msg.clnr, msg.blocksize)?;
+                session_payload(opcode, msg.capabilities, payload)?;
+                Ok((Self::CmdConnectReply(msg), payload))
+            }
+            8 => {
+                let (msg, payload) = decode_body::<DataBlock>(opcode, data)?;
+                min_payload(opcode, payload, 1)?;
+                Ok((Self::CmdData(msg), payload))
+            }
+            9 => {
+                let (msg, payload) = decode_body::<FecBlock>(opcode, data)?;
+                min_payload(opcode, payload, 1)?;
+                Ok((Self::CmdFec(msg), payload))
+            }
+            10 | udpcast::UDPCAST_CMD_HELLO => {
+                let (msg, payload) = decode_body::<MsgHello>(opcode, data)?;
+                block_size(opcode, 0, msg.blocksize as u32)?;
+                let payload = udpcast::hello_padding(payload);
+                session_payload(opcode, msg.capabilities, payload)?;
+                Ok((Self::CmdHello(msg), payload))
+            }
+            11 => {
+                let (msg, payload) = decode_body::<MsgHello>(opcode, data)?;
+                block_size(opcode, 0, msg.blocksize as u32)?;
+                let payload = udpcast::hello_padding(payload);
+                session_payload(opcode, msg.capabilities, payload)?;
+                Ok((Self::CmdHelloStreaming(msg), payload))
+            }
+            0x20 => {
+                let (msg, payload) = decode_body::<MsgStreamJoin>(opcode, data)?;
+                no_payload(opcode, payload)?;
+                Ok((Self::CmdStreamJoin(msg), payload))
+            }
+            0x21 => {
+                let (msg, payload) = decode_body::<MsgReport>(opcode, data)?;
+                no_payload(opcode, payload)?;
+                Ok((Self::CmdReport(msg), payload))
+            }
+            _ => Err(DecodeError::UnknownOpcode(opcode)),
         }
     }
 
-    #[allow(unused_assignments)]
     pub fn encode(&self) -> Vec<u8> {
+        let mut buf = [0; HEADER_MAX];
+        let len = self.encode_into(&mut buf);
+        buf[..len].to_vec()
+    }
+
+    /// Encode the message into the start of buf, which holds at least its
+    /// header (HEADER_MAX is enough for all), and return the length.
+    #[allow(unused_assignments)]
+    pub fn encode_into(&self, buf: &mut [u8]) -> usize {
         use Message::*;
 
-        let mut buf = [0; 2048];
         let mut opcode = OPCODE_VAL;
         let mut packet_len: usize = 0;
 
@@ -359,11 +686,27 @@ impl Message {
                 packet_len = MsgHello::PACKED_LEN;
                 msg.encode_as_be_bytes(&mut buf[OPCODE_LEN..]);
             }
+            CmdHelloStreaming(msg) => {
+                opcode = 11;
+                packet_len = MsgHello::PACKED_LEN;
+                msg.encode_as_be_bytes(&mut buf[OPCODE_LEN..]);
+            }
+            CmdStreamJoin(msg) => {
+                opcode = 0x20;
+                packet_len = MsgStreamJoin::PACKED_LEN;
+                msg.encode_as_be_bytes(&mut buf[OPCODE_LEN..]);
+            }
+            CmdReport(msg) => {
+                opcode = 0x21;
+                packet_len = MsgReport::PACKED_LEN;
+                msg.encode_as_be_bytes(&mut buf[OPCODE_LEN..]);
+            }
             _ => {
-                return [0].to_vec();
+                buf[0] = 0;
+                return 1;
             }
         }
         buf[0..OPCODE_LEN].copy_from_slice(&opcode.to_be_bytes());
-        buf[..packet_len + OPCODE_LEN].to_vec()
+        packet_len + OPCODE_LEN
     }
 }
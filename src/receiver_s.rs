@@ -2,17 +2,18 @@ use byte_unit::Byte;
 use log::{debug, error, info, warn};
 use std::collections::HashMap;
 use std::io;
-use std::io::Write;
-use std::net::SocketAddrV4;
+use std::io::{Seek, SeekFrom, Write};
 use std::sync::{Arc, RwLock};
 use std::time::Instant;
 
 use crate::bitarray::BitArray;
+use crate::crypt::{Cipher, Secret, SALT_LEN};
 use crate::datafifo::DataFIFO;
 use crate::dev::disk::Disk;
+use crate::fec;
 use crate::multicast::*;
 use crate::packet::*;
-use crate::slice::Slice;
+use crate::slice::{merge_range, Slice};
 use crate::*;
 
 pub struct McastReceiver {
@@ -21,9 +22,21 @@ pub struct McastReceiver {
     pub disk: Option<Disk>,
     data_fifo: DataFIFO,
     rcvbuf: u32,
+    capabilities: u32,
     client_number: u32,
     block_size: u32,
     max_slices: u32,
+    joined: bool,
+    first_slice: u32,
+    flags: u16,
+    passive_done: Option<u32>,
+    missed: Vec<(usize, usize)>,
+    lost_slices: u32,
+    image_digest: Option<[u8; 32]>,
+    secret: Option<Secret>,
+    cipher: Option<Cipher>,
+    selector: Option<String>,
+    pub undecryptable: u32,
     pub transferstarted: bool,
     pub slices: HashMap<u32, Slice>,
     pub start_time: Instant,
@@ -34,13 +47,12 @@ pub struct McastReceiver {
 
 impl McastReceiver {
     pub fn new(
-        nic: usize,
+        socket: MultiCast,
         rcvbuf: usize,
         data_fifo: DataFIFO,
         disk: Option<Disk>,
         disk_trace: Arc<RwLock<Box<Vec<(Instant, Instant)>>>>,
     ) -> Self {
-        let socket = MultiCast::receiver(nic, rcvbuf);
         socket.join_multicast().unwrap();
 
         Self {
@@ -51,7 +63,19 @@ impl McastReceiver {
             client_number: 0,
             block_size: 0,
             rcvbuf: rcvbuf as u32,
+            capabilities: RECEIVER_CAPABILITIES,
             max_slices: MAX_SLICE_SIZE,
+            joined: true,
+            first_slice: 0,
+            flags: 0,
+            passive_done: None,
+            missed: Vec::new(),
+            lost_slices: 0,
+            image_digest: None,
+            secret: None,
+            cipher: None,
+            selector: None,
+            undecryptable: 0,
             transferstarted: false,
             slices: HashMap::new(),
             start_time: Instant::now(),
@@ -61,32 +85,78 @@ impl McastReceiver {
         }
     }
 
+    // Listen only: never acknowledge anything to the sender.
+    pub fn set_passive(&mut self) {
+        self.flags |= FLAG_PASSIVE;
+        // the slice size of the sender is not announced in its hello
+        self.max_slices = 8 * 1024;
+    }
+
+    // Only take part in sessions encrypted with a key derived from secret.
+    pub fn set_secret(&mut self, secret: Secret) {
+        self.secret = Some(secret);
+    }
+
+    // Only join the session with this name or id.
+    pub fn set_session(&mut self, selector: &str) {
+        self.selector = Some(selector.to_owned());
+    }
+
+    fn wants_session(&self, session: u16, info: &SessionInfo) -> bool {
+        match &self.selector {
+            Some(selector) => info.matches(session, selector),
+            None => true,
+        }
+    }
+
+    fn is_passive(&self) -> bool {
+        self.flags & FLAG_PASSIVE != 0
+    }
+
     pub fn enumerate(&mut self) -> Result<bool, &'static str> {
+        if self.is_passive() {
+            return self.listen();
+        }
         let mut connect_req_sent = false;
-        let mut buff: [u8; 2048] = [0; 2048];
         loop {
-            if !connect_req_sent {
+            // wait for a hello to learn the session id, senders of other
+            // sessions ignore the connect request then
+            if !connect_req_sent && self.socket.session().is_some() {
                 let _ = self.send_connect_req();
                 connect_req_sent = true;
             }
-            if let Ok((msg, _remain)) = self.socket.recv_msg(&mut buff) {
-                match msg {
+            if let Ok(received) = self.socket.wait_msg(None) {
+                let remain = received.payload().to_vec();
+                match self.socket.recycle(received) {
                     Message::CmdConnectReply(m) => {
+                        let info = SessionInfo::parse(m.capabilities, &remain).unwrap_or_default();
+                        if m.clnr != 0xffffffff
+                            && !self.accept_capabilities(m.capabilities, info.salt)
+                        {
+                            let _ = self.send_disconnect();
+                            return Err("Sender requires unsupported capabilities");
+                        }
                         self.client_number = m.clnr;
-                        self.block_size = m.blocksize;
-                        self.max_slices = m.max_slices;
-                        self.socket.multicast_addr =
-                            SocketAddrV4::new(m.mcastaddr(), self.socket.myip_addr.port());
+                        self.joined = m.capabilities & CAP_STREAMING == 0;
+                        self.set_block_size(m.blocksize);
+                        self.set_max_slices(m.max_slices);
+                        self.socket.multicast_addr = self.socket.group_addr(m.mcastaddr());
                         if self.client_number == 0xffffffff {
                             return Err("Too many clients already connected");
                         }
+                        self.socket.join_session(m.session);
+                        info!("Session {:#06x} {}", m.session, info.name());
                         break;
                     }
-                    Message::CmdHello(m) => {
+                    Message::CmdHello(m) | Message::CmdHelloStreaming(m) => {
+                        let info = SessionInfo::parse(m.capabilities, &remain).unwrap_or_default();
+                        if !self.wants_session(m.session, &info) {
+                            continue;
+                        }
+                        self.socket.join_session(m.session);
                         connect_req_sent = false;
-                        self.block_size = m.blocksize as u32;
-                        self.socket.multicast_addr =
-                            SocketAddrV4::new(m.mcastaddr(), self.socket.myip_addr.port());
+                        self.set_block_size(m.blocksize as u32);
+                        self.socket.multicast_addr = self.socket.group_addr(m.mcastaddr());
                     }
                     _ => {}
                 }
@@ -100,13 +170,280 @@ impl McastReceiver {
         );
         let _ = self.socket.join_multicast();
         info!(
-            "Broadcast IP: {}, Multicast IP: {}",
-            self.socket.broadcast_addr, self.socket.multicast_addr
+            "Announce IP: {}, Multicast IP: {}",
+            self.socket.announce_addr, self.socket.multicast_addr
         );
 
         Ok(true)
     }
 
+    // Passive receivers learn the session parameters from the hello the
+    // sender repeats during the transfer.
+    fn listen(&mut self) -> Result<bool, &'static str> {
+        loop {
+            if let Ok(received) = self.socket.wait_msg(None) {
+                let remain = received.payload().to_vec();
+                match self.socket.recycle(received) {
+                    Message::CmdHello(m) => {
+                        let info = SessionInfo::parse(m.capabilities, &remain).unwrap_or_default();
+                        if !self.wants_session(m.session, &info) {
+                            continue;
+                        }
+                        if !self.accept_cipher(m.capabilities, info.salt) {
+                            return Err("Can not decrypt the session");
+                        }
+                        self.socket.join_session(m.session);
+                        info!("Session {:#06x} {}", m.session, info.name());
+                        self.capabilities = m.capabilities & RECEIVER_CAPABILITIES;
+                        if self.capabilities & CAP_DIGEST != 0 {
+                            self.data_fifo.enable_digest();
+                        }
+                        self.set_block_size(m.blocksize as u32);
+                        self.socket.multicast_addr = self.socket.group_addr(m.mcastaddr());
+                        break;
+                    }
+                    Message::CmdHelloStreaming(_m) => {
+                        return Err("Passive receivers can not join a streaming session");
+                    }
+                    _ => {}
+                }
+            }
+        }
+        info!(
+            "IP: {} Listening passively to {}",
+            self.socket.myip_addr,
+            self.socket.receivefrom.unwrap(),
+        );
+        let _ = self.socket.join_multicast();
+        info!(
+            "Announce IP: {}, Multicast IP: {}",
+            self.socket.announce_addr, self.socket.multicast_addr
+        );
+
+        Ok(true)
+    }
+
+    // A passive receiver can not ask for retransmissions. Once the sender has
+    // moved past a slice, its missing blocks are zero-filled and recorded.
+    fn finish_passive(&mut self, slice_no: u32) {
+        let mut done = match self.passive_done {
+            Some(done) => done,
+            None => {
+                if slice_no > 0 {
+                    warn!("Attached at slice {slice_no}, offsets are relative to it");
+                }
+                slice_no
+            }
+        };
+        while done < slice_no {
+            if let Some(slice) = self.slices.get_mut(&done) {
+                fec::recover(slice, &mut self.data_fifo);
+                for block_no in 0..slice.blocks_in_slice {
+                    if (block_no as usize) < slice.retransmit.map.len()
+                        && slice.retransmit.map.get(block_no as usize)
+                    {
+                        continue;
+                    }
+                    let pos = slice.get_block_pos(block_no);
+                    let len = slice.block_len(block_no) as usize;
+                    self.data_fifo.set(pos, &vec![0; len]);
+                    merge_range(&mut self.missed, pos, len);
+                }
+                slice.end_time = Instant::now();
+            } else {
+                error!("slice {done} lost entirely, the data after it is misplaced");
+                self.lost_slices += 1;
+            }
+            done += 1;
+        }
+        self.passive_done = Some(done);
+    }
+
+    // Compare the digest of the written data with the one of the sender and
+    // report the result to the sender.
+    pub fn send_report(&mut self) -> io::Result<usize> {
+        let digest = match self.data_fifo.digest() {
+            Some(digest) => digest,
+            None => return Ok(0),
+        };
+        let status = match self.image_digest {
+            Some(image_digest) if image_digest == digest => {
+                info!("Image verified, SHA-256: {}", hex(&digest));
+                REPORT_MATCH
+            }
+            Some(image_digest) => {
+                error!(
+                    "Image digest mismatch, SHA-256: {} expected: {}",
+                    hex(&digest),
+                    hex(&image_digest)
+                );
+                REPORT_MISMATCH
+            }
+            None => {
+                warn!("No image digest from the sender, SHA-256: {}", hex(&digest));
+                REPORT_NO_DIGEST
+            }
+        };
+        if self.is_passive() {
+            return Ok(0);
+        }
+        let bytes = self.data_fifo.written_bytes() as u64;
+        let msg = Message::CmdReport(MsgReport::new(status, bytes, digest));
+        self.socket.send_msg(&msg.encode())
+    }
+
+    pub fn report_missed(&self) {
+        if !self.is_passive() {
+            return;
+        }
+        if self.missed.is_empty() && self.lost_slices == 0 {
+            info!("Nothing missed");
+            return;
+        }
+        let total: usize = self.missed.iter().map(|range| range.1).sum();
+        warn!(
+            "Missed {} in {} ranges, {} slices lost",
+            Byte::from_bytes(total as u128).get_appropriate_unit(false),
+            self.missed.len(),
+            self.lost_slices
+        );
+        for (pos, len) in &self.missed {
+            warn!("  {:#012x} - {:#012x} ({} bytes)", pos, pos + len, len);
+        }
+    }
+
+    // Take over the session features announced by the sender. Returns false
+    // if the sender expects a feature this receiver does not implement.
+    fn accept_capabilities(&mut self, capabilities: u32, salt: &[u8]) -> bool {
+        let unsupported = capabilities & !RECEIVER_CAPABILITIES;
+        if unsupported != 0 {
+            error!(
+                "Sender requires unsupported capabilities {}",
+                capabilities_str(unsupported)
+            );
+            return false;
+        }
+        if !self.accept_cipher(capabilities, salt) {
+            return false;
+        }
+        if capabilities != self.capabilities {
+            info!("Session capabilities: {}", capabilities_str(capabilities));
+        }
+        self.capabilities = capabilities;
+        if capabilities & CAP_DIGEST != 0 {
+            self.data_fifo.enable_digest();
+        }
+        true
+    }
+
+    // Derive the session key from the salt announced by the sender. A
+    // receiver with a secret refuses to receive the image in plain text.
+    fn accept_cipher(&mut self, capabilities: u32, salt: &[u8]) -> bool {
+        let secret = match (&self.secret, capabilities & CAP_ENCRYPTED != 0) {
+            (None, false) => return true,
+            (None, true) => {
+                error!("Sender encrypts the image, a key is required");
+                return false;
+            }
+            (Some(_), false) => {
+                error!("Sender does not encrypt the image");
+                return false;
+            }
+            (Some(secret), true) => secret,
+        };
+        let salt: &[u8; SALT_LEN] = match salt.try_into() {
+            Ok(salt) => salt,
+            Err(_) => {
+                error!("Sender announced no session salt");
+                return false;
+            }
+        };
+        if self.cipher.as_ref().map(Cipher::salt) != Some(salt) {
+            self.cipher = Some(secret.session(salt));
+        }
+        true
+    }
+
+    // Blocks which fail to decrypt are dropped, and requested again like
+    // lost ones.
+    fn decrypt<'a>(&mut self, header: Message, data: &'a mut [u8]) -> Option<&'a [u8]> {
+        let cipher = match &self.cipher {
+            Some(cipher) => cipher,
+            None => return Some(data),
+        };
+        let mut buf = [0; HEADER_MAX];
+        let len = header.encode_into(&mut buf);
+        match cipher.open_in_place(&buf[..len], data) {
+            Some(len) => Some(&data[..len]),
+            None => {
+                debug!("Can't decrypt {:?}", header);
+                self.undecryptable += 1;
+                None
+            }
+        }
+    }
+
+    // slices sent before a late joiner was admitted to the stream are ignored
+    fn in_stream(&self, slice_no: u32) -> bool {
+        self.joined && slice_no >= self.first_slice
+    }
+
+    fn process_streamjoin(&mut self, msg: &MsgStreamJoin) -> bool {
+        if !self.joined {
+            info!(
+                "Join the stream at slice {} offset {} of {}",
+                msg.sliceno, msg.offset, msg.image_size
+            );
+            self.first_slice = msg.sliceno;
+            self.joined = true;
+            self.data_fifo
+                .set_image(msg.offset as usize, msg.image_size as usize);
+        }
+        RUNNING
+    }
+
+    // blocks of the sender, the socket makes room for them
+    fn set_block_size(&mut self, block_size: u32) {
+        self.block_size = block_size;
+        self.socket.set_block_size(block_size);
+    }
+
+    // block numbers have 16 bits, a larger slice size is not from a sender
+    fn set_max_slices(&mut self, max_slices: u32) {
+        self.max_slices = max_slices.min(u16::MAX as u32 + 1);
+    }
+
+    // Slices of more blocks than the sender announced can not be received,
+    // nor any slice before the block size is known.
+    fn slice_fits(&self, bytes: u32) -> bool {
+        self.block_size != 0 && bytes.div_ceil(self.block_size) <= self.max_slices
+    }
+
+    // The hello repeats the block size, which changes until the transfer
+    // starts.
+    fn process_hello(&mut self, msg: &MsgHello) -> bool {
+        if self.slices.is_empty() {
+            self.set_block_size(msg.blocksize as u32);
+        }
+        RUNNING
+    }
+
+    fn process_connectreply(&mut self, msg: &MsgConnectReply, payload: &[u8]) -> bool {
+        if msg.clnr != self.client_number {
+            return RUNNING;
+        }
+        let info = SessionInfo::parse(msg.capabilities, payload).unwrap_or_default();
+        if !self.accept_capabilities(msg.capabilities, info.salt) {
+            return ENDLOOP;
+        }
+        // the sender lowers the block size until the transfer starts, see --probe-mtu
+        if self.slices.is_empty() {
+            self.set_block_size(msg.blocksize);
+            self.set_max_slices(msg.max_slices);
+        }
+        RUNNING
+    }
+
     pub fn id(&self) -> String {
         self.client_number.to_string()
     }
@@ -117,12 +454,12 @@ impl McastReceiver {
     }
 
     pub fn send_connect_req(&mut self) -> io::Result<usize> {
-        let msg = Message::CmdConnectReq(MsgConnectReq::new(0x81, self.rcvbuf));
+        let msg = Message::CmdConnectReq(MsgConnectReq::new(RECEIVER_CAPABILITIES, self.rcvbuf));
         if let Some(sendto) = self.socket.receivefrom {
             self.socket.send_to(&msg.encode(), sendto)
         } else {
             self.socket
-                .send_to(&msg.encode(), self.socket.broadcast_addr)
+                .send_to(&msg.encode(), self.socket.announce_addr)
         }
     }
 
@@ -132,11 +469,17 @@ impl McastReceiver {
     }
 
     pub fn send_go(&mut self) -> io::Result<usize> {
+        if self.is_passive() {
+            return Ok(0);
+        }
         let msg = Message::CmdGo(MsgGo::new());
         self.socket.send_msg(&msg.encode())
     }
 
     pub fn send_disconnect(&mut self) -> io::Result<usize> {
+        if self.is_passive() {
+            return Ok(0);
+        }
         let msg = Message::CmdDisconnect(MsgDisconnect::new());
         self.socket.send_msg(&msg.encode())
     }
@@ -163,11 +506,48 @@ impl McastReceiver {
         return slice;
     }
 
-    fn process_datablock(&mut self, msg: &DataBlock, data: Vec<u8>) -> bool {
+    fn process_datablock(&mut self, msg: &DataBlock, data: &mut [u8]) -> bool {
+        if !self.slice_fits(msg.bytes) {
+            return RUNNING;
+        }
+        let data = match self.decrypt(Message::CmdData(*msg), data) {
+            Some(data) => data,
+            None => return RUNNING,
+        };
+        if !self.in_stream(msg.sliceno) {
+            return RUNNING;
+        }
+        if self.is_passive() {
+            self.finish_passive(msg.sliceno);
+        }
         let slice = self.get_slice(msg.sliceno, msg.bytes);
         if slice.update_block(msg.blockno as u32) {
             let pos = slice.get_block_pos(msg.blockno as u32);
-            self.data_fifo.set(pos, &data);
+            let len = data.len().min(slice.block_len(msg.blockno as u32) as usize);
+            let data = &data[..len];
+            self.data_fifo.set(pos, data);
+        }
+        RUNNING
+    }
+
+    fn process_fecblock(&mut self, msg: &FecBlock, data: &mut [u8]) -> bool {
+        if !self.slice_fits(msg.bytes) {
+            return RUNNING;
+        }
+        if self.capabilities & CAP_FEC == 0 || !self.in_stream(msg.sliceno) {
+            return RUNNING;
+        }
+        let data = match self.decrypt(Message::CmdFec(*msg), data) {
+            Some(data) => data,
+            None => return RUNNING,
+        };
+        if self.is_passive() {
+            self.finish_passive(msg.sliceno);
+        }
+        let slice = self.get_slice(msg.sliceno, msg.bytes);
+        if !slice.is_completed() {
+            slice.fec_stripes = msg.stripes as u32;
+            slice.fec_blocks.insert(msg.blockno as u32, data.to_vec());
         }
         RUNNING
     }
@@ -213,11 +593,63 @@ impl McastReceiver {
         }
     }
 
-    fn process_reqack(&mut self, msg: &MsgReqAck, ready_set: Vec<u8>) -> bool {
+    // the image digest closes the final reqack when the session has CAP_DIGEST
+    fn split_digest(&mut self, msg: &MsgReqAck, payload: &mut Vec<u8>) {
+        if msg.bytes != 0 || self.capabilities & CAP_DIGEST == 0 || payload.len() < 32 {
+            return;
+        }
+        let digest = payload.split_off(payload.len() - 32);
+        self.image_digest = digest.try_into().ok();
+    }
+
+    // the slice checksum follows the ready set when the session has CAP_CHECKSUM
+    fn split_reqack(&self, mut payload: Vec<u8>) -> (Vec<u8>, Option<u32>) {
+        if self.capabilities & CAP_CHECKSUM == 0 || payload.len() < 4 {
+            return (payload, None);
+        }
+        let checksum = payload.split_off(payload.len() - 4);
+        (
+            payload,
+            Some(u32::from_be_bytes(checksum.try_into().unwrap())),
+        )
+    }
+
+    fn process_reqack(&mut self, msg: &MsgReqAck, mut payload: Vec<u8>) -> bool {
+        if !self.slice_fits(msg.bytes) {
+            return RUNNING;
+        }
+        self.split_digest(msg, &mut payload);
+        if self.is_passive() {
+            return self.process_reqack_passive(msg);
+        }
+        let (ready_set, checksum) = self.split_reqack(payload);
         let ready_set = BitArray::from(ready_set);
         if ready_set.get(self.client_number as usize) {
             return RUNNING;
         }
+        if msg.bytes != 0 && !self.in_stream(msg.sliceno) {
+            if !self.joined {
+                let _ = self.send_connect_req();
+            }
+            return RUNNING;
+        }
+        if let Some(slice) = self.slices.get_mut(&msg.sliceno) {
+            fec::recover(slice, &mut self.data_fifo);
+            if let Some(checksum) = checksum {
+                if slice.is_completed() && !slice.verified {
+                    if slice.compute_checksum(&self.data_fifo) == checksum {
+                        slice.verified = true;
+                    } else {
+                        warn!(
+                            "slice {} checksum mismatch, request the whole slice again",
+                            msg.sliceno
+                        );
+                        slice.reset_blocks();
+                        slice.event("checksum".to_string());
+                    }
+                }
+            }
+        }
         let slice = self.get_slice(msg.sliceno, msg.bytes);
         if msg.rxmit == 0 && msg.bytes == 0 {
             self.data_fifo.close();
@@ -227,6 +659,7 @@ impl McastReceiver {
         }
         if slice.is_completed() {
             slice.end_time = Instant::now();
+            slice.fec_blocks.clear();
             let _ = self.write();
             let _ = self.send_ok(msg.sliceno);
             self.get_slice(msg.sliceno, msg.bytes)
@@ -244,22 +677,57 @@ impl McastReceiver {
         RUNNING
     }
 
+    fn process_reqack_passive(&mut self, msg: &MsgReqAck) -> bool {
+        self.finish_passive(msg.sliceno);
+        if msg.rxmit == 0 && msg.bytes == 0 {
+            self.data_fifo.close();
+            let _ = self.write();
+            return ENDLOOP;
+        }
+        self.get_slice(msg.sliceno, msg.bytes);
+        let _ = self.write();
+        self.display_progress(false);
+        if getch(0) == Some('q') {
+            self.data_fifo.close();
+            return ENDLOOP;
+        }
+        RUNNING
+    }
+
+    /// Handle the next message, waiting for it until a key is pressed.
     pub fn dispatch_message(&mut self) -> Result<bool, &'static str> {
-        let mut buff: [u8; 2048] = [0; 2048];
-        match self.socket.recv_msg(&mut buff) {
-            Ok((msg, remain)) => match msg {
-                Message::CmdData(m) => {
-                    if !self.transferstarted {
-                        self.start_time = Instant::now();
-                        self.transferstarted = true;
+        match self.socket.wait_msg(None) {
+            Ok(mut received) => {
+                let result = match received.msg {
+                    Message::CmdData(m) => {
+                        if !self.transferstarted {
+                            self.start_time = Instant::now();
+                            self.transferstarted = true;
+                        }
+                        Ok(self.process_datablock(&m, received.payload_mut()))
                     }
-                    return Ok(self.process_datablock(&m, remain));
-                }
-                Message::CmdReqack(m) => return Ok(self.process_reqack(&m, remain)),
-                Message::CmdHello(_m) => return Ok(RUNNING),
-                _ => return Err("Received an unexpected message."),
-            },
-            Err(ref err) if err.kind() == std::io::ErrorKind::TimedOut => {
+                    Message::CmdFec(m) => Ok(self.process_fecblock(&m, received.payload_mut())),
+                    Message::CmdReqack(m) => {
+                        Ok(self.process_reqack(&m, received.payload().to_vec()))
+                    }
+                    Message::CmdConnectReply(ref m) => {
+                        Ok(self.process_connectreply(m, received.payload()))
+                    }
+                    Message::CmdStreamJoin(m) => Ok(self.process_streamjoin(&m)),
+                    Message::CmdHello(ref m) | Message::CmdHelloStreaming(ref m) => {
+                        Ok(self.process_hello(m))
+                    }
+                    _ => Err("Received an unexpected message."),
+                };
+                self.socket.recycle(received);
+                result
+            }
+            Err(ref err)
+                if err.kind() == io::ErrorKind::TimedOut
+                    || err.kind() == io::ErrorKind::WouldBlock
+                    // forged, undecodable or of another session, dropped by the socket
+                    || err.kind() == io::ErrorKind::InvalidData =>
+            {
                 return Ok(RUNNING);
             }
             Err(_err) => return Err("Unexpected error!!"),
@@ -279,11 +747,27 @@ impl McastReceiver {
     }
 
     fn write(&mut self) -> io::Result<()> {
-        let mut size: usize = self.data_fifo.len();
-        if !self.data_fifo.is_closed() && ((size % self.write_chunk) != 0) {
-            size -= size % self.write_chunk;
-        }
-        if size > 0 {
+        loop {
+            let mut size: usize = self.data_fifo.len();
+            if !self.data_fifo.is_closed() && ((size % self.write_chunk) != 0) {
+                size -= size % self.write_chunk;
+            }
+            if let Some((offset, until_wrap, skip)) = self.data_fifo.image_pos() {
+                let available = self.data_fifo.len();
+                if skip > 0 && available != 0 {
+                    self.data_fifo.drain(skip.min(available));
+                    continue;
+                }
+                size = size.min(until_wrap);
+                if size > 0 {
+                    if let Some(ref mut disk) = self.disk {
+                        disk.seek(SeekFrom::Start(offset as u64))?;
+                    }
+                }
+            }
+            if size == 0 {
+                return Ok(());
+            }
             let start = Instant::now();
             debug!(" -> start write {}", self.data_fifo.len());
             let data = self.data_fifo.pop(size);
@@ -311,6 +795,5 @@ impl McastReceiver {
             debug!(" <- end write {:?}", end - start);
             self.disk_trace.write().unwrap().push((start, end));
         }
-        Ok(())
     }
 }
@@ -1,27 +1,52 @@
 use byte_unit::Byte;
-use log::{info, trace, warn};
+use log::{error, info, trace, warn};
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::io;
 use std::io::Write;
 use std::io::{Error, ErrorKind};
-use std::net::SocketAddrV4;
+use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
 use std::time::{Duration, Instant};
 
+use crate::auth::{Psk, TAG_LEN};
 use crate::bitarray::BitArray;
+use crate::congestion::{CongestionControl, Control, Legacy, Round, SliceDone};
+use crate::crypt::{Cipher, Secret, AEAD_TAG_LEN};
 use crate::datafifo::DataFIFO;
+use crate::fec::{self, Fec};
 use crate::multicast::*;
+use crate::multinic::Interfaces;
+use crate::pacer::Pacer;
 use crate::packet::*;
 use crate::slice::Slice;
+use crate::transport::Gather;
+use crate::udpcast::{self, UDPCAST_CAPABILITIES, UDPCAST_MAX_CLIENTS, UDPCAST_MAX_SLICE_SIZE};
 use crate::*;
 
+// the hello is repeated for late receivers, a reqack for lost answers
+const HELLO_INTERVAL: Duration = Duration::from_secs(1);
+const REQACK_INTERVAL: Duration = Duration::from_secs(1);
+
 #[derive(Debug)]
 pub struct McastSender {
     pub socket: MultiCast,
     data_fifo: Arc<RwLock<DataFIFO>>,
     blocksize: u32,
     capabilities: u32,
-    clientlist: HashMap<SocketAddrV4, (usize, u32, u32)>,
+    fec: Option<Fec>,
+    cipher: Option<Cipher>,
+    session_name: String,
+    udpcast: bool,
+    clientlist: HashMap<SocketAddr, (usize, u32, u32)>,
+    streaming: bool,
+    image_size: usize,
+    joiners: HashMap<SocketAddr, (usize, u32, u32)>,
+    stream: HashMap<SocketAddr, (MsgStreamJoin, u64)>,
+    hello_time: Instant,
+    digest: Sha256,
+    image_digest: Option<[u8; 32]>,
+    reports: HashMap<SocketAddr, MsgReport>,
     pub slices: HashMap<u32, Slice>,
     xmit_slice: i32,
     slice_size: u32,
@@ -31,62 +56,317 @@ pub struct McastSender {
     elaps_time: Instant,
     lastsendtime: Instant,
     written_elaps: u128,
+    pacer: Option<Pacer>,
+    max_bitrate: Option<u64>,
+    congestion: Box<dyn CongestionControl>,
+    // blocks of the current slice sent again
+    resent: u32,
+    // pause between the end of a slice and the next one
+    slice_gap: Duration,
+    // size the blocks to the path MTU, see set_probe_mtu
+    probe_mtu: bool,
+    // bytes put on the wire since the last progress line
+    sent_bytes: usize,
+    interfaces: Option<Arc<Interfaces>>,
+    // bytes sent on each interface up to the last progress line
+    nic_sent: Vec<usize>,
+    // block headers and encrypted payloads of the batch being sent
+    headers: Vec<[u8; BLOCK_HEADER_LEN]>,
+    sealed: Vec<Vec<u8>>,
+    // shard buffers of the FEC stripes
+    fec_encoder: fec::Encoder,
 }
 
 impl McastSender {
-    pub fn new(nic: usize, ttl: u32, max_slices: u32, data_fifo: Arc<RwLock<DataFIFO>>) -> Self {
-        let socket = MultiCast::sender(nic);
+    pub fn new(
+        mut socket: MultiCast,
+        ttl: u32,
+        max_slices: u32,
+        data_fifo: Arc<RwLock<DataFIFO>>,
+    ) -> Self {
         let _ = socket.set_ttl(ttl);
+        socket.set_session(new_session_id());
+        // data read from the disk ends the wait for messages
+        if let Some(waker) = socket.waker() {
+            data_fifo.read().unwrap().notify().watch(&waker);
+        }
 
         Self {
             socket,
             max_slices,
             data_fifo,
             blocksize: BLOCK_SIZE,
-            capabilities: 0,
+            // FEC and streaming are switched on by set_fec and set_streaming
+            capabilities: SENDER_CAPABILITIES & !(CAP_FEC | CAP_STREAMING),
+            fec: None,
+            cipher: None,
+            session_name: String::new(),
+            udpcast: false,
             retransmits: 0,
             slice_size: 130,
             xmit_slice: -1,
             clientlist: HashMap::new(),
+            streaming: false,
+            image_size: 0,
+            joiners: HashMap::new(),
+            stream: HashMap::new(),
+            hello_time: Instant::now(),
+            digest: Sha256::new(),
+            image_digest: None,
+            reports: HashMap::new(),
             slices: HashMap::new(),
             start_time: Instant::now(),
             elaps_time: Instant::now(),
             lastsendtime: Instant::now(),
             written_elaps: 0,
+            pacer: None,
+            max_bitrate: None,
+            congestion: Box::new(Legacy::default()),
+            resent: 0,
+            slice_gap: Duration::ZERO,
+            probe_mtu: false,
+            sent_bytes: 0,
+            interfaces: None,
+            nic_sent: Vec::new(),
+            headers: vec![[0; BLOCK_HEADER_LEN]; BATCH_SIZE],
+            sealed: vec![Vec::new(); BATCH_SIZE],
+            fec_encoder: fec::Encoder::default(),
+        }
+    }
+
+    pub fn set_fec(&mut self, fec: Fec) {
+        self.fec = Some(fec);
+        self.capabilities |= CAP_FEC;
+    }
+
+    // Send the data and FEC blocks at no more than bits_per_sec.
+    pub fn set_max_bitrate(&mut self, bits_per_sec: u64) {
+        self.max_bitrate = Some(bits_per_sec);
+        self.steer(|_, _| {});
+    }
+
+    pub fn set_congestion(&mut self, congestion: Box<dyn CongestionControl>) {
+        self.congestion = congestion;
+        self.steer(|congestion, control| congestion.start(control));
+    }
+
+    pub fn congestion(&self) -> &'static str {
+        self.congestion.name()
+    }
+
+    // Let the congestion control adjust slice size and bitrate, within
+    // --slices and --max-bitrate.
+    fn steer(&mut self, adjust: impl FnOnce(&mut dyn CongestionControl, &mut Control)) {
+        let mut control = Control {
+            slice_size: self.slice_size,
+            bitrate: self.pacer.as_ref().map(Pacer::bitrate),
+        };
+        adjust(self.congestion.as_mut(), &mut control);
+        self.slice_size = control.slice_size.min(self.max_blocks()).max(32);
+        let bitrate = match (control.bitrate, self.max_bitrate) {
+            (Some(bitrate), Some(max)) => Some(bitrate.min(max)),
+            (bitrate, max) => bitrate.or(max),
+        };
+        match (bitrate, self.pacer.as_mut()) {
+            (Some(bitrate), Some(pacer)) => pacer.set_rate(bitrate),
+            (bitrate, _) => self.pacer = bitrate.map(Pacer::new),
+        }
+    }
+
+    // The socket spans several interfaces, report clients and progress per interface.
+    pub fn set_interfaces(&mut self, interfaces: Arc<Interfaces>) {
+        self.nic_sent = vec![0; interfaces.len()];
+        self.interfaces = Some(interfaces);
+    }
+
+    // clients last heard on the interface
+    fn nic_clients(&self, nic: usize) -> Vec<SocketAddr> {
+        match &self.interfaces {
+            Some(interfaces) => self
+                .clientlist
+                .keys()
+                .filter(|addr| interfaces.nic_of(addr) == Some(nic))
+                .copied()
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn set_slice_gap(&mut self, gap: Duration) {
+        self.slice_gap = gap;
+    }
+
+    // Bytes of data in a block, the authentication tags of set_psk and
+    // set_secret are taken from them whichever is called first. What is left
+    // for the data is announced and has to be MIN_BLOCK_SIZE at least.
+    pub fn set_blocksize(&mut self, blocksize: u32) -> Result<(), String> {
+        let min = MIN_BLOCK_SIZE + self.tag_len();
+        if !(min..=MAX_BLOCK_SIZE).contains(&blocksize) {
+            return Err(format!(
+                "Invalid block size, from {min} to {MAX_BLOCK_SIZE} bytes"
+            ));
+        }
+        self.blocksize = blocksize - self.tag_len();
+        self.slice_size = self.slice_size.min(self.max_blocks()).max(32);
+        Ok(())
+    }
+
+    // Pick the largest block size which reaches the receivers unfragmented:
+    // the path MTU to the group is probed before the first hello, the one to
+    // each receiver when it connects.
+    pub fn set_probe_mtu(&mut self) {
+        self.probe_mtu = true;
+    }
+
+    // bytes of a block taken by the authentication tags
+    fn tag_len(&self) -> u32 {
+        let mut len = 0;
+        if self.socket.has_psk() {
+            len += TAG_LEN;
+        }
+        if self.cipher.is_some() {
+            len += AEAD_TAG_LEN;
+        }
+        len as u32
+    }
+
+    // Blocks of a slice, fewer of larger blocks keep the bytes of a slice
+    // within what --slices gives with the default block size.
+    fn max_blocks(&self) -> u32 {
+        let blocksize = self.blocksize + self.tag_len();
+        if blocksize <= BLOCK_SIZE {
+            return self.max_slices;
+        }
+        (self.max_slices * BLOCK_SIZE / blocksize).max(32)
+    }
+
+    // Largest block which reaches addr without fragmenting, after the IP,
+    // UDP and block headers.
+    fn path_blocksize(&self, addr: SocketAddr) -> Option<u32> {
+        let mtu = match self.socket.path_mtu(addr) {
+            Ok(mtu) => mtu,
+            Err(err) => {
+                warn!("Can't find the path MTU to {addr}: {err}");
+                return None;
+            }
+        };
+        let ip_header = if addr.is_ipv4() { 20 } else { 40 };
+        let blocksize = mtu.saturating_sub(ip_header + 8 + BLOCK_HEADER_LEN) as u32;
+        Some(blocksize.clamp(MIN_BLOCK_SIZE + self.tag_len(), MAX_BLOCK_SIZE))
+    }
+
+    // A receiver behind a smaller MTU lowers the block size of the session,
+    // the receivers told a larger one get the new size with the negotiation.
+    fn fit_blocksize(&mut self, clientaddr: SocketAddr) {
+        if let Some(blocksize) = self.path_blocksize(clientaddr) {
+            if blocksize < self.blocksize + self.tag_len() {
+                info!("Blocks of {blocksize} bytes for the path MTU to {clientaddr}");
+                let _ = self.set_blocksize(blocksize);
+            }
+        }
+    }
+
+    // Data left in a block after a tag of tag_len bytes, an error when it is
+    // less than receivers take.
+    fn data_left(&self, tag_len: usize) -> Result<u32, String> {
+        let blocksize = self.blocksize - tag_len as u32;
+        if blocksize < MIN_BLOCK_SIZE {
+            return Err(format!(
+                "Blocks of {} bytes leave less than {MIN_BLOCK_SIZE} bytes of data beside the tag",
+                self.blocksize + self.tag_len()
+            ));
+        }
+        Ok(blocksize)
+    }
+
+    // the authentication tag takes its room from the data blocks
+    pub fn set_psk(&mut self, psk: Psk) -> Result<(), String> {
+        self.blocksize = self.data_left(TAG_LEN)?;
+        self.socket.set_psk(psk);
+        Ok(())
+    }
+
+    // Encrypt the image with a key derived for this session, the
+    // authentication tag again takes its room from the data blocks.
+    pub fn set_secret(&mut self, secret: &Secret) -> Result<(), String> {
+        self.blocksize = self.data_left(AEAD_TAG_LEN)?;
+        self.cipher = Some(secret.new_session());
+        self.capabilities |= CAP_ENCRYPTED;
+        Ok(())
+    }
+
+    // Name announced with the hello, receivers select the session by it.
+    pub fn set_session_name(&mut self, name: &str) {
+        self.session_name = name.to_owned();
+    }
+
+    pub fn session(&self) -> String {
+        format!(
+            "{:#06x} {}",
+            self.socket.session().unwrap_or(0),
+            self.session_name
+        )
+    }
+
+    // Talk the plain udpcast protocol, so that udp-receiver can join
+    pub fn set_udpcast(&mut self) {
+        self.udpcast = true;
+        // udpcast keeps the session field reserved
+        self.socket.set_session(0);
+        self.capabilities = UDPCAST_CAPABILITIES;
+        self.max_slices = UDPCAST_MAX_SLICE_SIZE;
+    }
+
+    // bytes of the ready set following a reqack
+    fn ready_set_len(&self) -> usize {
+        if self.udpcast {
+            udpcast::map_len(UDPCAST_MAX_CLIENTS)
+        } else {
+            udpcast::map_len(MAX_CLIENTS)
+        }
+    }
+
+    // Loop the image of image_size bytes and let receivers join while it is sent.
+    pub fn set_streaming(&mut self, image_size: usize) {
+        if image_size > 0 {
+            self.streaming = true;
+            self.image_size = image_size;
+            self.capabilities |= CAP_STREAMING;
+            // a looped image has no end to verify
+            self.capabilities &= !CAP_DIGEST;
         }
     }
 
     pub fn enumerate(&mut self, timeout: Duration, p2p: bool) -> Result<usize, &'static str> {
-        let mut buff = [0u8; UDP_PACK_SIZE];
+        if self.probe_mtu {
+            let group = self.socket.multicast_addr;
+            if let Some(blocksize) = self.path_blocksize(group) {
+                info!("Blocks of {blocksize} bytes for the path MTU to {group}");
+                let _ = self.set_blocksize(blocksize);
+            }
+        }
         let _ = self.send_hello();
+        self.hello_time = Instant::now();
         self.elaps_time = Instant::now();
         loop {
+            // repeat the hello for receivers which missed it or their connect reply
+            if Instant::now() >= self.hello_time + HELLO_INTERVAL {
+                let _ = self.send_hello();
+                self.hello_time = Instant::now();
+            }
             if let Some(c) = getch(0) {
                 if c == '\r' {
                     break;
                 }
             }
-            if self.elaps_time.elapsed() > timeout {
+            if self.elaps_time.elapsed() >= timeout {
                 break;
             }
-            if let Ok((msg, _remain)) = self.socket.recv_msg(&mut buff) {
+            let deadline = (self.hello_time + HELLO_INTERVAL).min(self.elaps_time + timeout);
+            if let Ok(received) = self.socket.wait_msg(Some(deadline)) {
+                let msg = self.socket.recycle(received);
                 match msg {
-                    Message::CmdConnectReq(m) => {
-                        let clientaddr = self.socket.receivefrom.unwrap();
-                        if !self.clientlist.contains_key(&clientaddr) {
-                            let client_no = self.clientlist.len();
-                            self.clientlist
-                                .insert(clientaddr, (client_no, m.capabilities, m.rcvbuf));
-                            info!(
-                                "New client #{client_no} connected: {} {:?}",
-                                clientaddr,
-                                self.clientlist.get(&clientaddr)
-                            );
-                        }
-                        if let Some(client) = self.clientlist.get(&clientaddr) {
-                            let _ = self.send_connectreply(client.0 as u32);
-                        }
-                    }
+                    Message::CmdConnectReq(m) => self.accept_client(&m, false),
                     Message::CmdDisconnect(_m) => {
                         let clientaddr = self.socket.receivefrom.unwrap();
                         if let Some(client) = self.clientlist.get(&clientaddr) {
@@ -107,9 +387,17 @@ impl McastSender {
 
         info!("{} clients found", self.clientlist.len());
         info!("{:?}\n", self.clientlist);
+        if let Some(interfaces) = self.interfaces.clone() {
+            for nic in 0..interfaces.len() {
+                info!("{}: {:?}", interfaces.addr(nic), self.nic_clients(nic));
+            }
+        }
         self.start_time = Instant::now();
 
         let clients = self.clientlist.len();
+        if clients > 0 {
+            self.negotiate();
+        }
         if clients == 1 && p2p {
             self.socket.multicast_addr = self.socket.receivefrom.unwrap();
         }
@@ -121,92 +409,378 @@ impl McastSender {
         }
     }
 
+    fn free_client_no(&self) -> Option<usize> {
+        (0..MAX_CLIENTS as usize).find(|client_no| {
+            !self
+                .clientlist
+                .values()
+                .chain(self.joiners.values())
+                .any(|client| client.0 == *client_no)
+        })
+    }
+
+    // Register a client and answer its connect request. Clients connecting
+    // while a stream is running wait in joiners until the next slice starts.
+    fn accept_client(&mut self, msg: &MsgConnectReq, late: bool) {
+        // receivers learn the session id from the hello first, only udpcast
+        // clients connect without one
+        if msg.session == 0 && self.socket.session() != Some(0) {
+            return;
+        }
+        let clientaddr = self.socket.receivefrom.unwrap();
+        if msg.capabilities & CAP_REQUIRED != CAP_REQUIRED
+            || (self.streaming && msg.capabilities & CAP_STREAMING == 0)
+            || (self.cipher.is_some() && msg.capabilities & CAP_ENCRYPTED == 0)
+        {
+            warn!(
+                "Refuse client {}: capabilities {}",
+                clientaddr,
+                capabilities_str(msg.capabilities)
+            );
+            let _ = self.send_connectreply(0xffffffff, 0, clientaddr);
+            return;
+        }
+        if let Some(&(join, _)) = self.stream.get(&clientaddr) {
+            // the client missed its join message
+            let _ = self
+                .socket
+                .send_to(&Message::CmdStreamJoin(join).encode(), clientaddr);
+            return;
+        }
+        if !self.clientlist.contains_key(&clientaddr) && !self.joiners.contains_key(&clientaddr) {
+            let client_no = match self.free_client_no() {
+                Some(client_no) => client_no,
+                None => {
+                    warn!("Refuse client {}: too many clients", clientaddr);
+                    let _ = self.send_connectreply(0xffffffff, 0, clientaddr);
+                    return;
+                }
+            };
+            let list = if late {
+                &mut self.joiners
+            } else {
+                &mut self.clientlist
+            };
+            list.insert(clientaddr, (client_no, msg.capabilities, msg.rcvbuf));
+            info!(
+                "New client #{client_no} connected: {} {:?} {}",
+                clientaddr,
+                list.get(&clientaddr),
+                capabilities_str(msg.capabilities)
+            );
+            // udp-receiver takes the block size of the first reply only
+            if self.probe_mtu && !late && !self.udpcast {
+                self.fit_blocksize(clientaddr);
+            }
+        }
+        if let Some(&(client_no, capabilities, _)) = self
+            .clientlist
+            .get(&clientaddr)
+            .or(self.joiners.get(&clientaddr))
+        {
+            let _ = self.send_connectreply(
+                client_no as u32,
+                self.capabilities & capabilities,
+                clientaddr,
+            );
+        }
+    }
+
+    // Move waiting receivers into the session and tell every client which has
+    // not joined the stream yet where the next slice sits in the image.
+    fn admit_joiners(&mut self) {
+        for (clientaddr, client) in self.joiners.drain() {
+            self.clientlist.insert(clientaddr, client);
+        }
+        let offset = self.data_fifo.read().unwrap().slicebase() % self.image_size;
+        let sliceno = self.slices.len() as u32;
+        let newcomers: Vec<SocketAddr> = self
+            .clientlist
+            .keys()
+            .filter(|clientaddr| !self.stream.contains_key(clientaddr))
+            .cloned()
+            .collect();
+        for clientaddr in newcomers {
+            let join = MsgStreamJoin::new(sliceno, offset as u64, self.image_size as u64);
+            info!(
+                "client {} joins the stream at slice {} offset {}",
+                clientaddr, sliceno, offset
+            );
+            let _ = self
+                .socket
+                .send_to(&Message::CmdStreamJoin(join).encode(), clientaddr);
+            let remain = self.image_size + DataFIFO::image_pad(offset);
+            self.stream.insert(clientaddr, (join, remain as u64));
+        }
+    }
+
+    // Account a finished slice to the streaming clients and release the ones
+    // which have the whole image now. Returns false when nobody is left.
+    fn finish_stream_slice(&mut self, bytes: u32) -> bool {
+        let mut done = Vec::new();
+        for (clientaddr, (_, remain)) in self.stream.iter_mut() {
+            *remain = remain.saturating_sub(bytes as u64);
+            if *remain == 0 {
+                done.push(*clientaddr);
+            }
+        }
+        for clientaddr in done {
+            info!("client {} received the whole image", clientaddr);
+            self.remove_client(clientaddr);
+        }
+        !self.clientlist.is_empty() || !self.joiners.is_empty()
+    }
+
+    // Reduce the session features to the ones every client supports and
+    // announce the result to the clients.
+    fn negotiate(&mut self) {
+        let mut session = self.capabilities;
+        for (clientaddr, &(client_no, capabilities, _)) in &self.clientlist {
+            let missing = self.capabilities & !capabilities;
+            if missing != 0 {
+                warn!(
+                    "client #{client_no} {} does not support {}",
+                    clientaddr,
+                    capabilities_str(missing)
+                );
+            }
+            session &= capabilities;
+        }
+        if self.fec.is_some() && session & CAP_FEC == 0 {
+            warn!("FEC disabled, not supported by all clients");
+            self.fec = None;
+        }
+        self.capabilities = session;
+        info!("Session capabilities: {}", capabilities_str(session));
+
+        let clients: Vec<(SocketAddr, usize)> = self
+            .clientlist
+            .iter()
+            .map(|(clientaddr, client)| (*clientaddr, client.0))
+            .collect();
+        for (clientaddr, client_no) in clients {
+            let _ = self.send_connectreply(client_no as u32, session, clientaddr);
+        }
+    }
+
     pub fn send_hello(&mut self) -> io::Result<usize> {
-        let msg = packet::Message::CmdHello(packet::MsgHello::new(
+        let hello = packet::MsgHello::new(
             self.capabilities,
-            self.socket.multicast_addr.ip(),
+            &self.socket.multicast_addr.ip(),
             self.blocksize as u16,
-        ));
-        self.socket
-            .send_to(&msg.encode(), self.socket.broadcast_addr)
+        );
+        let msg = if self.streaming {
+            packet::Message::CmdHelloStreaming(hello)
+        } else {
+            packet::Message::CmdHello(hello)
+        };
+        let msg = if self.udpcast {
+            udpcast::encode(&msg, &[])
+        } else {
+            let salt = self.cipher.as_ref().map_or(&[][..], |cipher| cipher.salt());
+            let mut msg = msg.encode();
+            msg.append(&mut SessionInfo::new(salt, &self.session_name).encode());
+            msg
+        };
+        self.socket.send_to(&msg, self.socket.announce_addr)
     }
 
-    pub fn send_disconnect(&mut self, sendto: SocketAddrV4) -> io::Result<usize> {
+    pub fn send_disconnect(&mut self, sendto: SocketAddr) -> io::Result<usize> {
         let slice_no = self.slices.len() as u32;
         let reqack = packet::MsgReqAck::new(slice_no, 0, 0);
         let mut msg = packet::Message::CmdReqack(reqack).encode();
-        let mut ready_set = BitArray::new(MAX_CLIENTS as usize);
-        msg.append(&mut ready_set.bits());
+        let mut ready_set = BitArray::new(MAX_CLIENTS as usize).bits();
+        ready_set.resize(self.ready_set_len(), 0);
+        msg.append(&mut ready_set);
+        if self.capabilities & CAP_CHECKSUM != 0 {
+            msg.extend_from_slice(&0u32.to_be_bytes());
+        }
         self.socket.send_to(&msg, sendto)
     }
 
     pub fn send_reqack(&mut self) -> io::Result<usize> {
+        let ready_set_len = self.ready_set_len();
+        let checksum = self.capabilities & CAP_CHECKSUM != 0;
+        let image_digest = self.image_digest;
         if self.xmit_slice >= 0 {
             let xmit_slice = self.xmit_slice as u32;
             let slice = self.slices.get_mut(&xmit_slice).unwrap();
             slice.reqack.rxmit = slice.rxmit_id;
-            if self.retransmits == 0 {
-                self.slice_size += self.slice_size / 4;
-                if self.slice_size > self.max_slices {
-                    self.slice_size = self.max_slices;
-                }
-            }
-            if slice.last_good_block > 0 && slice.last_good_block < slice.blocks_in_slice {
-                if slice.last_good_block < self.slice_size / 2 {
-                    self.slice_size = self.slice_size / 2;
-                } else {
-                    self.slice_size = slice.last_good_block;
-                }
+            let mut msg = packet::Message::CmdReqack(slice.reqack).encode();
+            let mut ready_set = slice.ready_set.bits();
+            ready_set.resize(ready_set_len, 0);
+            msg.append(&mut ready_set);
+            if checksum {
+                msg.extend_from_slice(&slice.checksum.to_be_bytes());
             }
-            if self.slice_size < 32 {
-                self.slice_size = 32;
+            if let (0, Some(digest)) = (slice.bytes, image_digest) {
+                msg.extend_from_slice(&digest);
             }
-            slice.last_good_block = 0;
-            let mut msg = packet::Message::CmdReqack(slice.reqack).encode();
-            msg.append(&mut slice.ready_set.bits());
             self.socket.send_to(&msg, self.socket.multicast_addr)
         } else {
             Err(Error::new(ErrorKind::Other, "There is no xmit_slice!"))
         }
     }
 
-    pub fn send_connectreply(&mut self, clnr: u32) -> io::Result<usize> {
-        let msg = packet::Message::CmdConnectReply(packet::MsgConnectReply::new(
+    pub fn send_connectreply(
+        &mut self,
+        clnr: u32,
+        capabilities: u32,
+        sendto: SocketAddr,
+    ) -> io::Result<usize> {
+        let reply = packet::MsgConnectReply::new(
             clnr,
             self.blocksize as u32,
-            self.capabilities,
-            self.max_slices,
-            self.socket.multicast_addr.ip(),
-        ));
-        if let Some(receivefrom) = self.socket.receivefrom {
-            self.socket.send_to(&msg.encode(), receivefrom)
+            capabilities,
+            self.max_blocks(),
+            &self.socket.multicast_addr.ip(),
+        );
+        let msg = if self.udpcast {
+            udpcast::encode_connectreply(&reply)
         } else {
-            Err(Error::new(ErrorKind::Other, "There is no receivefrom!"))
-        }
+            // refused clients get no salt
+            let salt = match &self.cipher {
+                Some(cipher) if capabilities & CAP_ENCRYPTED != 0 => &cipher.salt()[..],
+                _ => &[],
+            };
+            let mut msg = packet::Message::CmdConnectReply(reply).encode();
+            msg.append(&mut SessionInfo::new(salt, &self.session_name).encode());
+            msg
+        };
+        self.socket.send_to(&msg, sendto)
     }
 
     pub fn send_datablock(&mut self, blockno: u32) -> io::Result<usize> {
-        if self.xmit_slice >= 0 {
-            let xmit_slice = self.xmit_slice as u32;
-            let slice = self.slices.get_mut(&xmit_slice).unwrap();
-            let mut msg = packet::Message::CmdData(packet::DataBlock::new(
-                slice.slice_no,
-                blockno as u16,
-                slice.bytes,
-            ))
-            .encode();
-            let mut data = self
-                .data_fifo
-                .write()
-                .unwrap()
-                .get(slice.get_block_pos(blockno), self.blocksize as u32);
-            msg.append(&mut data);
-            self.socket.send_to(&msg, self.socket.multicast_addr)
-        } else {
-            Err(Error::new(ErrorKind::Other, "There is no xmit_slice!"))
+        if self.xmit_slice < 0 {
+            return Err(Error::new(ErrorKind::Other, "There is no xmit_slice!"));
+        }
+        Ok(self.send_blocks(&[blockno]))
+    }
+
+    // Blocks sent at once: one batch, no more than the burst of the pacer.
+    fn batch_len(&self) -> usize {
+        let packet = BLOCK_HEADER_LEN + (self.blocksize + self.tag_len()) as usize;
+        match &self.pacer {
+            Some(pacer) => (pacer.burst() / packet).clamp(1, BATCH_SIZE),
+            None => BATCH_SIZE,
+        }
+    }
+
+    // Wait until the pacer lets out the datagrams of blocks with these data
+    // lengths, charged with their headers and tags.
+    fn pace(&mut self, data_lens: impl Iterator<Item = usize>) {
+        let overhead = BLOCK_HEADER_LEN + self.tag_len() as usize;
+        let bytes = data_lens.map(|len| overhead + len).sum();
+        if let Some(pacer) = self.pacer.as_mut() {
+            pacer.wait(bytes);
         }
     }
 
+    fn send_gathered(&mut self, packets: &[Gather]) -> usize {
+        let bytes = packets.iter().map(|packet| packet.len()).sum();
+        self.sent_bytes += bytes;
+        let _ = self.socket.send_batch(packets, self.socket.multicast_addr);
+        bytes
+    }
+
+    // Send blocks of the slice being sent, their data borrowed from the FIFO
+    // and the headers encoded into buffers kept from batch to batch. Returns
+    // the bytes sent.
+    fn send_blocks(&mut self, blocks: &[u32]) -> usize {
+        let (slice_no, bytes, base) = match self.slices.get(&(self.xmit_slice as u32)) {
+            Some(slice) if self.xmit_slice >= 0 => {
+                (slice.slice_no, slice.bytes, slice.get_block_pos(0))
+            }
+            _ => return 0,
+        };
+        let blocksize = self.blocksize;
+        let data_fifo = Arc::clone(&self.data_fifo);
+        let mut headers = std::mem::take(&mut self.headers);
+        let mut sealed = std::mem::take(&mut self.sealed);
+        let mut sent = 0;
+        for batch in blocks.chunks(self.batch_len()) {
+            // the last block of a slice may be short
+            self.pace(batch.iter().map(|&blockno| {
+                bytes
+                    .saturating_sub(blocksize.saturating_mul(blockno))
+                    .min(blocksize) as usize
+            }));
+            for (header, &blockno) in headers.iter_mut().zip(batch) {
+                Message::CmdData(DataBlock::new(slice_no, blockno as u16, bytes))
+                    .encode_into(header);
+                // the session id is part of the nonce
+                self.socket.stamp(header);
+            }
+            let data_fifo = data_fifo.read().unwrap();
+            let payloads: Vec<(&[u8], &[u8])> = batch
+                .iter()
+                .map(|&blockno| {
+                    data_fifo.as_slices(base + (blocksize * blockno) as usize, blocksize)
+                })
+                .collect();
+            let packets = gather(self.cipher.as_ref(), &headers, &payloads, &mut sealed);
+            sent += self.send_gathered(&packets);
+        }
+        self.headers = headers;
+        self.sealed = sealed;
+        sent
+    }
+
+    fn send_fecblocks(&mut self) {
+        let fec = match self.fec {
+            Some(fec) => fec,
+            None => return,
+        };
+        if self.xmit_slice < 0 {
+            return;
+        }
+        let xmit_slice = self.xmit_slice as u32;
+        let slice = self.slices.get(&xmit_slice).unwrap();
+        let stripes = fec.stripes_for(slice.blocks_in_slice);
+        let mut fecblocks = Vec::new();
+        let mut encoder = std::mem::take(&mut self.fec_encoder);
+        {
+            let data_fifo = self.data_fifo.read().unwrap();
+            for stripe in 0..stripes {
+                let blocks = (stripe..slice.blocks_in_slice)
+                    .step_by(stripes as usize)
+                    .map(|block_no| {
+                        data_fifo.as_slices(slice.get_block_pos(block_no), self.blocksize)
+                    });
+                if encoder.encode(stripe, fec.redundancy, self.blocksize as usize, blocks) {
+                    for index in 0..fec.redundancy {
+                        let blockno = fec::fec_blockno(stripes, stripe, index);
+                        if blockno <= u16::MAX as u32 {
+                            fecblocks.push((blockno as u16, stripe, index));
+                        }
+                    }
+                }
+            }
+        }
+        let (slice_no, bytes) = (slice.slice_no, slice.bytes);
+        let mut headers = std::mem::take(&mut self.headers);
+        let mut sealed = std::mem::take(&mut self.sealed);
+        for batch in fecblocks.chunks(self.batch_len()) {
+            let blocksize = self.blocksize as usize;
+            self.pace(batch.iter().map(|_| blocksize));
+            for (header, (blockno, _, _)) in headers.iter_mut().zip(batch) {
+                Message::CmdFec(FecBlock::new(stripes as u16, slice_no, *blockno, bytes))
+                    .encode_into(header);
+                // the session id is part of the nonce
+                self.socket.stamp(header);
+            }
+            let payloads: Vec<(&[u8], &[u8])> = batch
+                .iter()
+                .map(|&(_, stripe, index)| (encoder.parity(stripe, index), &[][..]))
+                .collect();
+            let packets = gather(self.cipher.as_ref(), &headers, &payloads, &mut sealed);
+            self.send_gathered(&packets);
+        }
+        self.fec_encoder = encoder;
+        self.headers = headers;
+        self.sealed = sealed;
+    }
+
     pub fn display_progress(&mut self, final_disp: bool) {
         let elapsed = self.elaps_time.elapsed();
         if elapsed.as_secs() > 0 || final_disp {
@@ -217,22 +791,46 @@ impl McastSender {
             let writtenbytes = self.data_fifo.read().unwrap().written_bytes() as u128;
             let mbps = writtenbytes / difftime.as_millis();
             let mut embps = 0;
+            let mut rate = 0;
             if elapsed.as_millis() > 0 {
                 embps = (writtenbytes - self.written_elaps) / elapsed.as_millis();
+                // Mbit/s on the wire, retransmissions included
+                rate = self.sent_bytes as u128 * 8 / 1000 / elapsed.as_millis();
             }
+            let limit = match &self.pacer {
+                Some(pacer) => format!("/{}", pacer.bitrate() / 1_000_000),
+                None => String::new(),
+            };
             info!(
-                "Total: {} ({}.{:0<3} MB/s) {:>6} pps, slicesize={}, elaps: ({}.{:0<3} MB/s)",
+                "Total: {} ({}.{:0<3} MB/s) {:>6} pps, {}{} Mbit/s, slicesize={}, elaps: ({}.{:0<3} MB/s)",
                 Byte::from_bytes(writtenbytes)
                     .get_appropriate_unit(false)
                     .to_string(),
                 mbps / 1000,
                 mbps % 1000,
                 self.socket.packet_count,
+                rate,
+                limit,
                 self.slice_size,
                 embps / 1000,
                 embps % 1000
             );
+            if let Some(interfaces) = self.interfaces.clone() {
+                for nic in 0..interfaces.len() {
+                    let sent = interfaces.sent_bytes(nic);
+                    let rate =
+                        (sent - self.nic_sent[nic]) as u128 * 8 / 1000 / elapsed.as_millis().max(1);
+                    info!(
+                        "  {}: {} clients, {} Mbit/s",
+                        interfaces.addr(nic),
+                        self.nic_clients(nic).len(),
+                        rate
+                    );
+                    self.nic_sent[nic] = sent;
+                }
+            }
             self.written_elaps = writtenbytes;
+            self.sent_bytes = 0;
             let _ = std::io::stdout().flush();
             self.elaps_time = Instant::now();
             self.socket.packet_count = 0;
@@ -252,13 +850,7 @@ impl McastSender {
     #[allow(unused_assignments)]
     fn make_slice(&mut self, block_size: u32, slice_size: u32) -> &mut Slice {
         let mut slice_size = slice_size;
-        let mut remain = 0;
-        loop {
-            remain = self.data_fifo.read().unwrap().remain();
-            if remain > 0 || self.data_fifo.read().unwrap().is_closed() {
-                break;
-            }
-        }
+        let remain = self.data_fifo.read().unwrap().remain();
         if block_size * slice_size > remain as u32 {
             slice_size = remain as u32 / block_size;
         }
@@ -267,14 +859,28 @@ impl McastSender {
             bytes = remain as u32;
         }
         let slice_no = self.slices.len() as u32;
-        let slice = Slice::new(
+        let mut slice = Slice::new(
             slice_no,
             bytes,
             block_size,
             self.data_fifo.read().unwrap().slicebase(),
-            self.max_slices,
+            self.max_blocks(),
         );
         self.data_fifo.write().unwrap().assign(bytes);
+        if self.capabilities & CAP_CHECKSUM != 0 {
+            slice.checksum = slice.compute_checksum(&self.data_fifo.read().unwrap());
+        }
+        if self.capabilities & CAP_DIGEST != 0 {
+            if bytes > 0 {
+                // hashed where it lies, in the one or two parts of the FIFO
+                let data_fifo = self.data_fifo.read().unwrap();
+                let (first, second) = data_fifo.as_slices(slice.get_block_pos(0), bytes);
+                self.digest.update(first);
+                self.digest.update(second);
+            } else {
+                self.image_digest = Some(self.digest.clone().finalize().into());
+            }
+        }
         self.slices.insert(slice_no, slice);
         self.xmit_slice = slice_no as i32;
         let slice = self.slices.get_mut(&slice_no).unwrap();
@@ -282,7 +888,9 @@ impl McastSender {
         return slice;
     }
 
-    fn send_slice(&mut self, rxmit: bool) {
+    // Send the blocks of the slice, all or those still missing, and return
+    // how many.
+    fn send_slice(&mut self, rxmit: bool) -> u32 {
         let mut blocklist = Vec::new();
         if self.xmit_slice >= 0 {
             let xmit_slice = self.xmit_slice as u32;
@@ -298,9 +906,12 @@ impl McastSender {
             }
             slice.need_rxmit = false;
         }
-        for block_no in blocklist {
-            let _ = self.send_datablock(block_no);
+        self.send_blocks(&blocklist);
+        let sent = blocklist.len() as u32;
+        if !rxmit {
+            self.send_fecblocks();
         }
+        sent
     }
 
     fn do_retransmissions(&mut self) {
@@ -314,11 +925,28 @@ impl McastSender {
             );
         }
         self.retransmits += 1;
-        self.send_slice(true);
+        let resent = self.send_slice(true);
+        self.resent += resent;
+        if let Some(slice) = self.slices.get_mut(&(self.xmit_slice as u32)) {
+            let round = Round {
+                blocks: slice.blocks_in_slice,
+                resent,
+                last_good_block: slice.last_good_block,
+            };
+            slice.last_good_block = 0;
+            slice.next_round();
+            self.steer(|congestion, control| congestion.on_round(&round, control));
+        }
         let _ = self.send_reqack();
+        self.lastsendtime = Instant::now();
     }
 
     pub fn transfer_data(&mut self) -> bool {
+        // keep announcing the session for late joiners and passive receivers
+        if Instant::now() >= self.hello_time + HELLO_INTERVAL {
+            let _ = self.send_hello();
+            self.hello_time = Instant::now();
+        }
         if self.xmit_slice >= 0 {
             let xmit_slice = self.xmit_slice as u32;
             let slice = self.slices.get_mut(&xmit_slice).unwrap();
@@ -326,7 +954,7 @@ impl McastSender {
                 if slice.rxmit_id >= 10 {
                     return self.drop_client() > 0;
                 }
-                if self.lastsendtime.elapsed().as_millis() > 1000 {
+                if Instant::now() >= self.lastsendtime + REQACK_INTERVAL {
                     slice.rxmit_id += 1;
                     warn!(
                         "Waiting for response from clients {}/{}, sliceno {} rxmit_id {}",
@@ -347,12 +975,40 @@ impl McastSender {
             }
             self.data_fifo.write().unwrap().drain(slice.bytes as usize);
             slice.end_time = Instant::now();
+            let bytes = slice.bytes;
+            let done = SliceDone {
+                blocks: slice.blocks_in_slice,
+                resent: self.resent,
+                rounds: slice.rxmit_id,
+                elapsed: slice.end_time - slice.start_time,
+            };
+            self.resent = 0;
+            self.steer(|congestion, control| congestion.on_slice(&done, control));
             self.xmit_slice = -1;
+            if self.streaming && !self.finish_stream_slice(bytes) {
+                info!("All clients received the whole image");
+                return ENDLOOP;
+            }
             if getch(0) == Some('q') {
                 let _ = self.send_disconnect(self.socket.multicast_addr);
                 return ENDLOOP;
             }
         }
+        // the next slice waits for the end of the gap and for data, the
+        // deadline and the FIFO wake the loop for them
+        if self.gap_end().is_some() {
+            return RUNNING;
+        }
+        {
+            // under one lock, the reader may push the rest and close in between
+            let data_fifo = self.data_fifo.read().unwrap();
+            if data_fifo.remain() == 0 && !data_fifo.is_closed() {
+                return RUNNING;
+            }
+        }
+        if self.streaming {
+            self.admit_joiners();
+        }
 
         self.lastsendtime = Instant::now();
         // self.read();
@@ -386,9 +1042,11 @@ impl McastSender {
         return self.clientlist.len();
     }
 
-    fn remove_client(&mut self, clientaddr: SocketAddrV4) -> bool {
+    fn remove_client(&mut self, clientaddr: SocketAddr) -> bool {
+        self.joiners.remove(&clientaddr);
         if let Some(&(client_no, _, _)) = self.clientlist.get(&clientaddr) {
             self.clientlist.remove(&clientaddr);
+            self.stream.remove(&clientaddr);
             let _ = self.send_disconnect(clientaddr);
             if self.xmit_slice >= 0 {
                 let xmit_slice = self.xmit_slice as u32;
@@ -415,7 +1073,10 @@ impl McastSender {
 
     fn handle_ok(&mut self, msg: &MsgOk) -> bool {
         let clientaddr = self.socket.receivefrom.unwrap();
-        let slice = self.slices.get_mut(&msg.sliceno).unwrap();
+        let slice = match self.slices.get_mut(&msg.sliceno) {
+            Some(slice) => slice,
+            None => return true,
+        };
         if let Some(&(client_no, _, _)) = self.clientlist.get(&clientaddr) {
             slice.responce(client_no);
             slice.event(format!(
@@ -428,9 +1089,20 @@ impl McastSender {
         return true;
     }
 
-    fn handle_retransmit(&mut self, msg: &MsgRetransmit, map: Vec<u8>) -> bool {
+    fn handle_retransmit(&mut self, msg: &MsgRetransmit, map: &[u8]) -> bool {
         let clientaddr = self.socket.receivefrom.unwrap();
-        let slice = self.slices.get_mut(&msg.sliceno).unwrap();
+        let slice = match self.slices.get_mut(&msg.sliceno) {
+            Some(slice) => slice,
+            None => return true,
+        };
+        if map.len() != slice.retransmit.map.len().div_ceil(8) {
+            warn!(
+                "Ignore retransmit map of {} bytes from {}",
+                map.len(),
+                clientaddr
+            );
+            return true;
+        }
         warn!(
             "handle {:?}: {} / {} from {}",
             msg,
@@ -438,32 +1110,137 @@ impl McastSender {
             slice.rxmit_id,
             clientaddr.ip()
         );
-        slice.nr_answered += 1;
+        // answers to an earlier reqack miss the blocks retransmitted since
         if msg.rxmit < slice.rxmit_id {
             return true;
         }
-        let map = BitArray::from(map);
-        slice.retransmit.map |= map;
-        slice.need_rxmit = true;
+        if let Some(&(client_no, _, _)) = self.clientlist.get(&clientaddr) {
+            let map = BitArray::from(map.to_vec());
+            let blocks = slice.blocks_in_slice;
+            let missing = blocks - (map.count_ones() as u32).min(blocks);
+            slice.request_retransmit(client_no, map);
+            self.congestion.on_nack(client_no, missing, blocks);
+        }
         return true;
     }
 
+    // End of the pause after the last slice, while it lasts
+    fn gap_end(&self) -> Option<Instant> {
+        let last = (self.slices.len() as u32).checked_sub(1)?;
+        let end = self.slices.get(&last)?.end_time + self.slice_gap;
+        (self.xmit_slice < 0 && end > Instant::now()).then_some(end)
+    }
+
+    /// When transfer_data has to run again without a message: to repeat the
+    /// hello or the reqack, or at the end of the gap between slices.
+    pub fn deadline(&self) -> Instant {
+        let mut deadline = self.hello_time + HELLO_INTERVAL;
+        if self.xmit_slice >= 0 {
+            deadline = deadline.min(self.lastsendtime + REQACK_INTERVAL);
+        }
+        if let Some(end) = self.gap_end() {
+            deadline = deadline.min(end);
+        }
+        deadline
+    }
+
+    /// Handle the next message, waiting for it until the deadline, a key or
+    /// new data in the FIFO.
     pub fn dispatch_message(&mut self) -> Result<bool, &'static str> {
-        let mut buff: [u8; 2048] = [0; 2048];
-        match self.socket.recv_msg(&mut buff) {
-            Ok((msg, remain)) => match msg {
-                Message::CmdOk(m) => return Ok(self.handle_ok(&m)),
-                Message::CmdDisconnect(m) => return Ok(self.handle_disconnect(&m)),
-                Message::CmdRetransmit(m) => return Ok(self.handle_retransmit(&m, remain)),
-                _ => Err("Received an unexpected message."),
-            },
-            Err(ref err) if err.kind() == std::io::ErrorKind::TimedOut => {
+        match self.socket.wait_msg(Some(self.deadline())) {
+            Ok(received) => {
+                let result = match &received.msg {
+                    Message::CmdOk(m) => Ok(self.handle_ok(m)),
+                    Message::CmdDisconnect(m) => Ok(self.handle_disconnect(m)),
+                    Message::CmdRetransmit(m) => Ok(self.handle_retransmit(m, received.payload())),
+                    Message::CmdConnectReq(m) if self.streaming => {
+                        self.accept_client(m, true);
+                        Ok(true)
+                    }
+                    _ => Err("Received an unexpected message."),
+                };
+                self.socket.recycle(received);
+                result
+            }
+            Err(ref err)
+                if err.kind() == io::ErrorKind::TimedOut
+                    || err.kind() == io::ErrorKind::WouldBlock
+                    // forged, undecodable or of another session, dropped by the socket
+                    || err.kind() == io::ErrorKind::InvalidData =>
+            {
                 return Ok(true);
             }
             Err(_err) => return Err("Unexpected error!!"),
         }
     }
 
+    // Wait for the verification reports of the receivers after the last slice.
+    pub fn collect_reports(&mut self, timeout: Duration) {
+        if self.image_digest.is_none() || self.clientlist.is_empty() {
+            return;
+        }
+        let start = Instant::now();
+        while self.reports.len() < self.clientlist.len() && start.elapsed() < timeout {
+            if Instant::now() >= self.lastsendtime + REQACK_INTERVAL {
+                // receivers which missed the final reqack still wait for it
+                let _ = self.send_reqack();
+                self.lastsendtime = Instant::now();
+            }
+            let deadline = (self.lastsendtime + REQACK_INTERVAL).min(start + timeout);
+            if let Ok(received) = self.socket.wait_msg(Some(deadline)) {
+                if let Message::CmdReport(report) = self.socket.recycle(received) {
+                    let clientaddr = self.socket.receivefrom.unwrap();
+                    if self.clientlist.contains_key(&clientaddr) {
+                        self.reports.insert(clientaddr, report);
+                    }
+                }
+            }
+        }
+    }
+
+    // Rounds of retransmissions the session needed so far.
+    pub fn retransmits(&self) -> u32 {
+        self.retransmits
+    }
+
+    pub fn print_reports(&self) {
+        let image_digest = match self.image_digest {
+            Some(digest) => digest,
+            None => return,
+        };
+        info!("Image SHA-256: {}", hex(&image_digest));
+        let mut clients: Vec<(&SocketAddr, usize)> = self
+            .clientlist
+            .iter()
+            .map(|(clientaddr, client)| (clientaddr, client.0))
+            .collect();
+        clients.sort_by_key(|client| client.1);
+        println!("\n{:>4}  {:<40} {:>12}  result", "#", "client", "bytes");
+        let mut failed = 0;
+        for (clientaddr, client_no) in clients {
+            let (bytes, result) = match self.reports.get(clientaddr) {
+                Some(report) if report.digest == image_digest => (report.bytes.to_string(), "ok"),
+                Some(report) if report.status == REPORT_NO_DIGEST => {
+                    (report.bytes.to_string(), "no digest")
+                }
+                Some(report) => (report.bytes.to_string(), "MISMATCH"),
+                None => ("-".to_string(), "no report"),
+            };
+            if result != "ok" {
+                failed += 1;
+            }
+            println!(
+                "{client_no:>4}  {:<40} {bytes:>12}  {result}",
+                clientaddr.to_string()
+            );
+        }
+        if failed > 0 {
+            error!("{failed} of {} clients not verified", self.clientlist.len());
+        } else {
+            info!("All {} clients verified", self.clientlist.len());
+        }
+    }
+
     pub fn get_events(&mut self) -> Vec<(String, Instant, Instant)> {
         let mut events: Vec<(String, Instant, Instant)> = Vec::new();
         for (_, slice) in self.slices.iter_mut() {
@@ -476,3 +1253,42 @@ impl McastSender {
         events
     }
 }
+
+// Datagrams of the blocks with the given headers and payloads, the payloads
+// sealed into the buffers of sealed when the session is encrypted.
+fn gather<'a>(
+    cipher: Option<&Cipher>,
+    headers: &'a [[u8; BLOCK_HEADER_LEN]],
+    payloads: &[(&'a [u8], &'a [u8])],
+    sealed: &'a mut [Vec<u8>],
+) -> Vec<Gather<'a>> {
+    match cipher {
+        Some(cipher) => {
+            for ((out, header), (data, wrapped)) in sealed.iter_mut().zip(headers).zip(payloads) {
+                cipher.seal_into(header, &[data, wrapped], out);
+            }
+            headers
+                .iter()
+                .zip(sealed.iter())
+                .take(payloads.len())
+                .map(|(header, data)| Gather::new(&[header, data]))
+                .collect()
+        }
+        None => headers
+            .iter()
+            .zip(payloads)
+            .map(|(header, (data, wrapped))| Gather::new(&[header, data, wrapped]))
+            .collect(),
+    }
+}
+
+// random nonzero id which tells the messages of this sender from other
+// sessions on the same segment, 0 is left to udpcast
+fn new_session_id() -> u16 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(std::process::id());
+    (hasher.finish() % 0xffff) as u16 + 1
+}
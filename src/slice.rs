@@ -1,4 +1,5 @@
 use crate::bitarray::BitArray;
+use crate::datafifo::DataFIFO;
 use crate::packet::*;
 use crate::*;
 
@@ -16,39 +17,59 @@ pub struct Slice {
     pub retransmit: Retransmit,
     pub reqack: MsgReqAck,
     pub ready_set: BitArray,
+    // clients which acknowledged or asked for a retransmission in this round
+    answered: BitArray,
     pub rxmit_id: u32,
     pub need_rxmit: bool,
     pub nr_answered: u32,
     pub last_good_block: u32,
     pub start_time: Instant,
     pub end_time: Instant,
+    pub fec_stripes: u32,
+    pub fec_blocks: HashMap<u32, Vec<u8>>,
+    pub checksum: u32,
+    pub verified: bool,
     events: HashMap<String, Instant>,
 }
 
 impl Slice {
     pub fn new(slice_no: u32, bytes: u32, block_size: u32, base: usize, max_slice: u32) -> Self {
+        // decoding rejects hellos without a block size, this only keeps a
+        // receiver which never saw one alive
+        let blocks_in_slice = match block_size {
+            0 => 0,
+            block_size => bytes.div_ceil(block_size),
+        };
         Self {
             slice_no,
             bytes,
             block_size,
             base,
-            blocks_in_slice: ((bytes + block_size - 1) / block_size),
+            blocks_in_slice,
             blocks_transferred: 0,
             retransmit: Retransmit::new(slice_no, 0, max_slice),
             reqack: MsgReqAck::new(slice_no, bytes, 0),
             ready_set: BitArray::new(MAX_CLIENTS as usize),
+            answered: BitArray::new(MAX_CLIENTS as usize),
             rxmit_id: 0,
             need_rxmit: false,
             nr_answered: 0,
             last_good_block: 0,
             start_time: Instant::now(),
             end_time: Instant::now(),
+            fec_stripes: 0,
+            fec_blocks: HashMap::new(),
+            checksum: 0,
+            verified: false,
             events: HashMap::new(),
         }
     }
 
     pub fn update_block(&mut self, block_no: u32) -> bool {
-        if self.retransmit.map.get(block_no as usize) {
+        if block_no >= self.blocks_in_slice
+            || block_no as usize >= self.retransmit.map.len()
+            || self.retransmit.map.get(block_no as usize)
+        {
             return false;
         }
         self.retransmit.map.set(block_no as usize, true);
@@ -56,22 +77,78 @@ impl Slice {
         return true;
     }
 
-    pub fn get_block_pos(&mut self, block_no: u32) -> usize {
+    pub fn get_block_pos(&self, block_no: u32) -> usize {
         self.base + (self.block_size * block_no) as usize
     }
 
+    // FIFO position right after the slice
+    pub fn end(&self) -> usize {
+        self.base + self.bytes as usize
+    }
+
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    // the last block of a slice may be shorter than block_size
+    pub fn block_len(&self, block_no: u32) -> u32 {
+        self.block_size.min(self.bytes - self.block_size * block_no)
+    }
+
     pub fn is_completed(&self) -> bool {
         self.blocks_in_slice == self.blocks_transferred
     }
 
+    // CRC32C of the slice data in the FIFO
+    pub fn compute_checksum(&self, data_fifo: &DataFIFO) -> u32 {
+        let (data, wrapped) = data_fifo.peek(self.base, self.bytes as usize);
+        crc32c::crc32c_append(crc32c::crc32c(data), wrapped)
+    }
+
+    // forget the received blocks, the whole slice has to be sent again
+    pub fn reset_blocks(&mut self) {
+        self.retransmit.map = BitArray::new(self.retransmit.map.len());
+        self.blocks_transferred = 0;
+        self.fec_blocks.clear();
+    }
+
     pub fn responce(&mut self, client_no: usize) {
         self.ready_set.set(client_no, true);
+        if !self.answered.get(client_no) {
+            self.answered.set(client_no, true);
+            self.nr_answered += 1;
+        }
+    }
+
+    // A client asks for the blocks missing in received. Only blocks every
+    // client of the round got are skipped by the retransmission.
+    pub fn request_retransmit(&mut self, client_no: usize, received: BitArray) {
+        if self.answered.get(client_no) {
+            return;
+        }
+        self.answered.set(client_no, true);
         self.nr_answered += 1;
+        if self.need_rxmit {
+            self.retransmit.map &= received;
+        } else {
+            self.retransmit.map = BitArray::new(self.retransmit.map.len());
+            self.retransmit.map |= received;
+            self.need_rxmit = true;
+        }
+    }
+
+    // After a retransmission only the clients with the whole slice have answered.
+    pub fn next_round(&mut self) {
+        self.answered = self.ready_set.clone();
+        self.nr_answered = self.ready_set.count_ones() as u32;
     }
 
     pub fn remove_client(&mut self, client_no: usize) {
         self.ready_set.set(client_no, false);
-        self.nr_answered -= 1;
+        if self.answered.get(client_no) {
+            self.answered.set(client_no, false);
+            self.nr_answered -= 1;
+        }
     }
 
     pub fn event(&mut self, id: String) {
@@ -87,6 +164,17 @@ impl Slice {
     }
 }
 
+// add a byte range to a sorted list of ranges, merging it with its predecessor
+pub fn merge_range(ranges: &mut Vec<(usize, usize)>, pos: usize, len: usize) {
+    if let Some(last) = ranges.last_mut() {
+        if last.0 + last.1 == pos {
+            last.1 += len;
+            return;
+        }
+    }
+    ranges.push((pos, len));
+}
+
 impl fmt::Debug for Slice {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         fmt::Display::fmt(self, fmt)
@@ -0,0 +1,370 @@
+use log::{info, warn};
+use mio::{Registry, Token, Waker};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::event_loop::{Registration, SharedWaker};
+use crate::multicast::{MultiCast, NetConfig};
+use crate::transport::{Gather, Transport};
+
+// same read timeout as the UDP sockets
+const READ_TIMEOUT: Duration = Duration::from_millis(50);
+// a receiver which takes no data for this long is cut off
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+// writes queued for a receiver, more are dropped like datagrams which do not
+// fit into the socket buffer
+const QUEUE_LEN: usize = 256;
+
+type Datagram = (Vec<u8>, SocketAddr);
+
+// Datagrams go over the stream with a big endian u16 length in front.
+fn frame(packet: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+    out.extend_from_slice(packet);
+}
+
+// Queue the datagrams of a stream until it is closed.
+fn read_frames(
+    mut stream: TcpStream,
+    from: SocketAddr,
+    queue: Sender<Datagram>,
+    waker: SharedWaker,
+) {
+    let mut len = [0u8; 2];
+    while stream.read_exact(&mut len).is_ok() {
+        let mut packet = vec![0; u16::from_be_bytes(len) as usize];
+        if stream.read_exact(&mut packet).is_err() || queue.send((packet, from)).is_err() {
+            break;
+        }
+        waker.wake();
+    }
+}
+
+// Write what is queued for a stream until it fails or the queue is dropped.
+// A failed stream is shut down, which ends its reader as well.
+fn write_frames(mut stream: TcpStream, peer: SocketAddr, queue: Receiver<Arc<Vec<u8>>>) {
+    for data in queue {
+        if stream.write_all(&data).is_err() {
+            warn!("Lost the connection to {peer}");
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+            break;
+        }
+    }
+}
+
+fn receive(
+    incoming: &Receiver<Datagram>,
+    nonblocking: bool,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr)> {
+    let (data, from) = if nonblocking {
+        incoming.try_recv().map_err(|err| match err {
+            TryRecvError::Empty => io::Error::from(io::ErrorKind::WouldBlock),
+            TryRecvError::Disconnected => io::Error::from(io::ErrorKind::NotConnected),
+        })?
+    } else {
+        incoming
+            .recv_timeout(READ_TIMEOUT)
+            .map_err(|err| match err {
+                RecvTimeoutError::Timeout => io::Error::from(io::ErrorKind::TimedOut),
+                RecvTimeoutError::Disconnected => io::Error::from(io::ErrorKind::NotConnected),
+            })?
+    };
+    let size = data.len().min(buf.len());
+    buf[..size].copy_from_slice(&data[..size]);
+    Ok((size, from))
+}
+
+// Stream of a receiver, written by a thread of its own.
+#[derive(Debug)]
+struct Peer {
+    stream: TcpStream,
+    queue: SyncSender<Arc<Vec<u8>>>,
+}
+
+impl Peer {
+    // Whole frames are dropped when the queue is full, the receiver asks for
+    // them again. A peer whose writer failed is removed by its reader.
+    fn send(&self, data: &Arc<Vec<u8>>) {
+        let _ = self.queue.try_send(Arc::clone(data));
+    }
+}
+
+/// Sender end of a session over TCP, for networks without multicast. Every
+/// receiver connects with a stream of its own. Datagrams to the address of a
+/// receiver go to its stream, all others, the data of the multicast group
+/// and the hello, to every stream.
+#[derive(Debug)]
+pub struct TcpServer {
+    addr: SocketAddr,
+    streams: Arc<Mutex<HashMap<SocketAddr, Peer>>>,
+    incoming: Receiver<Datagram>,
+    closed: Arc<AtomicBool>,
+    nonblocking: AtomicBool,
+    waker: SharedWaker,
+}
+
+impl TcpServer {
+    pub fn bind(addr: SocketAddr) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let streams = Arc::new(Mutex::new(HashMap::new()));
+        let closed = Arc::new(AtomicBool::new(false));
+        let (queue, incoming) = mpsc::channel();
+        let waker = SharedWaker::default();
+        {
+            let streams = Arc::clone(&streams);
+            let closed = Arc::clone(&closed);
+            let waker = waker.clone();
+            thread::spawn(move || accept(listener, streams, queue, closed, waker));
+        }
+        Ok(Self {
+            addr,
+            streams,
+            incoming,
+            closed,
+            nonblocking: AtomicBool::new(false),
+            waker,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // Queue the framed datagrams for the stream of addr, or for all of them.
+    // A slow receiver holds up no other, one which takes nothing for
+    // WRITE_TIMEOUT is disconnected like a lost one.
+    fn write(&self, data: Vec<u8>, addr: SocketAddr) {
+        let data = Arc::new(data);
+        let streams = self.streams.lock().unwrap();
+        match streams.get(&addr) {
+            Some(peer) => peer.send(&data),
+            None => streams.values().for_each(|peer| peer.send(&data)),
+        }
+    }
+}
+
+fn accept(
+    listener: TcpListener,
+    streams: Arc<Mutex<HashMap<SocketAddr, Peer>>>,
+    queue: Sender<Datagram>,
+    closed: Arc<AtomicBool>,
+    waker: SharedWaker,
+) {
+    loop {
+        let accepted = listener.accept();
+        // the server connects once more when it is dropped
+        if closed.load(Ordering::Relaxed) {
+            break;
+        }
+        let (stream, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!("Can't accept a connection: {err}");
+                continue;
+            }
+        };
+        let (reader, writer) = match (stream.try_clone(), stream.try_clone()) {
+            (Ok(reader), Ok(writer)) => (reader, writer),
+            _ => continue,
+        };
+        let _ = stream.set_nodelay(true);
+        let _ = stream.set_write_timeout(Some(WRITE_TIMEOUT));
+        info!("Receiver connected over TCP from {peer}");
+        let (frames, queued) = mpsc::sync_channel(QUEUE_LEN);
+        thread::spawn(move || write_frames(writer, peer, queued));
+        let frames = Peer {
+            stream,
+            queue: frames,
+        };
+        streams.lock().unwrap().insert(peer, frames);
+        let streams = Arc::clone(&streams);
+        let queue = queue.clone();
+        let waker = waker.clone();
+        thread::spawn(move || {
+            read_frames(reader, peer, queue, waker);
+            streams.lock().unwrap().remove(&peer);
+        });
+    }
+}
+
+impl Transport for TcpServer {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let mut data = Vec::with_capacity(buf.len() + 2);
+        frame(buf, &mut data);
+        self.write(data, addr);
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        receive(
+            &self.incoming,
+            self.nonblocking.load(Ordering::Relaxed),
+            buf,
+        )
+    }
+
+    // one write for the whole batch
+    fn send_batch(&self, packets: &[Gather], addr: SocketAddr) -> io::Result<usize> {
+        let mut data = Vec::with_capacity(packets.iter().map(|p| p.len() + 2).sum());
+        for packet in packets {
+            data.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+            packet.copy_to(&mut data);
+        }
+        self.write(data, addr);
+        Ok(packets.len())
+    }
+
+    fn join_multicast(&self, _group: IpAddr, _myip: IpAddr, _ifindex: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_broadcast(&self, _broadcast: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_multicast_ttl(&self, _ttl: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking.store(nonblocking, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn register(
+        &self,
+        _registry: &Registry,
+        _token: Token,
+        waker: &Arc<Waker>,
+    ) -> io::Result<Registration> {
+        self.waker.set(waker);
+        Ok(Box::new(()))
+    }
+}
+
+impl Drop for TcpServer {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::Relaxed);
+        // ends the blocking accept of the accepting thread
+        let mut listener = self.addr;
+        if listener.ip().is_unspecified() {
+            listener.set_ip(match listener {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        let _ = TcpStream::connect_timeout(&listener, READ_TIMEOUT);
+        for peer in self.streams.lock().unwrap().values() {
+            let _ = peer.stream.shutdown(std::net::Shutdown::Both);
+        }
+    }
+}
+
+/// Receiver end of a session over TCP, everything it sends goes to the
+/// sender it is connected to.
+#[derive(Debug)]
+pub struct TcpClient {
+    stream: TcpStream,
+    incoming: Receiver<Datagram>,
+    nonblocking: AtomicBool,
+    waker: SharedWaker,
+}
+
+impl TcpClient {
+    pub fn connect(addr: SocketAddr) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        let reader = stream.try_clone()?;
+        let (queue, incoming) = mpsc::channel();
+        let waker = SharedWaker::default();
+        {
+            let waker = waker.clone();
+            thread::spawn(move || read_frames(reader, addr, queue, waker));
+        }
+        Ok(Self {
+            stream,
+            incoming,
+            nonblocking: AtomicBool::new(false),
+            waker,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.local_addr()
+    }
+}
+
+impl Transport for TcpClient {
+    fn send_to(&self, buf: &[u8], _addr: SocketAddr) -> io::Result<usize> {
+        let mut data = Vec::with_capacity(buf.len() + 2);
+        frame(buf, &mut data);
+        (&self.stream).write_all(&data)?;
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        receive(
+            &self.incoming,
+            self.nonblocking.load(Ordering::Relaxed),
+            buf,
+        )
+    }
+
+    fn join_multicast(&self, _group: IpAddr, _myip: IpAddr, _ifindex: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_broadcast(&self, _broadcast: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_multicast_ttl(&self, _ttl: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking.store(nonblocking, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn register(
+        &self,
+        _registry: &Registry,
+        _token: Token,
+        waker: &Arc<Waker>,
+    ) -> io::Result<Registration> {
+        self.waker.set(waker);
+        Ok(Box::new(()))
+    }
+}
+
+impl Drop for TcpClient {
+    fn drop(&mut self) {
+        let _ = self.stream.shutdown(std::net::Shutdown::Both);
+    }
+}
+
+/// Sender which listens for receivers on the control port of the interface.
+pub fn sender(config: &NetConfig) -> io::Result<MultiCast> {
+    let myip = config.nic.ipv4()?.addr();
+    let server = TcpServer::bind(SocketAddrV4::new(myip, config.control_port).into())?;
+    let addr = server.local_addr();
+    // hello and data reach all receivers alike
+    Ok(MultiCast::new(Box::new(server), 0, addr, addr, addr))
+}
+
+/// Receiver connected to the sender at the given address and control port.
+pub fn receiver(config: &NetConfig, sender: IpAddr) -> io::Result<MultiCast> {
+    let addr = SocketAddr::new(sender, config.control_port);
+    let client = TcpClient::connect(addr)?;
+    let myip = client.local_addr()?;
+    Ok(MultiCast::new(Box::new(client), 0, myip, addr, addr))
+}
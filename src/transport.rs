@@ -0,0 +1,442 @@
+use mio::{Interest, Registry, Token, Waker};
+#[cfg(not(target_os = "linux"))]
+use socket2::SockAddr;
+use socket2::SockRef;
+use std::fmt;
+use std::io;
+#[cfg(not(target_os = "linux"))]
+use std::io::IoSlice;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::Instant;
+
+#[cfg(windows)]
+use crate::event_loop::Registered;
+use crate::event_loop::Registration;
+
+// parts of a gathered datagram: header, data in up to two pieces, tag
+const MAX_PARTS: usize = 4;
+
+/// Datagram sent from several buffers without copying them together: the
+/// header of a block, its data borrowed from the FIFO, the tag.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Gather<'a> {
+    parts: [&'a [u8]; MAX_PARTS],
+    count: usize,
+}
+
+impl<'a> Gather<'a> {
+    pub fn new(parts: &[&'a [u8]]) -> Self {
+        let mut gather = Self::default();
+        for part in parts {
+            gather.push(part);
+        }
+        gather
+    }
+
+    /// Append a part, empty ones are left out.
+    pub fn push(&mut self, part: &'a [u8]) {
+        if !part.is_empty() {
+            assert!(self.count < MAX_PARTS, "too many parts in a datagram");
+            self.parts[self.count] = part;
+            self.count += 1;
+        }
+    }
+
+    pub fn parts(&self) -> &[&'a [u8]] {
+        &self.parts[..self.count]
+    }
+
+    pub fn len(&self) -> usize {
+        self.parts().iter().map(|part| part.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Append the whole datagram to out.
+    pub fn copy_to(&self, out: &mut Vec<u8>) {
+        for part in self.parts() {
+            out.extend_from_slice(part);
+        }
+    }
+}
+
+/// Datagram socket under a `MultiCast`: a UDP socket or an in-process network
+/// which runs a whole session without touching the real one.
+pub trait Transport: fmt::Debug + Send {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize>;
+
+    /// Receive a datagram, TimedOut when none arrived within the read timeout
+    /// and WouldBlock when none is pending on a nonblocking transport.
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+
+    /// Send several datagrams to addr, returns how many of them were sent.
+    fn send_batch(&self, packets: &[Gather], addr: SocketAddr) -> io::Result<usize> {
+        let mut buf = Vec::with_capacity(crate::UDP_PACK_SIZE);
+        for packet in packets {
+            buf.clear();
+            packet.copy_to(&mut buf);
+            self.send_to(&buf, addr)?;
+        }
+        Ok(packets.len())
+    }
+
+    /// Wait like recv_from for a datagram, then take the ones already pending
+    /// as well, up to one per buffer. Returns size and source of each.
+    fn recv_batch(&self, bufs: &mut [Vec<u8>]) -> io::Result<Vec<(usize, SocketAddr)>> {
+        Ok(vec![self.recv_from(&mut bufs[0])?])
+    }
+
+    /// Receive the datagrams sent to group on the interface with address
+    /// myip, the IPv6 interface is selected by its index instead.
+    fn join_multicast(&self, group: IpAddr, myip: IpAddr, ifindex: u32) -> io::Result<()>;
+
+    fn set_broadcast(&self, broadcast: bool) -> io::Result<()>;
+
+    fn set_multicast_ttl(&self, ttl: u32) -> io::Result<()>;
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+
+    /// Largest datagram, IP header included, which reaches addr without being
+    /// fragmented as far as the system knows: the MTU of the route, lowered by
+    /// what path MTU discovery learned since.
+    fn path_mtu(&self, _addr: SocketAddr) -> io::Result<usize> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Report readiness to the poll of an event loop: the sockets register
+    /// with token, in-process transports call waker when a datagram arrives.
+    /// Unsupported leaves the loop to wait with the read timeout.
+    fn register(
+        &self,
+        _registry: &Registry,
+        _token: Token,
+        _waker: &Arc<Waker>,
+    ) -> io::Result<Registration> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// When the next datagram held back by the transport is due, the event
+    /// loop waits no longer than that.
+    fn next_due(&self) -> Option<Instant> {
+        None
+    }
+}
+
+impl Transport for UdpSocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn send_batch(&self, packets: &[Gather], addr: SocketAddr) -> io::Result<usize> {
+        mmsg::send(self, packets, addr)
+    }
+
+    // one system call per datagram, still without copying its parts together
+    #[cfg(not(target_os = "linux"))]
+    fn send_batch(&self, packets: &[Gather], addr: SocketAddr) -> io::Result<usize> {
+        let socket = SockRef::from(self);
+        let addr = SockAddr::from(addr);
+        for packet in packets {
+            let mut slices = [IoSlice::new(&[]); MAX_PARTS];
+            for (slice, part) in slices.iter_mut().zip(packet.parts()) {
+                *slice = IoSlice::new(part);
+            }
+            socket.send_to_vectored(&slices[..packet.parts().len()], &addr)?;
+        }
+        Ok(packets.len())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn recv_batch(&self, bufs: &mut [Vec<u8>]) -> io::Result<Vec<(usize, SocketAddr)>> {
+        mmsg::recv(self, bufs)
+    }
+
+    fn join_multicast(&self, group: IpAddr, myip: IpAddr, ifindex: u32) -> io::Result<()> {
+        match (group, myip) {
+            (IpAddr::V4(group), IpAddr::V4(myip)) => self.join_multicast_v4(&group, &myip),
+            (IpAddr::V6(group), IpAddr::V6(_)) => self.join_multicast_v6(&group, ifindex),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "multicast group and interface address family differ",
+            )),
+        }
+    }
+
+    fn set_broadcast(&self, broadcast: bool) -> io::Result<()> {
+        UdpSocket::set_broadcast(self, broadcast)
+    }
+
+    fn set_multicast_ttl(&self, ttl: u32) -> io::Result<()> {
+        match self.local_addr()? {
+            SocketAddr::V4(_) => self.set_multicast_ttl_v4(ttl),
+            SocketAddr::V6(_) => SockRef::from(self).set_multicast_hops_v6(ttl),
+        }
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UdpSocket::set_nonblocking(self, nonblocking)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn path_mtu(&self, addr: SocketAddr) -> io::Result<usize> {
+        mtu::path_mtu(self, addr)
+    }
+
+    #[cfg(unix)]
+    fn register(
+        &self,
+        registry: &Registry,
+        token: Token,
+        _waker: &Arc<Waker>,
+    ) -> io::Result<Registration> {
+        use mio::unix::SourceFd;
+        use std::os::unix::io::AsRawFd;
+
+        let fd = self.as_raw_fd();
+        registry.register(
+            &mut SourceFd(&fd),
+            token,
+            Interest::READABLE | Interest::WRITABLE,
+        )?;
+        Ok(Box::new(()))
+    }
+
+    // mio polls sockets of its own only, a duplicate of this one stands in
+    #[cfg(windows)]
+    fn register(
+        &self,
+        registry: &Registry,
+        token: Token,
+        _waker: &Arc<Waker>,
+    ) -> io::Result<Registration> {
+        let mut socket = mio::net::UdpSocket::from_std(self.try_clone()?);
+        registry.register(&mut socket, token, Interest::READABLE | Interest::WRITABLE)?;
+        Ok(Box::new(Duplicate { socket, token }))
+    }
+}
+
+// The mio socket registered for a UDP socket whose I/O goes through the
+// original, armed again before every wait.
+#[cfg(windows)]
+#[derive(Debug)]
+struct Duplicate {
+    socket: mio::net::UdpSocket,
+    token: Token,
+}
+
+#[cfg(windows)]
+impl Registered for Duplicate {
+    fn rearm(&mut self, registry: &Registry) -> io::Result<()> {
+        registry.reregister(
+            &mut self.socket,
+            self.token,
+            Interest::READABLE | Interest::WRITABLE,
+        )
+    }
+}
+
+// The kernel tells the path MTU of connected sockets only, a socket of its
+// own is connected to the destination for the question.
+#[cfg(target_os = "linux")]
+mod mtu {
+    use libc::{c_int, c_void, socklen_t};
+    use socket2::{Domain, SockAddr, SockRef, Socket, Type};
+    use std::io;
+    use std::mem;
+    use std::net::{SocketAddr, UdpSocket};
+    use std::os::unix::io::AsRawFd;
+
+    fn setsockopt(socket: &Socket, level: c_int, name: c_int, value: c_int) -> io::Result<()> {
+        // SAFETY: value is a c_int which outlives the call
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &value as *const c_int as *const c_void,
+                mem::size_of::<c_int>() as socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn getsockopt(socket: &Socket, level: c_int, name: c_int) -> io::Result<c_int> {
+        let mut value: c_int = 0;
+        let mut len = mem::size_of::<c_int>() as socklen_t;
+        // SAFETY: value and len outlive the call, len holds the size of value
+        let result = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &mut value as *mut c_int as *mut c_void,
+                &mut len,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(value)
+    }
+
+    pub fn path_mtu(socket: &UdpSocket, addr: SocketAddr) -> io::Result<usize> {
+        let local = socket.local_addr()?;
+        let probe = Socket::new(Domain::for_address(addr), Type::DGRAM, None)?;
+        // the same interface as the socket, for multicast groups too
+        if !local.ip().is_unspecified() {
+            probe.bind(&SockAddr::from(SocketAddr::new(local.ip(), 0)))?;
+        }
+        let (level, mtu) = match addr {
+            SocketAddr::V4(_) => {
+                probe.set_multicast_if_v4(&SockRef::from(socket).multicast_if_v4()?)?;
+                setsockopt(
+                    &probe,
+                    libc::IPPROTO_IP,
+                    libc::IP_MTU_DISCOVER,
+                    libc::IP_PMTUDISC_DO,
+                )?;
+                (libc::IPPROTO_IP, libc::IP_MTU)
+            }
+            SocketAddr::V6(_) => {
+                probe.set_multicast_if_v6(SockRef::from(socket).multicast_if_v6()?)?;
+                setsockopt(
+                    &probe,
+                    libc::IPPROTO_IPV6,
+                    libc::IPV6_MTU_DISCOVER,
+                    libc::IPV6_PMTUDISC_DO,
+                )?;
+                (libc::IPPROTO_IPV6, libc::IPV6_MTU)
+            }
+        };
+        probe.connect(&SockAddr::from(addr))?;
+        Ok(getsockopt(&probe, level, mtu)? as usize)
+    }
+}
+
+// One system call for a batch of datagrams
+#[cfg(target_os = "linux")]
+mod mmsg {
+    use super::Gather;
+    use libc::{c_uint, c_void, iovec, mmsghdr, sockaddr_storage, socklen_t};
+    use socket2::SockAddr;
+    use std::io;
+    use std::mem;
+    use std::net::{SocketAddr, UdpSocket};
+    use std::os::unix::io::AsRawFd;
+    use std::ptr;
+
+    fn header(iov: *mut iovec, iovlen: usize, name: *mut c_void, namelen: socklen_t) -> mmsghdr {
+        // SAFETY: all fields of msghdr are plain integers and pointers
+        let mut msg: mmsghdr = unsafe { mem::zeroed() };
+        msg.msg_hdr.msg_name = name;
+        msg.msg_hdr.msg_namelen = namelen;
+        msg.msg_hdr.msg_iov = iov;
+        msg.msg_hdr.msg_iovlen = iovlen as _;
+        msg
+    }
+
+    pub fn send(socket: &UdpSocket, packets: &[Gather], addr: SocketAddr) -> io::Result<usize> {
+        if packets.is_empty() {
+            return Ok(0);
+        }
+        let addr = SockAddr::from(addr);
+        // the parts of all datagrams, each header points at its own
+        let mut iovecs: Vec<iovec> = packets
+            .iter()
+            .flat_map(|packet| packet.parts())
+            .map(|part| iovec {
+                iov_base: part.as_ptr() as *mut c_void,
+                iov_len: part.len(),
+            })
+            .collect();
+        let mut first = 0;
+        let mut msgs: Vec<mmsghdr> = packets
+            .iter()
+            .map(|packet| {
+                let count = packet.parts().len();
+                // SAFETY: first + count stays within iovecs, which holds the
+                // parts of every packet in order
+                let iov = unsafe { iovecs.as_mut_ptr().add(first) };
+                first += count;
+                header(iov, count, addr.as_ptr() as *mut c_void, addr.len())
+            })
+            .collect();
+        // SAFETY: the headers point into packets, iovecs and addr, which
+        // outlive the call, sendmmsg only reads them
+        let sent = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                msgs.len() as c_uint,
+                0,
+            )
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(sent as usize)
+    }
+
+    pub fn recv(socket: &UdpSocket, bufs: &mut [Vec<u8>]) -> io::Result<Vec<(usize, SocketAddr)>> {
+        // SAFETY: an all zero sockaddr_storage is a valid unspecified address
+        let mut names: Vec<sockaddr_storage> = vec![unsafe { mem::zeroed() }; bufs.len()];
+        let mut iovecs: Vec<iovec> = bufs
+            .iter_mut()
+            .map(|buf| iovec {
+                iov_base: buf.as_mut_ptr() as *mut c_void,
+                iov_len: buf.len(),
+            })
+            .collect();
+        let mut msgs: Vec<mmsghdr> = iovecs
+            .iter_mut()
+            .zip(names.iter_mut())
+            .map(|(iov, name)| {
+                header(
+                    iov,
+                    1,
+                    name as *mut sockaddr_storage as *mut c_void,
+                    mem::size_of::<sockaddr_storage>() as socklen_t,
+                )
+            })
+            .collect();
+        // SAFETY: the headers point into bufs, iovecs and names, which
+        // outlive the call. MSG_WAITFORONE blocks for the first datagram only,
+        // up to the read timeout of the socket.
+        let received = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                msgs.len() as c_uint,
+                libc::MSG_WAITFORONE,
+                ptr::null_mut(),
+            )
+        };
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(msgs[..received as usize]
+            .iter()
+            .zip(names)
+            .map(|(msg, name)| {
+                // SAFETY: recvmmsg filled in name and its length
+                let addr = unsafe { SockAddr::new(name, msg.msg_hdr.msg_namelen) };
+                // one entry per buffer, a source which is no IP address is unspecified
+                let addr = addr
+                    .as_socket()
+                    .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+                (msg.msg_len as usize, addr)
+            })
+            .collect())
+    }
+}
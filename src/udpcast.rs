@@ -0,0 +1,91 @@
+//! Wire compatibility with udpcast's udp-sender and udp-receiver.
+//!
+//! img_caster uses the opcodes and big endian message layouts of udpcast. The
+//! differences which matter on the wire are:
+//! - udpcast reserves room for 1024 clients in the reqack ready set and for
+//!   1024 blocks in the retransmit map.
+//! - the udpcast connect reply has no max_slices field.
+//! - udp-sender announces itself with the old CMD_HELLO (0x0500) opcode and
+//!   pads the hello to the size of its C struct.
+//! - udpcast FEC blocks use another code, so FEC and the img_caster
+//!   extensions (streaming join) are not used with udpcast peers.
+
+use crate::packet::*;
+use crate::*;
+
+use endian_codec::{DecodeBE, EncodeBE, PackedSize};
+
+pub const UDPCAST_MAX_CLIENTS: u32 = 1024;
+pub const UDPCAST_MAX_SLICE_SIZE: u32 = 1024;
+pub const UDPCAST_CAPABILITIES: u32 = CAP_NEW_GEN | CAP_BIG_ENDIAN;
+// sizeof(struct hello) is 28, the message itself 26 bytes
+pub const UDPCAST_HELLO_PADDING: usize = 2;
+// opcode of the hello sent by udp-sender
+pub const UDPCAST_CMD_HELLO: u16 = 0x0500;
+
+/// struct connectReply of udpcast
+#[derive(Debug, PartialEq, Eq, PackedSize, EncodeBE, DecodeBE)]
+pub struct ConnectReply {
+    reserved: u16,
+    pub clnr: u32,
+    pub blocksize: u32,
+    pub capabilities: u32,
+    pub mcastaddr: [u8; 16],
+}
+
+impl From<ConnectReply> for MsgConnectReply {
+    fn from(reply: ConnectReply) -> Self {
+        MsgConnectReply::new(
+            reply.clnr,
+            reply.blocksize,
+            // the udpcast FEC blocks can not be decoded here
+            reply.capabilities & !(CAP_FEC | CAP_STREAMING),
+            UDPCAST_MAX_SLICE_SIZE,
+            &decode_mcastaddr(&reply.mcastaddr),
+        )
+    }
+}
+
+/// Encode a connect reply in the udpcast layout.
+pub fn encode_connectreply(msg: &MsgConnectReply) -> Vec<u8> {
+    let reply = ConnectReply {
+        reserved: 0,
+        clnr: msg.clnr,
+        blocksize: msg.blocksize,
+        capabilities: msg.capabilities,
+        mcastaddr: msg.mcastaddr,
+    };
+    let mut buf = vec![0; 2 + ConnectReply::PACKED_LEN];
+    buf[0..2].copy_from_slice(&7u16.to_be_bytes());
+    reply.encode_as_be_bytes(&mut buf[2..]);
+    buf
+}
+
+/// The C struct padding udp-sender appends to its hello.
+pub fn hello_padding(payload: &[u8]) -> &[u8] {
+    if payload.len() == UDPCAST_HELLO_PADDING && payload.iter().all(|&b| b == 0) {
+        &payload[UDPCAST_HELLO_PADDING..]
+    } else {
+        payload
+    }
+}
+
+/// Length of a ready set or retransmit map for `bits` entries.
+pub fn map_len(bits: u32) -> usize {
+    (bits / BITS_PER_CHAR) as usize
+}
+
+/// Encode a message the way a sender or receiver in udpcast mode puts it on
+/// the wire.
+pub fn encode(msg: &Message, payload: &[u8]) -> Vec<u8> {
+    let mut buf = match msg {
+        Message::CmdConnectReply(reply) => encode_connectreply(reply),
+        _ => msg.encode(),
+    };
+    buf.extend_from_slice(payload);
+    // only the hello of udp-sender, which has no session info, is padded
+    if matches!(msg, Message::CmdHello(_)) && payload.is_empty() {
+        buf.resize(buf.len() + UDPCAST_HELLO_PADDING, 0);
+    }
+    buf
+}
@@ -0,0 +1,106 @@
+// Whatever the tags take from a block, a sender announces no block size
+// receivers reject.
+
+use img_caster::auth::{Psk, TAG_LEN};
+use img_caster::crypt::{Secret, AEAD_TAG_LEN};
+use img_caster::datafifo::DataFIFO;
+use img_caster::loopback::Loopback;
+use img_caster::packet::Message;
+use img_caster::sender::McastSender;
+use img_caster::transport::Transport;
+use img_caster::*;
+use std::net::SocketAddrV4;
+use std::sync::{Arc, RwLock};
+
+fn psk() -> Psk {
+    Psk::new(b"blocksize")
+}
+
+// The block size in the hello of a sender set up by configure.
+fn announced(
+    configure: impl FnOnce(&mut McastSender) -> Result<(), String>,
+) -> Result<u32, String> {
+    let network = Loopback::new();
+    let receiver = network
+        .bind(SocketAddrV4::new(Loopback::addr(10), PORTBASE).into())
+        .unwrap();
+    let data_fifo = Arc::new(RwLock::new(DataFIFO::new(MAX_BUFFER_SIZE)));
+    let mut sender = McastSender::new(network.sender(1).unwrap(), 1, MAX_SLICE_SIZE, data_fifo);
+    configure(&mut sender)?;
+    sender.send_hello().unwrap();
+    let mut buf = [0; 2048];
+    let (size, _) = receiver.recv_from(&mut buf).unwrap();
+    // the hello is signed with the key, if there is one
+    let packet = psk().verify(&buf[..size]).unwrap_or(&buf[..size]);
+    match Message::decode(packet) {
+        Ok((Message::CmdHello(hello), _)) => Ok(hello.blocksize as u32),
+        other => panic!("{other:?}"),
+    }
+}
+
+// --blocksize 256 --psk-file, in either order
+#[test]
+fn psk_min_blocksize() {
+    assert!(announced(|sender| {
+        sender.set_blocksize(MIN_BLOCK_SIZE)?;
+        sender.set_psk(psk())
+    })
+    .is_err());
+    assert!(announced(|sender| {
+        sender.set_psk(psk())?;
+        sender.set_blocksize(MIN_BLOCK_SIZE)
+    })
+    .is_err());
+    let blocksize = MIN_BLOCK_SIZE + TAG_LEN as u32;
+    assert_eq!(
+        announced(|sender| {
+            sender.set_blocksize(blocksize)?;
+            sender.set_psk(psk())
+        }),
+        Ok(MIN_BLOCK_SIZE)
+    );
+}
+
+// --blocksize 256 --passphrase, in either order
+#[test]
+fn secret_min_blocksize() {
+    let secret = Secret::new(b"blocksize");
+    assert!(announced(|sender| {
+        sender.set_blocksize(MIN_BLOCK_SIZE)?;
+        sender.set_secret(&secret)
+    })
+    .is_err());
+    assert!(announced(|sender| {
+        sender.set_secret(&secret)?;
+        sender.set_blocksize(MIN_BLOCK_SIZE)
+    })
+    .is_err());
+    let blocksize = MIN_BLOCK_SIZE + AEAD_TAG_LEN as u32;
+    assert_eq!(
+        announced(|sender| {
+            sender.set_blocksize(blocksize)?;
+            sender.set_secret(&secret)
+        }),
+        Ok(MIN_BLOCK_SIZE)
+    );
+}
+
+#[test]
+fn psk_and_secret_min_blocksize() {
+    let secret = Secret::new(b"blocksize");
+    assert!(announced(|sender| {
+        sender.set_blocksize(MIN_BLOCK_SIZE + TAG_LEN as u32)?;
+        sender.set_psk(psk())?;
+        sender.set_secret(&secret)
+    })
+    .is_err());
+    let blocksize = MIN_BLOCK_SIZE + (TAG_LEN + AEAD_TAG_LEN) as u32;
+    assert_eq!(
+        announced(|sender| {
+            sender.set_psk(psk())?;
+            sender.set_secret(&secret)?;
+            sender.set_blocksize(blocksize)
+        }),
+        Ok(MIN_BLOCK_SIZE)
+    );
+}
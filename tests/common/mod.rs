@@ -0,0 +1,168 @@
+// Sessions of a sender and its receivers over a loopback network, shared by
+// the integration tests.
+#![allow(dead_code)]
+
+use img_caster::datafifo::DataFIFO;
+use img_caster::impair::Impairment;
+use img_caster::loopback::Loopback;
+use img_caster::multicast::MultiCast;
+use img_caster::receiver_a::McastReceiver;
+use img_caster::sender::McastSender;
+use img_caster::*;
+use log::{error, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Barrier, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Outcome of a session on a loopback network.
+#[derive(Debug)]
+pub struct SessionResult {
+    /// what each receiver wrote
+    pub images: Vec<Vec<u8>>,
+    /// datagrams each receiver lost, got twice and got out of order
+    pub impaired: Vec<(usize, usize, usize)>,
+    pub slices: usize,
+    /// retransmission rounds of the sender
+    pub retransmits: u32,
+    pub elapsed: Duration,
+}
+
+/// Send image to one receiver per impairment over a loopback network and
+/// return what each of them wrote. configure sets up the sender before the
+/// receivers connect.
+pub fn run_session(
+    image: &[u8],
+    receivers: &[Impairment],
+    configure: impl FnOnce(&mut McastSender),
+) -> Result<SessionResult, &'static str> {
+    let network = Loopback::new();
+    let sender_socket = network.sender(1).map_err(|_| "Can't bind the sender")?;
+    // every receiver connects before the first one says go
+    let connected = Arc::new(Barrier::new(receivers.len()));
+    let finished = Arc::new(AtomicBool::new(false));
+    let mut threads = Vec::new();
+    let mut stats = Vec::new();
+    let mut wakers = Vec::new();
+    for (host, impairment) in receivers.iter().enumerate() {
+        let (mut socket, impaired) = network
+            .impaired_receiver(10 + host as u8, impairment.clone())
+            .map_err(|_| "Can't bind a receiver")?;
+        stats.push(impaired);
+        wakers.extend(socket.waker());
+        let connected = Arc::clone(&connected);
+        let finished = Arc::clone(&finished);
+        threads.push(thread::spawn(move || receive(socket, connected, finished)));
+    }
+
+    let start = Instant::now();
+    let data_fifo = Arc::new(RwLock::new(DataFIFO::new(MAX_BUFFER_SIZE)));
+    let mut sender = McastSender::new(sender_socket, 1, MAX_SLICE_SIZE, Arc::clone(&data_fifo));
+    configure(&mut sender);
+    let feeder = {
+        let data_fifo = Arc::clone(&data_fifo);
+        let image = image.to_vec();
+        thread::spawn(move || feed(&image, data_fifo))
+    };
+    let result = sender.enumerate(Duration::from_secs(10), false);
+    if result.is_ok() {
+        while sender.transfer_data() {
+            if let Ok(false) = sender.dispatch_message() {
+                break;
+            }
+        }
+    }
+    data_fifo.write().unwrap().close();
+    let _ = feeder.join();
+    sender.collect_reports(Duration::from_secs(10));
+    // receivers which missed the end of the session give up now
+    finished.store(true, Ordering::Relaxed);
+    for waker in wakers {
+        let _ = waker.wake();
+    }
+    if let Err(err) = result {
+        error!("{err}");
+    }
+
+    let images = threads
+        .into_iter()
+        .map(|thread| thread.join().map_err(|_| "A receiver panicked")?)
+        .collect::<Result<_, _>>()?;
+    Ok(SessionResult {
+        images,
+        impaired: stats
+            .iter()
+            .map(|stats| {
+                (
+                    stats.lost.load(Ordering::Relaxed),
+                    stats.duplicated.load(Ordering::Relaxed),
+                    stats.reordered.load(Ordering::Relaxed),
+                )
+            })
+            .collect(),
+        slices: sender.slices.len(),
+        retransmits: sender.retransmits(),
+        elapsed: start.elapsed(),
+    })
+}
+
+// push the image into the FIFO of the sender as fast as it takes it
+fn feed(image: &[u8], data_fifo: Arc<RwLock<DataFIFO>>) {
+    let changes = data_fifo.read().unwrap().notify();
+    let mut offset = 0;
+    while offset < image.len() && !data_fifo.read().unwrap().is_closed() {
+        let seen = changes.count();
+        let room = MAX_BUFFER_SIZE - data_fifo.read().unwrap().len();
+        let size = room.min(image.len() - offset);
+        if size > 0 {
+            data_fifo
+                .write()
+                .unwrap()
+                .push(&image[offset..offset + size]);
+            offset += size;
+        } else {
+            changes.wait(seen);
+        }
+    }
+    data_fifo.write().unwrap().close();
+}
+
+fn receive(
+    socket: MultiCast,
+    connected: Arc<Barrier>,
+    finished: Arc<AtomicBool>,
+) -> Result<Vec<u8>, &'static str> {
+    let data_fifo = Arc::new(RwLock::new(DataFIFO::new(MAX_BUFFER_SIZE)));
+    let mut receiver = McastReceiver::new(socket, 8 * MI_BYTES, Arc::clone(&data_fifo));
+    let joined = receiver.enumerate();
+    connected.wait();
+    joined?;
+    receiver.start_transfer();
+
+    let mut image = Vec::new();
+    loop {
+        let running = receiver.dispatch_message();
+        let size = data_fifo.read().unwrap().len();
+        if size > 0 {
+            if let Some(data) = data_fifo.write().unwrap().pop(size) {
+                image.extend_from_slice(&data);
+            }
+        }
+        if running == Ok(ENDLOOP)
+            || data_fifo.read().unwrap().is_closed()
+            || finished.load(Ordering::Relaxed)
+        {
+            break;
+        }
+    }
+    let size = data_fifo.read().unwrap().len();
+    if let Some(data) = data_fifo.write().unwrap().pop(size) {
+        image.extend_from_slice(&data);
+    }
+    let _ = receiver.send_report();
+    let _ = receiver.send_disconnect();
+    if receiver.undecryptable > 0 {
+        warn!("{} blocks failed to decrypt", receiver.undecryptable);
+    }
+    Ok(image)
+}
@@ -0,0 +1,233 @@
+// Stray and malformed datagrams must neither panic the decoder nor a
+// receiver which dispatches them.
+
+use img_caster::datafifo::DataFIFO;
+use img_caster::loopback::{Loopback, LoopbackSocket};
+use img_caster::multicast::MultiCast;
+use img_caster::packet::{self, *};
+use img_caster::transport::Transport;
+use img_caster::*;
+use img_caster::{receiver_a, receiver_s, udpcast};
+use proptest::collection::vec;
+use proptest::prelude::*;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+const OPCODES: [u16; 14] = [0, 1, 2, 3, 4, 6, 7, 8, 9, 10, 11, 0x20, 0x21, 0x500];
+const GROUP: IpAddr = IpAddr::V4(Ipv4Addr::new(232, 0, 2, 10));
+
+fn capabilities() -> impl Strategy<Value = u32> {
+    // every optional capability a receiver without a key takes
+    let optional = CAP_FEC | CAP_STREAMING | CAP_CHECKSUM | CAP_DIGEST;
+    any::<u32>().prop_map(move |bits| CAP_REQUIRED | bits & optional)
+}
+
+fn block_size() -> impl Strategy<Value = u32> {
+    prop_oneof![Just(BLOCK_SIZE), MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE]
+}
+
+// mostly sizes of slices a receiver takes, now and then anything
+fn slice_bytes() -> impl Strategy<Value = u32> {
+    prop_oneof![4 => 0..4 * MI_BYTES as u32, 1 => 0..256 * MI_BYTES as u32, 1 => any::<u32>()]
+}
+
+fn with_payload(msg: Message, payload: &[u8]) -> Vec<u8> {
+    let mut packet = msg.encode();
+    packet.extend_from_slice(payload);
+    packet
+}
+
+// Noise, known opcodes followed by anything, and well formed messages with
+// arbitrary fields and payloads.
+fn datagram() -> impl Strategy<Value = Vec<u8>> {
+    let slice = 0..8u32;
+    prop_oneof![
+        vec(any::<u8>(), 0..64),
+        (prop::sample::select(&OPCODES[..]), vec(any::<u8>(), 0..160))
+            .prop_map(|(opcode, body)| [&opcode.to_be_bytes()[..], &body].concat()),
+        (
+            slice.clone(),
+            0..4096u16,
+            slice_bytes(),
+            vec(any::<u8>(), 1..1600)
+        )
+            .prop_map(|(slice, block, bytes, data)| {
+                with_payload(Message::CmdData(DataBlock::new(slice, block, bytes)), &data)
+            }),
+        (
+            any::<u16>(),
+            slice.clone(),
+            0..4096u16,
+            slice_bytes(),
+            vec(any::<u8>(), 1..1600)
+        )
+            .prop_map(|(stripes, slice, block, bytes, data)| {
+                let msg = FecBlock::new(stripes, slice, block, bytes);
+                with_payload(Message::CmdFec(msg), &data)
+            }),
+        (
+            slice.clone(),
+            slice_bytes(),
+            0..4u32,
+            vec(any::<u8>(), 16..52)
+        )
+            .prop_map(|(slice, bytes, rxmit, ready_set)| {
+                let msg = MsgReqAck::new(slice, bytes, rxmit);
+                with_payload(Message::CmdReqack(msg), &ready_set)
+            }),
+        (capabilities(), block_size()).prop_map(|(capabilities, blocksize)| {
+            Message::CmdHello(MsgHello::new(capabilities, &GROUP, blocksize as u16)).encode()
+        }),
+        (0..MAX_CLIENTS, block_size(), capabilities(), any::<u32>()).prop_map(
+            |(clnr, blocksize, capabilities, max_slices)| {
+                let msg = MsgConnectReply::new(clnr, blocksize, capabilities, max_slices, &GROUP);
+                Message::CmdConnectReply(msg).encode()
+            }
+        ),
+        (slice, any::<u64>(), any::<u64>()).prop_map(|(slice, offset, size)| {
+            Message::CmdStreamJoin(MsgStreamJoin::new(slice, offset, size)).encode()
+        }),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn decode_never_panics(data in vec(any::<u8>(), 0..2048)) {
+        let _ = Message::decode(&data);
+    }
+
+    // whatever decodes encodes back to the same header and payload
+    #[test]
+    fn decode_round_trip(data in datagram()) {
+        if let Ok((msg, payload)) = Message::decode(&data) {
+            let mut encoded = msg.encode();
+            encoded.extend_from_slice(payload);
+            if encoded != data {
+                // udpcast layouts are normalized while decoding
+                let encoded = udpcast::encode(&msg, payload);
+                let (again, again_payload) = Message::decode(&encoded).unwrap();
+                prop_assert_eq!(format!("{again:?}"), format!("{msg:?}"));
+                prop_assert_eq!(again_payload, payload);
+            }
+        }
+    }
+
+    #[test]
+    fn decode_rejects_bad_block_sizes(blocksize in prop_oneof![0..MIN_BLOCK_SIZE, MAX_BLOCK_SIZE + 1..]) {
+        let reply = MsgConnectReply::new(1, blocksize, CAP_REQUIRED, MAX_SLICE_SIZE, &GROUP);
+        prop_assert!(Message::decode(&Message::CmdConnectReply(reply).encode()).is_err());
+        if blocksize <= u16::MAX as u32 {
+            let hello = MsgHello::new(CAP_REQUIRED, &GROUP, blocksize as u16);
+            prop_assert!(Message::decode(&Message::CmdHello(hello).encode()).is_err());
+        }
+    }
+}
+
+// A receiver connected as clnr to a session with these parameters, and the
+// socket the datagrams come from.
+fn connect(
+    session: u16,
+    clnr: u32,
+    blocksize: u32,
+    capabilities: u32,
+) -> (Loopback, LoopbackSocket, MultiCast, SocketAddr) {
+    let network = Loopback::new();
+    let peer = network
+        .bind(SocketAddrV4::new(Loopback::addr(1), PORTBASE + 1).into())
+        .unwrap();
+    let socket = network.receiver(10).unwrap();
+    let addr = SocketAddrV4::new(Loopback::addr(10), PORTBASE).into();
+    let reply = MsgConnectReply::new(clnr, blocksize, capabilities, MAX_SLICE_SIZE, &GROUP);
+    let mut reply = Message::CmdConnectReply(reply).encode();
+    packet::set_session(&mut reply, session);
+    peer.send_to(&reply, addr).unwrap();
+    (network, peer, socket, addr)
+}
+
+// datagrams of other sessions are dropped before they are decoded, most of
+// these belong to the session
+fn stamp(mut datagrams: Vec<Vec<u8>>, session: u16) -> Vec<Vec<u8>> {
+    for (n, datagram) in datagrams.iter_mut().enumerate() {
+        if n % 8 != 7 {
+            packet::set_session(datagram, session);
+        }
+    }
+    datagrams
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn receiver_a_survives(
+        session in 1..=u16::MAX,
+        clnr in 0..MAX_CLIENTS,
+        blocksize in block_size(),
+        capabilities in capabilities(),
+        datagrams in vec(datagram(), 1..48),
+    ) {
+        let (_network, peer, socket, addr) = connect(session, clnr, blocksize, capabilities);
+        let data_fifo = Arc::new(RwLock::new(DataFIFO::new(MAX_BUFFER_SIZE)));
+        let mut receiver = receiver_a::McastReceiver::new(socket, 8 * MI_BYTES, Arc::clone(&data_fifo));
+        prop_assert_eq!(receiver.enumerate(), Ok(true));
+        let writer = {
+            let data_fifo = Arc::clone(&data_fifo);
+            thread::spawn(move || {
+                receiver_a::write(&mut None, data_fifo, CHUNK_SIZE, Arc::default())
+            })
+        };
+        for datagram in stamp(datagrams, session) {
+            peer.send_to(&datagram, addr).unwrap();
+            let _ = receiver.dispatch_message();
+        }
+        data_fifo.write().unwrap().close();
+        prop_assert!(writer.join().is_ok());
+    }
+
+    #[test]
+    fn receiver_s_survives(
+        session in 1..=u16::MAX,
+        clnr in 0..MAX_CLIENTS,
+        blocksize in block_size(),
+        capabilities in capabilities(),
+        datagrams in vec(datagram(), 1..48),
+    ) {
+        let (_network, peer, socket, addr) = connect(session, clnr, blocksize, capabilities);
+        let data_fifo = DataFIFO::new(MAX_BUFFER_SIZE);
+        let mut receiver = receiver_s::McastReceiver::new(socket, 8 * MI_BYTES, data_fifo, None, Arc::default());
+        prop_assert_eq!(receiver.enumerate(), Ok(true));
+        for datagram in stamp(datagrams, session) {
+            peer.send_to(&datagram, addr).unwrap();
+            let _ = receiver.dispatch_message();
+        }
+    }
+}
+
+// datagrams the socket drops end the wait, not the session
+#[test]
+fn receivers_skip_dropped_datagrams() {
+    let session = 0x1234;
+    let mut other = Message::CmdData(DataBlock::new(0, 0, BLOCK_SIZE)).encode();
+    packet::set_session(&mut other, 0x4321);
+    let dropped = [vec![0xff; 4], other];
+
+    let (_network, peer, socket, addr) = connect(session, 0, BLOCK_SIZE, 0);
+    let data_fifo = Arc::new(RwLock::new(DataFIFO::new(MAX_BUFFER_SIZE)));
+    let mut receiver = receiver_a::McastReceiver::new(socket, 8 * MI_BYTES, data_fifo);
+    assert_eq!(receiver.enumerate(), Ok(true));
+    for datagram in &dropped {
+        peer.send_to(datagram, addr).unwrap();
+        assert_eq!(receiver.dispatch_message(), Ok(RUNNING));
+    }
+
+    let (_network, peer, socket, addr) = connect(session, 0, BLOCK_SIZE, 0);
+    let data_fifo = DataFIFO::new(MAX_BUFFER_SIZE);
+    let mut receiver =
+        receiver_s::McastReceiver::new(socket, 8 * MI_BYTES, data_fifo, None, Arc::default());
+    assert_eq!(receiver.enumerate(), Ok(true));
+    for datagram in &dropped {
+        peer.send_to(datagram, addr).unwrap();
+        assert_eq!(receiver.dispatch_message(), Ok(RUNNING));
+    }
+}
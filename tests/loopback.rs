@@ -0,0 +1,66 @@
+// Whole sessions over the loopback network, every receiver has to write the
+// bytes the sender read.
+
+mod common;
+
+use img_caster::fec::Fec;
+use img_caster::impair::Impairment;
+use img_caster::sender::McastSender;
+use img_caster::*;
+
+fn image(size: usize) -> Vec<u8> {
+    (0..size as u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect()
+}
+
+fn check(image: &[u8], receivers: usize, configure: impl FnOnce(&mut McastSender)) {
+    let clean = vec![Impairment::default(); receivers];
+    let result = common::run_session(image, &clean, configure).unwrap();
+    assert_eq!(result.images.len(), receivers);
+    for (no, received) in result.images.iter().enumerate() {
+        let first_difference = received.iter().zip(image).position(|(a, b)| a != b);
+        assert_eq!(
+            (received.len(), first_difference),
+            (image.len(), None),
+            "receiver {no} wrote another image"
+        );
+    }
+    // nothing is lost on the loopback network
+    assert_eq!(result.retransmits, 0);
+}
+
+#[test]
+fn one_receiver() {
+    check(&image(MI_BYTES + 333), 1, |_| {});
+}
+
+#[test]
+fn several_receivers() {
+    check(&image(2 * MI_BYTES + 333), 8, |_| {});
+}
+
+// less than one block
+#[test]
+fn short_image() {
+    check(&image(100), 2, |_| {});
+}
+
+#[test]
+fn whole_blocks() {
+    check(&image(1000 * BLOCK_SIZE as usize), 2, |_| {});
+}
+
+#[test]
+fn fec() {
+    check(&image(MI_BYTES + 333), 3, |sender| {
+        sender.set_fec(Fec::new(8, 2))
+    });
+}
+
+#[test]
+fn jumbo_blocks() {
+    check(&image(2 * MI_BYTES + 333), 3, |sender| {
+        sender.set_blocksize(8956).unwrap()
+    });
+}
@@ -0,0 +1,162 @@
+// Sessions over receivers which lose, reorder, duplicate and delay datagrams.
+// Every receiver has to write the image, the retransmission rounds of each
+// scenario are printed: cargo test --test netsim -- --nocapture
+
+mod common;
+
+use img_caster::congestion;
+use img_caster::impair::Impairment;
+use img_caster::sender::McastSender;
+use std::time::Duration;
+
+// the same seed gives every receiver the same faults in every run
+const SEED: u64 = 1;
+const RECEIVERS: u64 = 3;
+
+fn ms(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+}
+
+// not a multiple of the block size, so the last block is a short one
+fn image() -> Vec<u8> {
+    (0..4 * 1024 * 1024 + 333u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect()
+}
+
+// Run the scenario and check every receiver, returns the retransmission
+// rounds of the sender.
+fn run(name: &str, impairment: Impairment, configure: impl FnOnce(&mut McastSender)) -> u32 {
+    let image = image();
+    // every receiver gets faults of its own
+    let impairments: Vec<Impairment> = (0..RECEIVERS)
+        .map(|no| Impairment {
+            seed: SEED * 1000 + no,
+            ..impairment.clone()
+        })
+        .collect();
+    let result = common::run_session(&image, &impairments, configure).unwrap();
+    for (no, received) in result.images.iter().enumerate() {
+        let first_difference = received.iter().zip(&image).position(|(a, b)| a != b);
+        assert_eq!(
+            (received.len(), first_difference),
+            (image.len(), None),
+            "{name}: receiver {no} wrote another image"
+        );
+    }
+    let (lost, duplicated, reordered) = result.impaired.iter().fold((0, 0, 0), |sum, counts| {
+        (sum.0 + counts.0, sum.1 + counts.1, sum.2 + counts.2)
+    });
+    println!(
+        "{name}: {} slices {} retransmit rounds, lost {lost} duplicated {duplicated} reordered {reordered} in {:.1?}",
+        result.slices, result.retransmits, result.elapsed
+    );
+    if lost > 0 {
+        assert!(
+            result.retransmits > 0,
+            "{name}: losses without retransmission"
+        );
+    }
+    result.retransmits
+}
+
+#[test]
+fn clean() {
+    assert_eq!(run("clean", Impairment::default(), |_| {}), 0);
+}
+
+#[test]
+fn loss_1() {
+    let impairment = Impairment {
+        loss: 0.01,
+        ..Default::default()
+    };
+    run("loss 1%", impairment, |_| {});
+}
+
+#[test]
+fn loss_5() {
+    let impairment = Impairment {
+        loss: 0.05,
+        ..Default::default()
+    };
+    run("loss 5%", impairment, |_| {});
+}
+
+#[test]
+fn burst_loss() {
+    let impairment = Impairment {
+        burst: 0.002,
+        burst_len: ms(20),
+        ..Default::default()
+    };
+    run("burst loss", impairment, |_| {});
+}
+
+#[test]
+fn reorder() {
+    let impairment = Impairment {
+        reorder: 0.05,
+        ..Default::default()
+    };
+    run("reorder", impairment, |_| {});
+}
+
+#[test]
+fn duplicate() {
+    let impairment = Impairment {
+        duplicate: 0.05,
+        ..Default::default()
+    };
+    // nothing is lost, so nothing is sent twice
+    assert_eq!(run("duplicate", impairment, |_| {}), 0);
+}
+
+#[test]
+fn delay_jitter() {
+    let impairment = Impairment {
+        delay: ms(2),
+        jitter: ms(3),
+        ..Default::default()
+    };
+    run("delay jitter", impairment, |_| {});
+}
+
+fn all() -> Impairment {
+    Impairment {
+        loss: 0.02,
+        burst: 0.001,
+        burst_len: ms(10),
+        reorder: 0.02,
+        duplicate: 0.02,
+        delay: ms(1),
+        jitter: ms(1),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn all_faults() {
+    run("all", all(), |_| {});
+}
+
+#[test]
+fn all_faults_paced() {
+    run("all, 200 Mbit/s", all(), |sender| {
+        sender.set_max_bitrate(200_000_000)
+    });
+}
+
+#[test]
+fn all_faults_aimd() {
+    run("all, aimd", all(), |sender| {
+        sender.set_congestion(congestion::from_name("aimd").unwrap())
+    });
+}
+
+#[test]
+fn all_faults_jumbo() {
+    run("all, blocks of 8956 bytes", all(), |sender| {
+        sender.set_blocksize(8956).unwrap()
+    });
+}
@@ -0,0 +1,138 @@
+// The messages of udp-sender and udp-receiver, decoded and encoded again the
+// way img_caster puts them on the wire in udpcast mode.
+
+use img_caster::bitarray::BitArray;
+use img_caster::packet::*;
+use img_caster::udpcast::{self, *};
+use img_caster::*;
+use std::net::{IpAddr, Ipv4Addr};
+
+const MCAST: IpAddr = IpAddr::V4(Ipv4Addr::new(232, 1, 2, 3));
+
+fn decode(packet: &[u8]) -> (Message, &[u8]) {
+    Message::decode(packet).unwrap_or_else(|err| panic!("{err}: {packet:02x?}"))
+}
+
+// img_caster sends what it decoded in the same bytes
+fn round_trip(packet: &[u8]) {
+    let (msg, payload) = decode(packet);
+    assert_eq!(udpcast::encode(&msg, payload), packet);
+}
+
+fn bits(map: &[u8]) -> Vec<usize> {
+    let map = BitArray::from(map.to_vec());
+    (0..map.len()).filter(|&bit| map.get(bit)).collect()
+}
+
+#[test]
+fn ok() {
+    let packet = include_bytes!("udpcast/ok.bin");
+    assert!(matches!(decode(packet), (Message::CmdOk(msg), []) if msg == MsgOk::new(5)));
+    round_trip(packet);
+}
+
+#[test]
+fn retransmit() {
+    let packet = include_bytes!("udpcast/retransmit.bin");
+    let (msg, map) = decode(packet);
+    assert!(matches!(msg, Message::CmdRetransmit(msg) if msg == MsgRetransmit::new(5, 1)));
+    assert_eq!(map.len(), map_len(UDPCAST_MAX_SLICE_SIZE));
+    assert_eq!(bits(map), [0, 9, 1023]);
+    round_trip(packet);
+}
+
+#[test]
+fn go() {
+    let packet = include_bytes!("udpcast/go.bin");
+    assert!(matches!(decode(packet), (Message::CmdGo(_), [])));
+    round_trip(packet);
+}
+
+#[test]
+fn connect_req() {
+    let packet = include_bytes!("udpcast/connect_req.bin");
+    assert!(matches!(
+        decode(packet),
+        (Message::CmdConnectReq(msg), []) if msg == MsgConnectReq::new(UDPCAST_CAPABILITIES, MI_BYTES as u32)
+    ));
+    round_trip(packet);
+}
+
+#[test]
+fn disconnect() {
+    let packet = include_bytes!("udpcast/disconnect.bin");
+    assert!(matches!(decode(packet), (Message::CmdDisconnect(_), [])));
+    round_trip(packet);
+}
+
+#[test]
+fn reqack() {
+    let packet = include_bytes!("udpcast/reqack.bin");
+    let (msg, ready_set) = decode(packet);
+    assert!(
+        matches!(msg, Message::CmdReqack(msg) if msg == MsgReqAck::new(5, 100 * BLOCK_SIZE, 2))
+    );
+    assert_eq!(ready_set.len(), map_len(UDPCAST_MAX_CLIENTS));
+    assert_eq!(bits(ready_set), [0, 3, 1023]);
+    round_trip(packet);
+}
+
+#[test]
+fn connect_reply() {
+    let packet = include_bytes!("udpcast/connect_reply.bin");
+    match decode(packet) {
+        (Message::CmdConnectReply(msg), []) => {
+            assert_eq!(msg.clnr, 3);
+            assert_eq!(msg.blocksize, BLOCK_SIZE);
+            assert_eq!(msg.capabilities, UDPCAST_CAPABILITIES);
+            // not sent by udp-sender, its slices hold up to 1024 blocks
+            assert_eq!(msg.max_slices, UDPCAST_MAX_SLICE_SIZE);
+            assert_eq!(msg.mcastaddr(), MCAST);
+        }
+        other => panic!("{other:?}"),
+    }
+    round_trip(packet);
+}
+
+#[test]
+fn data() {
+    let packet = include_bytes!("udpcast/data.bin");
+    let (msg, data) = decode(packet);
+    assert!(matches!(msg, Message::CmdData(msg) if msg == DataBlock::new(5, 99, 100 * BLOCK_SIZE)));
+    assert_eq!(data, [0xde, 0xad, 0xbe, 0xef]);
+    round_trip(packet);
+}
+
+fn check_hello(msg: &Message) {
+    match msg {
+        Message::CmdHello(msg) => {
+            assert_eq!(msg.capabilities, UDPCAST_CAPABILITIES);
+            assert_eq!(msg.blocksize as u32, BLOCK_SIZE);
+            assert_eq!(msg.mcastaddr(), MCAST);
+        }
+        other => panic!("{other:?}"),
+    }
+}
+
+#[test]
+fn hello_new() {
+    let packet = include_bytes!("udpcast/hello_new.bin");
+    let (msg, payload) = decode(packet);
+    check_hello(&msg);
+    // the struct padding is no payload
+    assert!(payload.is_empty());
+    round_trip(packet);
+}
+
+// the old hello of udp-sender is answered with CMD_HELLO_NEW
+#[test]
+fn hello() {
+    let packet = include_bytes!("udpcast/hello.bin");
+    let (msg, payload) = decode(packet);
+    check_hello(&msg);
+    assert!(payload.is_empty());
+    assert_eq!(
+        udpcast::encode(&msg, payload),
+        include_bytes!("udpcast/hello_new.bin")
+    );
+}